@@ -0,0 +1,25 @@
+#!/bin/sh
+
+DIR=$1
+TAR=$2
+
+echo extract_data: Extracting $TAR to $DIR
+
+# tar detects compressed archives by itself
+tar xf "$TAR" -C "$DIR" || exit 1
+cd $DIR || exit 1
+
+# The files may be inside of a directory in the archive
+find . -mindepth 2 \( -name posixinfo -o -name zone1970.tab -o -name backward -o -name overrides -o -name GeoLite2-City.mmdb -o -name GeoLite2-Country.mmdb \) -exec mv {} . \;
+
+# Country databases are stored under the same name, since the server detects the type when loading
+if [ ! -f GeoLite2-City.mmdb ] && [ -f GeoLite2-Country.mmdb ]; then
+	mv GeoLite2-Country.mmdb GeoLite2-City.mmdb
+fi
+
+for i in posixinfo zone1970.tab; do
+	if [ ! -f $i ]; then
+		echo "extract_data: $i could not be found in $TAR"
+		exit 1
+	fi
+done
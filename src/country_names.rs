@@ -0,0 +1,284 @@
+// English names of countries and their ISO 3166 codes, based on iso3166.tab from tzdata with some
+// common alternative names added
+pub const COUNTRY_NAMES: &[(&str, &str)] = &[
+    ("Andorra", "AD"),
+    ("United Arab Emirates", "AE"),
+    ("UAE", "AE"),
+    ("Afghanistan", "AF"),
+    ("Antigua and Barbuda", "AG"),
+    ("Anguilla", "AI"),
+    ("Albania", "AL"),
+    ("Armenia", "AM"),
+    ("Angola", "AO"),
+    ("Antarctica", "AQ"),
+    ("Argentina", "AR"),
+    ("American Samoa", "AS"),
+    ("Austria", "AT"),
+    ("Australia", "AU"),
+    ("Aruba", "AW"),
+    ("Åland Islands", "AX"),
+    ("Aland Islands", "AX"),
+    ("Azerbaijan", "AZ"),
+    ("Bosnia and Herzegovina", "BA"),
+    ("Barbados", "BB"),
+    ("Bangladesh", "BD"),
+    ("Belgium", "BE"),
+    ("Burkina Faso", "BF"),
+    ("Bulgaria", "BG"),
+    ("Bahrain", "BH"),
+    ("Burundi", "BI"),
+    ("Benin", "BJ"),
+    ("St Barthelemy", "BL"),
+    ("Saint Barthelemy", "BL"),
+    ("Bermuda", "BM"),
+    ("Brunei", "BN"),
+    ("Bolivia", "BO"),
+    ("Caribbean NL", "BQ"),
+    ("Brazil", "BR"),
+    ("Bahamas", "BS"),
+    ("Bhutan", "BT"),
+    ("Bouvet Island", "BV"),
+    ("Botswana", "BW"),
+    ("Belarus", "BY"),
+    ("Belize", "BZ"),
+    ("Canada", "CA"),
+    ("Cocos Islands", "CC"),
+    ("Keeling Islands", "CC"),
+    ("Democratic Republic of the Congo", "CD"),
+    ("DR Congo", "CD"),
+    ("Central African Republic", "CF"),
+    ("Republic of the Congo", "CG"),
+    ("Congo", "CG"),
+    ("Switzerland", "CH"),
+    ("Côte d'Ivoire", "CI"),
+    ("Cote d'Ivoire", "CI"),
+    ("Ivory Coast", "CI"),
+    ("Cook Islands", "CK"),
+    ("Chile", "CL"),
+    ("Cameroon", "CM"),
+    ("China", "CN"),
+    ("Colombia", "CO"),
+    ("Costa Rica", "CR"),
+    ("Cuba", "CU"),
+    ("Cape Verde", "CV"),
+    ("Curaçao", "CW"),
+    ("Curacao", "CW"),
+    ("Christmas Island", "CX"),
+    ("Cyprus", "CY"),
+    ("Czech Republic", "CZ"),
+    ("Czechia", "CZ"),
+    ("Germany", "DE"),
+    ("Djibouti", "DJ"),
+    ("Denmark", "DK"),
+    ("Dominica", "DM"),
+    ("Dominican Republic", "DO"),
+    ("Algeria", "DZ"),
+    ("Ecuador", "EC"),
+    ("Estonia", "EE"),
+    ("Egypt", "EG"),
+    ("Western Sahara", "EH"),
+    ("Eritrea", "ER"),
+    ("Spain", "ES"),
+    ("Ethiopia", "ET"),
+    ("Finland", "FI"),
+    ("Fiji", "FJ"),
+    ("Falkland Islands", "FK"),
+    ("Micronesia", "FM"),
+    ("Faroe Islands", "FO"),
+    ("France", "FR"),
+    ("Gabon", "GA"),
+    ("United Kingdom", "GB"),
+    ("Britain", "GB"),
+    ("Great Britain", "GB"),
+    ("UK", "GB"),
+    ("Grenada", "GD"),
+    ("Georgia", "GE"),
+    ("French Guiana", "GF"),
+    ("Guernsey", "GG"),
+    ("Ghana", "GH"),
+    ("Gibraltar", "GI"),
+    ("Greenland", "GL"),
+    ("Gambia", "GM"),
+    ("Guinea", "GN"),
+    ("Guadeloupe", "GP"),
+    ("Equatorial Guinea", "GQ"),
+    ("Greece", "GR"),
+    ("South Georgia and the South Sandwich Islands", "GS"),
+    ("South Georgia", "GS"),
+    ("Guatemala", "GT"),
+    ("Guam", "GU"),
+    ("Guinea-Bissau", "GW"),
+    ("Guyana", "GY"),
+    ("Hong Kong", "HK"),
+    ("Heard Island and McDonald Islands", "HM"),
+    ("Honduras", "HN"),
+    ("Croatia", "HR"),
+    ("Haiti", "HT"),
+    ("Hungary", "HU"),
+    ("Indonesia", "ID"),
+    ("Ireland", "IE"),
+    ("Israel", "IL"),
+    ("Isle of Man", "IM"),
+    ("India", "IN"),
+    ("British Indian Ocean Territory", "IO"),
+    ("Iraq", "IQ"),
+    ("Iran", "IR"),
+    ("Iceland", "IS"),
+    ("Italy", "IT"),
+    ("Jersey", "JE"),
+    ("Jamaica", "JM"),
+    ("Jordan", "JO"),
+    ("Japan", "JP"),
+    ("Kenya", "KE"),
+    ("Kyrgyzstan", "KG"),
+    ("Cambodia", "KH"),
+    ("Kiribati", "KI"),
+    ("Comoros", "KM"),
+    ("Saint Kitts and Nevis", "KN"),
+    ("St Kitts and Nevis", "KN"),
+    ("North Korea", "KP"),
+    ("South Korea", "KR"),
+    ("Korea", "KR"),
+    ("Kuwait", "KW"),
+    ("Cayman Islands", "KY"),
+    ("Kazakhstan", "KZ"),
+    ("Laos", "LA"),
+    ("Lebanon", "LB"),
+    ("St Lucia", "LC"),
+    ("Saint Lucia", "LC"),
+    ("Liechtenstein", "LI"),
+    ("Sri Lanka", "LK"),
+    ("Liberia", "LR"),
+    ("Lesotho", "LS"),
+    ("Lithuania", "LT"),
+    ("Luxembourg", "LU"),
+    ("Latvia", "LV"),
+    ("Libya", "LY"),
+    ("Morocco", "MA"),
+    ("Monaco", "MC"),
+    ("Moldova", "MD"),
+    ("Montenegro", "ME"),
+    ("Saint Martin", "MF"),
+    ("St Martin", "MF"),
+    ("Madagascar", "MG"),
+    ("Marshall Islands", "MH"),
+    ("North Macedonia", "MK"),
+    ("Mali", "ML"),
+    ("Myanmar", "MM"),
+    ("Burma", "MM"),
+    ("Mongolia", "MN"),
+    ("Macau", "MO"),
+    ("Northern Mariana Islands", "MP"),
+    ("Martinique", "MQ"),
+    ("Mauritania", "MR"),
+    ("Montserrat", "MS"),
+    ("Malta", "MT"),
+    ("Mauritius", "MU"),
+    ("Maldives", "MV"),
+    ("Malawi", "MW"),
+    ("Mexico", "MX"),
+    ("Malaysia", "MY"),
+    ("Mozambique", "MZ"),
+    ("Namibia", "NA"),
+    ("New Caledonia", "NC"),
+    ("Niger", "NE"),
+    ("Norfolk Island", "NF"),
+    ("Nigeria", "NG"),
+    ("Nicaragua", "NI"),
+    ("Netherlands", "NL"),
+    ("Holland", "NL"),
+    ("Norway", "NO"),
+    ("Nepal", "NP"),
+    ("Nauru", "NR"),
+    ("Niue", "NU"),
+    ("New Zealand", "NZ"),
+    ("Oman", "OM"),
+    ("Panama", "PA"),
+    ("Peru", "PE"),
+    ("French Polynesia", "PF"),
+    ("Papua New Guinea", "PG"),
+    ("Philippines", "PH"),
+    ("Pakistan", "PK"),
+    ("Poland", "PL"),
+    ("Saint Pierre and Miquelon", "PM"),
+    ("St Pierre and Miquelon", "PM"),
+    ("Pitcairn", "PN"),
+    ("Puerto Rico", "PR"),
+    ("Palestine", "PS"),
+    ("Portugal", "PT"),
+    ("Palau", "PW"),
+    ("Paraguay", "PY"),
+    ("Qatar", "QA"),
+    ("Réunion", "RE"),
+    ("Reunion", "RE"),
+    ("Romania", "RO"),
+    ("Serbia", "RS"),
+    ("Russia", "RU"),
+    ("Rwanda", "RW"),
+    ("Saudi Arabia", "SA"),
+    ("Solomon Islands", "SB"),
+    ("Seychelles", "SC"),
+    ("Sudan", "SD"),
+    ("Sweden", "SE"),
+    ("Singapore", "SG"),
+    ("St Helena", "SH"),
+    ("Saint Helena", "SH"),
+    ("Slovenia", "SI"),
+    ("Svalbard and Jan Mayen", "SJ"),
+    ("Svalbard", "SJ"),
+    ("Slovakia", "SK"),
+    ("Sierra Leone", "SL"),
+    ("San Marino", "SM"),
+    ("Senegal", "SN"),
+    ("Somalia", "SO"),
+    ("Suriname", "SR"),
+    ("South Sudan", "SS"),
+    ("Sao Tome and Principe", "ST"),
+    ("El Salvador", "SV"),
+    ("Sint Maarten", "SX"),
+    ("St Maarten", "SX"),
+    ("Syria", "SY"),
+    ("Eswatini", "SZ"),
+    ("Swaziland", "SZ"),
+    ("Turks and Caicos Islands", "TC"),
+    ("Chad", "TD"),
+    ("French Southern Territories", "TF"),
+    ("Togo", "TG"),
+    ("Thailand", "TH"),
+    ("Tajikistan", "TJ"),
+    ("Tokelau", "TK"),
+    ("East Timor", "TL"),
+    ("Turkmenistan", "TM"),
+    ("Tunisia", "TN"),
+    ("Tonga", "TO"),
+    ("Turkey", "TR"),
+    ("Türkiye", "TR"),
+    ("Turkiye", "TR"),
+    ("Trinidad and Tobago", "TT"),
+    ("Tuvalu", "TV"),
+    ("Taiwan", "TW"),
+    ("Tanzania", "TZ"),
+    ("Ukraine", "UA"),
+    ("Uganda", "UG"),
+    ("US minor outlying islands", "UM"),
+    ("United States", "US"),
+    ("United States of America", "US"),
+    ("USA", "US"),
+    ("Uruguay", "UY"),
+    ("Uzbekistan", "UZ"),
+    ("Vatican City", "VA"),
+    ("St Vincent", "VC"),
+    ("Saint Vincent", "VC"),
+    ("Venezuela", "VE"),
+    ("British Virgin Islands", "VG"),
+    ("US Virgin Islands", "VI"),
+    ("Vietnam", "VN"),
+    ("Vanuatu", "VU"),
+    ("Wallis and Futuna", "WF"),
+    ("Samoa", "WS"),
+    ("Yemen", "YE"),
+    ("Mayotte", "YT"),
+    ("South Africa", "ZA"),
+    ("Zambia", "ZM"),
+    ("Zimbabwe", "ZW"),
+];
@@ -0,0 +1,400 @@
+use crate::logger::REQUEST_LOG_TARGET;
+use crate::Config;
+use log::{debug, info};
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::ssl::{
+    ErrorCode, Ssl, SslContext, SslContextBuilder, SslFiletype, SslMethod, SslOptions, SslRef,
+    SslStream,
+};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant};
+
+// Records are kept small enough to fit in a single datagram on any path
+const DTLS_MTU: u32 = 1200;
+// Sessions are closed when nothing has been received from the peer for this long
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+// Datagrams from new peers are ignored while this many sessions are open
+const MAX_SESSIONS: usize = 4096;
+
+// Datagrams to and from a peer, which OpenSSL reads and writes as if they were a socket
+#[derive(Default)]
+struct Datagrams {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.incoming.pop_front() {
+            Some(datagram) => {
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok(len)
+            }
+            // OpenSSL reports this as WANT_READ, until the peer sends another datagram
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for Datagrams {
+    // OpenSSL writes one datagram at a time
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Session {
+    stream: SslStream<Datagrams>,
+    last_activity: Instant,
+}
+
+// Serves the request protocol over DTLS on its own UDP socket, with a session for each peer
+pub struct DtlsServer {
+    socket: UdpSocket,
+    context: SslContext,
+    // Address of the peer of each session, used for cookies
+    peer_index: Index<Ssl, SocketAddr>,
+    sessions: HashMap<SocketAddr, Session>,
+    // Decrypted requests that haven't been returned by recv yet
+    requests: VecDeque<(SocketAddr, Vec<u8>)>,
+}
+
+impl DtlsServer {
+    // Loads the certificate and key in the config and binds the DTLS socket
+    pub async fn bind(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let peer_index = Ssl::new_ex_index()?;
+        let context = Self::context(config, peer_index)?;
+        let addr = (config.host.as_str(), config.dtls_port);
+        info!("Binding DTLS socket {}:{}", config.host, config.dtls_port);
+        Ok(DtlsServer {
+            socket: UdpSocket::bind(addr).await?,
+            context,
+            peer_index,
+            sessions: HashMap::new(),
+            requests: VecDeque::new(),
+        })
+    }
+
+    fn context(config: &Config, peer_index: Index<Ssl, SocketAddr>) -> Result<SslContext, String> {
+        let mut builder = SslContextBuilder::new(SslMethod::dtls_server())
+            .map_err(|err| format!("Could not initialize DTLS: {}", err))?;
+        builder
+            .set_certificate_chain_file(&config.dtls_cert)
+            .map_err(|err| {
+                format!(
+                    "Could not load TZD_DTLS_CERT {}: {}",
+                    config.dtls_cert.display(),
+                    err
+                )
+            })?;
+        // This also fails if the key isn't the certificate's
+        builder
+            .set_private_key_file(&config.dtls_key, SslFiletype::PEM)
+            .map_err(|err| {
+                format!(
+                    "Could not load TZD_DTLS_KEY {}: {}",
+                    config.dtls_key.display(),
+                    err
+                )
+            })?;
+
+        // Peers have to echo a cookie tied to their address before the handshake continues, so
+        // the server can't be used to send certificates to spoofed addresses
+        builder.set_options(SslOptions::COOKIE_EXCHANGE | SslOptions::NO_QUERY_MTU);
+        let mut secret = [0; 32];
+        openssl::rand::rand_bytes(&mut secret).map_err(|err| err.to_string())?;
+        let key = PKey::hmac(&secret).map_err(|err| err.to_string())?;
+        let generate_key = key.clone();
+        builder.set_cookie_generate_cb(move |ssl, buf| {
+            let cookie = cookie(&generate_key, ssl, peer_index)?;
+            buf[..cookie.len()].copy_from_slice(&cookie);
+            Ok(cookie.len())
+        });
+        builder.set_cookie_verify_cb(move |ssl, received| {
+            cookie(&key, ssl, peer_index).is_ok_and(|cookie| openssl::memcmp::eq(&cookie, received))
+        });
+        Ok(builder.build())
+    }
+
+    // Receives the next decrypted request, handling handshakes and other DTLS messages until there
+    // is one. Requests longer than buf are truncated, like they would be by a UDP socket.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut datagram = [0; DTLS_MTU as usize];
+        loop {
+            if let Some((addr, request)) = self.requests.pop_front() {
+                let len = request.len().min(buf.len());
+                buf[..len].copy_from_slice(&request[..len]);
+                return Ok((len, addr));
+            }
+            let (len, addr) = self.socket.recv_from(&mut datagram).await?;
+            self.receive(&datagram[..len], addr);
+        }
+    }
+
+    // Encrypts and sends a response to a peer, if its session is still open
+    pub fn send(&mut self, response: &[u8], addr: SocketAddr) {
+        let Some(session) = self.sessions.get_mut(&addr) else {
+            return;
+        };
+        if let Err(err) = session.stream.ssl_write(response) {
+            debug!(
+                target: REQUEST_LOG_TARGET,
+                "Could not send DTLS response to {}: {}",
+                addr,
+                err
+            );
+        }
+        send_outgoing(&self.socket, session.stream.get_mut(), addr);
+    }
+
+    // Closes sessions that haven't received anything within SESSION_TIMEOUT
+    pub fn prune(&mut self, now: Instant) {
+        self.sessions
+            .retain(|_, session| now - session.last_activity < SESSION_TIMEOUT);
+    }
+
+    fn receive(&mut self, datagram: &[u8], addr: SocketAddr) {
+        if !self.sessions.contains_key(&addr) {
+            if self.sessions.len() >= MAX_SESSIONS {
+                debug!(
+                    target: REQUEST_LOG_TARGET,
+                    "Too many DTLS sessions, ignoring datagram from {}",
+                    addr
+                );
+                return;
+            }
+            match self.new_session(addr) {
+                Ok(session) => {
+                    self.sessions.insert(addr, session);
+                }
+                Err(err) => {
+                    debug!(
+                        target: REQUEST_LOG_TARGET,
+                        "Could not start DTLS session with {}: {}",
+                        addr,
+                        err
+                    );
+                    return;
+                }
+            }
+        }
+        let Some(session) = self.sessions.get_mut(&addr) else {
+            return;
+        };
+        session.last_activity = Instant::now();
+        session
+            .stream
+            .get_mut()
+            .incoming
+            .push_back(datagram.to_vec());
+
+        // Reading continues the handshake until it's complete, then decrypts requests
+        let mut buf = [0; DTLS_MTU as usize];
+        let closed = loop {
+            match session.stream.ssl_read(&mut buf) {
+                Ok(len) => self.requests.push_back((addr, buf[..len].to_vec())),
+                Err(err) if err.code() == ErrorCode::WANT_READ => break false,
+                Err(err) if err.code() == ErrorCode::ZERO_RETURN => break true,
+                Err(err) => {
+                    debug!(
+                        target: REQUEST_LOG_TARGET,
+                        "DTLS session with {} failed: {}",
+                        addr,
+                        err
+                    );
+                    break true;
+                }
+            }
+        };
+        send_outgoing(&self.socket, session.stream.get_mut(), addr);
+        if closed {
+            self.sessions.remove(&addr);
+        }
+    }
+
+    fn new_session(&self, addr: SocketAddr) -> Result<Session, ErrorStack> {
+        let mut ssl = Ssl::new(&self.context)?;
+        ssl.set_mtu(DTLS_MTU)?;
+        ssl.set_ex_data(self.peer_index, addr);
+        ssl.set_accept_state();
+        Ok(Session {
+            stream: SslStream::new(ssl, Datagrams::default())?,
+            last_activity: Instant::now(),
+        })
+    }
+}
+
+// Cookie for the peer of a session, which is an HMAC of its address
+fn cookie(
+    key: &PKey<Private>,
+    ssl: &SslRef,
+    peer_index: Index<Ssl, SocketAddr>,
+) -> Result<Vec<u8>, ErrorStack> {
+    let addr = ssl.ex_data(peer_index).map(SocketAddr::to_string);
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    signer.update(addr.unwrap_or_default().as_bytes())?;
+    signer.sign_to_vec()
+}
+
+// Sends the datagrams OpenSSL has written for a peer. This doesn't wait for the socket to be
+// writable, so that recv can be cancelled at any time without losing session state.
+fn send_outgoing(socket: &UdpSocket, datagrams: &mut Datagrams, addr: SocketAddr) {
+    for datagram in datagrams.outgoing.drain(..) {
+        if let Err(err) = socket.try_send_to(&datagram, addr) {
+            debug!(
+                target: REQUEST_LOG_TARGET,
+                "Could not send DTLS datagram to {}: {}",
+                addr,
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    // Writes a self-signed certificate and its key to dir
+    fn self_signed(dir: &Path) -> (PathBuf, PathBuf) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_path, cert.build().to_pem().unwrap()).unwrap();
+        fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    // A connected UDP socket, which OpenSSL can use as a blocking stream
+    struct Connected(std::net::UdpSocket);
+
+    impl Read for Connected {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.recv(buf)
+        }
+    }
+
+    impl Write for Connected {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Sends each request over one DTLS session, returning the responses
+    fn client(server: SocketAddr, requests: &[&str]) -> Vec<String> {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(server).unwrap();
+        socket
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let mut connector = SslConnector::builder(SslMethod::dtls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.set_options(SslOptions::NO_QUERY_MTU);
+        let mut ssl = connector
+            .build()
+            .configure()
+            .unwrap()
+            .into_ssl("localhost")
+            .unwrap();
+        ssl.set_mtu(DTLS_MTU).unwrap();
+        let mut stream = SslStream::new(ssl, Connected(socket)).unwrap();
+        stream.connect().unwrap();
+
+        requests
+            .iter()
+            .map(|request| {
+                stream.ssl_write(request.as_bytes()).unwrap();
+                let mut buf = [0; DTLS_MTU as usize];
+                let len = stream.ssl_read(&mut buf).unwrap();
+                String::from_utf8(buf[..len].to_vec()).unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = fixtures::config();
+        config.host = "127.0.0.1".into();
+        (config.dtls_cert, config.dtls_key) = self_signed(dir.path());
+        let mut server = DtlsServer::bind(&config).await.unwrap();
+        let addr = server.socket.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || client(addr, &["Europe/London", "MYIP"]));
+        for _ in 0..2 {
+            let mut buf = [0; 512];
+            let (len, peer) = server.recv(&mut buf).await.unwrap();
+            let response = format!("OK {} {}", String::from_utf8_lossy(&buf[..len]), peer);
+            server.send(response.as_bytes(), peer);
+        }
+        let responses = tokio::task::spawn_blocking(move || client.join().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].starts_with("OK Europe/London 127.0.0.1:"));
+        assert!(responses[1].starts_with("OK MYIP 127.0.0.1:"));
+
+        // Sessions are closed once they've been idle for SESSION_TIMEOUT
+        assert_eq!(server.sessions.len(), 1);
+        server.prune(Instant::now() + SESSION_TIMEOUT);
+        assert!(server.sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = fixtures::config();
+        config.host = "127.0.0.1".into();
+        (config.dtls_cert, config.dtls_key) = self_signed(dir.path());
+        config.dtls_cert = dir.path().join("missing.pem");
+        let err = DtlsServer::bind(&config).await.err().unwrap().to_string();
+        assert!(err.starts_with("Could not load TZD_DTLS_CERT "), "{}", err);
+
+        // A key that isn't for the certificate
+        let other = tempfile::tempdir().unwrap();
+        (config.dtls_cert, _) = self_signed(dir.path());
+        (_, config.dtls_key) = self_signed(other.path());
+        let err = DtlsServer::bind(&config).await.err().unwrap().to_string();
+        assert!(err.starts_with("Could not load TZD_DTLS_KEY "), "{}", err);
+    }
+}
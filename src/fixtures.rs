@@ -0,0 +1,155 @@
+// Timezone data and settings for tests. The timezones are a small part of tzdata, picked to cover
+// timezones with and without DST in both hemispheres, with numeric abbreviations and offsets that
+// aren't whole hours, and that are shared by several countries.
+
+use crate::request::Context;
+use crate::{Config, TimezoneDb};
+#[cfg(test)]
+use crate::{GeoIpDb, GeoIpLocation, GeoIpReader};
+use std::collections::HashMap;
+#[cfg(test)]
+use std::net::IpAddr;
+#[cfg(test)]
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+#[cfg(test)]
+use std::time::{Duration, UNIX_EPOCH};
+
+pub const POSIXINFO: &str = "\
+Africa/Abidjan GMT0
+America/New_York EST5EDT,M3.2.0,M11.1.0
+America/Chicago CST6CDT,M3.2.0,M11.1.0
+America/Argentina/Buenos_Aires <-03>3
+America/St_Johns NST3:30NDT,M3.2.0,M11.1.0
+Asia/Riyadh <+03>-3
+Asia/Kathmandu <+0545>-5:45
+Asia/Kolkata IST-5:30
+Australia/Sydney AEST-10AEDT,M10.1.0,M4.1.0/3
+Australia/Lord_Howe <+1030>-10:30<+11>-11,M10.1.0,M4.1.0
+Australia/Brisbane AEST-10
+Etc/UTC UTC0
+Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3
+Europe/Dublin IST-1GMT0,M10.5.0,M3.5.0/1
+Europe/Istanbul <+03>-3
+Europe/London GMT0BST,M3.5.0/1,M10.5.0
+Europe/Zurich CET-1CEST,M3.5.0,M10.5.0/3
+Pacific/Marquesas <-0930>9:30
+";
+
+pub const ZONETAB: &str = "\
+# tz zone descriptions
+AR\t-3436-05827\tAmerica/Argentina/Buenos_Aires\tBuenos Aires (BA, CF)
+AU\t-3133+15905\tAustralia/Lord_Howe\tLord Howe Island
+AU\t-3352+15113\tAustralia/Sydney\tNew South Wales (most areas)
+AU\t-2728+15302\tAustralia/Brisbane\tQueensland (most areas)
+CA\t+4734-05243\tAmerica/St_Johns\tNewfoundland, Labrador (SE)
+CH,DE,LI\t+4723+00832\tEurope/Zurich\tBüsingen
+CI,BF,GH,GM,GN,IS,ML,MR,SH,SL,SN,TG\t+0519-00402\tAfrica/Abidjan
+DE,DK,NO,SE,SJ\t+5230+01322\tEurope/Berlin\tmost of Germany
+GB,GG,IM,JE\t+513030-0000731\tEurope/London
+IE\t+5320-00615\tEurope/Dublin
+IN\t+2232+08822\tAsia/Kolkata
+NP\t+2743+08519\tAsia/Kathmandu
+PF\t-0900-13930\tPacific/Marquesas\tMarquesas Islands
+SA,AQ,KW,YE\t+2438+04643\tAsia/Riyadh\tSyowa
+TR\t+4101+02858\tEurope/Istanbul
+US\t+404251-0740023\tAmerica/New_York\tEastern (most areas)
+US\t+415100-0873900\tAmerica/Chicago\tCentral (most areas)
+";
+
+pub fn timezone_db() -> TimezoneDb {
+    TimezoneDb::parse(POSIXINFO.as_bytes(), ZONETAB.as_bytes(), &[]).unwrap()
+}
+
+// Default settings, as if no TZD_ variables were set
+pub fn config() -> Config {
+    Config::from_file(&HashMap::new()).unwrap()
+}
+
+// Settings with a new, empty data directory, which is removed when the TempDir is dropped
+#[cfg(test)]
+pub fn config_with_data_dir() -> (Config, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = config();
+    config.data_dir = dir.path().to_owned();
+    (config, dir)
+}
+
+// Path of a file in testdata/. GeoLite2-City.mmdb and GeoLite2-Country.mmdb there are small
+// databases in each schema, with these records:
+//
+// City: 192.0.2.0/24 is Europe/Zurich in CH-ZH, 198.51.100.0/24 is IN with no timezone, and
+// 2001:db8::/32 is America/Chicago in US-IL
+// Country: 192.0.2.0/24 is IN, and 198.51.100.0/24 is US
+#[cfg(test)]
+pub fn testdata(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata")
+        .join(name)
+}
+
+// Context for a request without a GeoIP database
+pub fn context<'a>(config: &'a Config, timezones: &'a TimezoneDb) -> Context<'a> {
+    Context {
+        config,
+        timezones,
+        geoip: None,
+        geoip_cache: None,
+        response_cache: None,
+        reloading: false,
+        data_stale: false,
+        tz_refresh_at: None,
+        geoip_refresh_at: None,
+        geoip_reloaded: None,
+        now: SystemTime::now(),
+    }
+}
+
+// An RFC 3339 time, for the time a request is answered for
+#[cfg(test)]
+pub fn time(rfc3339: &str) -> SystemTime {
+    let time = crate::posix::parse_rfc3339(rfc3339).unwrap();
+    UNIX_EPOCH + Duration::from_secs(time as u64)
+}
+
+// A GeoIP record with the fields the server reads from a City database
+#[cfg(test)]
+#[derive(Default)]
+pub struct GeoIpRecord {
+    pub timezone: Option<&'static str>,
+    pub country: Option<&'static str>,
+    pub subdivision: Option<&'static str>,
+}
+
+#[cfg(test)]
+struct GeoIpRecords(HashMap<IpAddr, GeoIpRecord>);
+
+#[cfg(test)]
+impl GeoIpReader for GeoIpRecords {
+    fn location(&self, addr: IpAddr) -> Option<GeoIpLocation<'_>> {
+        let record = self.0.get(&addr)?;
+        Some(GeoIpLocation {
+            timezone: record.timezone,
+            country: record.country,
+            subdivision: record.subdivision,
+        })
+    }
+
+    fn record(&self, addr: IpAddr) -> Option<serde_json::Value> {
+        let record = self.0.get(&addr)?;
+        Some(serde_json::json!({
+            "country": {"iso_code": record.country},
+            "location": {"time_zone": record.timezone},
+        }))
+    }
+}
+
+// GeoIP database with only the given records, by address
+#[cfg(test)]
+pub fn geoip(records: impl IntoIterator<Item = (&'static str, GeoIpRecord)>) -> GeoIpDb {
+    let records = records
+        .into_iter()
+        .map(|(addr, record)| (addr.parse().unwrap(), record))
+        .collect();
+    GeoIpDb::new(GeoIpRecords(records))
+}
@@ -0,0 +1,112 @@
+use lru::LruCache;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::path::Path;
+
+// GeoIP results are cached by subnet, since addresses in the same subnet are almost always in the
+// same timezone
+const IPV4_PREFIX_LEN: u32 = 24;
+const IPV6_PREFIX_LEN: u32 = 48;
+
+// Cache of GeoIP timezones by subnet, which can be saved to a file so it survives restarts
+pub struct GeoIpCache {
+    entries: LruCache<IpAddr, String>,
+}
+
+impl GeoIpCache {
+    pub fn new(size: NonZeroUsize) -> Self {
+        GeoIpCache {
+            entries: LruCache::new(size),
+        }
+    }
+
+    pub fn get(&mut self, addr: IpAddr) -> Option<&str> {
+        self.entries.get(&subnet_of(addr)).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, addr: IpAddr, olson: &str) {
+        self.entries.put(subnet_of(addr), olson.to_owned());
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Loads entries from a file written by save. If the file has more entries than fit in the
+    // cache, only the most recently used ones are kept.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        let entries = serde_json::from_slice::<Vec<(IpAddr, String)>>(&fs::read(path)?)?;
+        // Entries are saved most recently used first
+        for (subnet, olson) in entries.into_iter().take(self.entries.cap().get()).rev() {
+            self.entries.put(subnet_of(subnet), olson);
+        }
+        Ok(())
+    }
+
+    // Saves every entry to a file, most recently used first. The file is replaced atomically, so
+    // it's never left partially written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let new_path = path.with_extension("json.new");
+        let entries = self.entries.iter().collect::<Vec<_>>();
+        fs::write(&new_path, serde_json::to_vec(&entries)?)?;
+        fs::rename(new_path, path)?;
+        Ok(())
+    }
+}
+
+fn subnet_of(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => IpAddr::V4(Ipv4Addr::from(
+            u32::from(addr) & !(u32::MAX >> IPV4_PREFIX_LEN),
+        )),
+        IpAddr::V6(addr) => IpAddr::V6(Ipv6Addr::from(
+            u128::from(addr) & !(u128::MAX >> IPV6_PREFIX_LEN),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn subnets() {
+        let mut cache = GeoIpCache::new(NonZeroUsize::new(4).unwrap());
+        cache.insert(ip("192.0.2.1"), "Europe/London");
+        cache.insert(ip("2001:db8:1:2::1"), "Asia/Kolkata");
+        assert_eq!(cache.get(ip("192.0.2.200")), Some("Europe/London"));
+        assert_eq!(cache.get(ip("192.0.3.1")), None);
+        assert_eq!(cache.get(ip("2001:db8:1:ffff::1")), Some("Asia/Kolkata"));
+        assert_eq!(cache.get(ip("2001:db8:2::1")), None);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("geoip_cache.json");
+        let mut cache = GeoIpCache::new(NonZeroUsize::new(4).unwrap());
+        cache.insert(ip("192.0.2.1"), "Europe/London");
+        cache.insert(ip("198.51.100.1"), "Asia/Kolkata");
+        cache.insert(ip("203.0.113.1"), "Etc/UTC");
+        cache.save(&path).unwrap();
+
+        // Only the most recently used entries fit in a smaller cache
+        let mut cache = GeoIpCache::new(NonZeroUsize::new(2).unwrap());
+        cache.load(&path).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(ip("192.0.2.1")), None);
+        assert_eq!(cache.get(ip("198.51.100.1")), Some("Asia/Kolkata"));
+        assert_eq!(cache.get(ip("203.0.113.1")), Some("Etc/UTC"));
+    }
+}
@@ -0,0 +1,4074 @@
+#[macro_use]
+mod macros;
+mod country_names;
+mod dial_codes;
+#[cfg(feature = "dtls")]
+mod dtls;
+#[cfg(any(test, fuzzing))]
+mod fixtures;
+mod geoip_cache;
+mod logger;
+#[cfg(feature = "metrics")]
+mod metrics_push;
+#[cfg(feature = "metrics")]
+mod metrics_unix;
+mod posix;
+mod privileges;
+mod request;
+mod socket;
+
+use country_names::COUNTRY_NAMES;
+use futures::stream::{unfold, StreamExt};
+use geoip_cache::GeoIpCache;
+use log::{debug, error, info, warn};
+use lru::LruCache;
+use maxminddb::geoip2;
+use posix::Posix;
+use request::{
+    is_not_found, respond_default, Context, Request, ERR_DATA_TOO_STALE, ERR_GEOIP_DISABLED,
+    ERR_GEOIP_UNAVAILABLE, ERR_INITIALIZING, ERR_RATE_LIMITED, ERR_RESPONSE_TOO_LARGE,
+    ERR_TIMEZONE_NOT_FOUND,
+};
+use std::cell::{Cell, RefCell};
+#[cfg(feature = "metrics")]
+use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io::{self, BufRead};
+#[cfg(feature = "metrics")]
+use std::net::Ipv4Addr;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use tokio::net::{unix, UdpSocket, UnixDatagram};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::{interval_at, Duration, Instant, Interval, MissedTickBehavior};
+use tokio::{pin, select};
+
+#[cfg(feature = "dtls")]
+use dtls::DtlsServer;
+// Without the dtls feature, there is never a DTLS server
+#[cfg(not(feature = "dtls"))]
+type DtlsServer = Infallible;
+
+const MAX_REQUEST_SIZE: usize = 512;
+// Datagrams longer than the receive buffer are truncated to fit, so it has room for one more byte
+// than the largest request, to tell a request of exactly MAX_REQUEST_SIZE from a truncated one
+const RECV_BUFFER_SIZE: usize = MAX_REQUEST_SIZE + 1;
+// Length of the shortest valid requests, which are country codes
+const MIN_VALID_REQUEST_SIZE: usize = 2;
+// Requests logged by TZD_LOG_UNKNOWN are truncated to this many characters
+const MAX_LOGGED_REQUEST_CHARS: usize = 64;
+const SECONDS_PER_DAY: u64 = 86400;
+// Update script output is truncated to this many bytes from its end when logged
+const MAX_LOGGED_SCRIPT_OUTPUT: usize = 4096;
+
+const UPDATE_TZDATA_SH_PATH: &str = "./update_tzdata.sh";
+const UPDATE_MMDB_SH_PATH: &str = "./update_mmdb.sh";
+const EXTRACT_DATA_SH_PATH: &str = "./extract_data.sh";
+const MAXMIND_CITY_URL: &str =
+    "https://download.maxmind.com/app/geoip_download?edition_id=GeoLite2-City&suffix=tar.gz";
+const POSIXINFO_FILE: &str = "posixinfo";
+// posixinfo from before the last refresh, kept by update_tzdata.sh for DIFFTZ
+const PREVIOUS_POSIXINFO_FILE: &str = "posixinfo.prev";
+const ZONETAB_FILE: &str = "zone1970.tab";
+// tzdata's links from deprecated timezone names to current ones
+const BACKWARD_FILE: &str = "backward";
+const MMDB_CITY_FILE: &str = "GeoLite2-City.mmdb";
+const OVERRIDES_FILE: &str = "overrides";
+// Overrides downloaded from TZD_OVERRIDES_URL by update_tzdata.sh
+const REMOTE_OVERRIDES_FILE: &str = "overrides.remote";
+const GEOIP_CACHE_FILE: &str = "geoip_cache.json";
+
+// Number of requests that got a not found response, used to sample them for TZD_LOG_UNKNOWN
+static UNKNOWN_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+// Fallback timezone data used when the data directory doesn't have any, embedded at build time
+// from the directory in TZD_EMBEDDED_DATA_DIR
+#[cfg(feature = "embedded-data")]
+const EMBEDDED_POSIXINFO: &[u8] =
+    include_bytes!(concat!(env!("TZD_EMBEDDED_DATA_DIR"), "/posixinfo"));
+#[cfg(feature = "embedded-data")]
+const EMBEDDED_ZONETAB: &[u8] =
+    include_bytes!(concat!(env!("TZD_EMBEDDED_DATA_DIR"), "/zone1970.tab"));
+
+#[derive(Debug)]
+struct Timezone {
+    olson: String,
+    // POSIX string as it is in tzdata
+    posix: String,
+    // POSIX string that is served instead of the one from tzdata, if it has been rewritten
+    posix_override: Option<String>,
+    // Comment from zone1970.tab, if the timezone has one
+    description: Option<String>,
+    // Latitude and longitude in degrees from zone1970.tab, if the timezone is in it
+    coordinates: Option<(f64, f64)>,
+    // Name returned to clients instead of olson, for clients that expect a legacy name
+    response_alias: Option<String>,
+}
+
+impl Timezone {
+    // Name returned to clients in responses
+    fn response_olson(&self) -> &str {
+        self.response_alias.as_deref().unwrap_or(&self.olson)
+    }
+
+    // Friendly name for the timezone from the last part of its name, like Buenos Aires for
+    // America/Argentina/Buenos_Aires
+    fn label(&self) -> String {
+        let city = self
+            .olson
+            .rsplit_once('/')
+            .map_or(&*self.olson, |(_, city)| city);
+        city.replace('_', " ")
+    }
+
+    fn posix(&self) -> &str {
+        self.posix_override.as_deref().unwrap_or(&self.posix)
+    }
+
+    fn rule(&self) -> Option<Posix> {
+        Posix::parse(self.posix()).ok()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Country {
+    timezones: Vec<usize>,
+    // Timezone returned for this country when it spans multiple timezones
+    primary: Option<usize>,
+}
+
+// Why the timezone or GeoIP database couldn't be loaded. Only missing files are fixed by
+// downloading the data again, so callers decide whether to refresh based on this.
+#[derive(Debug)]
+enum LoadError {
+    FileNotFound(PathBuf),
+    // Line number in posixinfo, starting from 1
+    Parse { line: usize, reason: String },
+    Mmdb(maxminddb::MaxMindDBError),
+    Io(PathBuf, io::Error),
+}
+
+impl LoadError {
+    fn from_io(path: &Path, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => LoadError::FileNotFound(path.to_owned()),
+            _ => LoadError::Io(path.to_owned(), err),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::FileNotFound(path) => write!(f, "{} does not exist", path.display()),
+            LoadError::Parse { line, reason } => {
+                write!(f, "{} line {}: {}", POSIXINFO_FILE, line, reason)
+            }
+            LoadError::Mmdb(err) => write!(f, "invalid GeoIP database: {}", err),
+            LoadError::Io(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Mmdb(err) => Some(err),
+            LoadError::Io(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TimezoneDb {
+    timezones: Vec<Timezone>,
+    olson_map: HashMap<String, usize>,
+    // Timezones by the city portion of their name, which isn't always unique
+    city_map: HashMap<String, Vec<usize>>,
+    country_map: HashMap<String, Country>,
+    // Country codes by normalized country name
+    country_name_map: HashMap<String, &'static str>,
+    // Timezones by their deprecated names from tzdata's backward file, only used for GeoIP results
+    // and labels
+    link_map: HashMap<String, usize>,
+    // POSIX strings from before the last refresh by normalized timezone name, for DIFFTZ
+    previous_posix_map: HashMap<String, String>,
+    loaded_at: SystemTime,
+    // Time taken to read and parse the database
+    load_duration: Duration,
+}
+
+impl TimezoneDb {
+    async fn update(config: &Config) -> Result<(), Box<dyn Error>> {
+        info!("Updating timezone database...");
+        sh!(
+            config.update_timeout,
+            UPDATE_TZDATA_SH_PATH,
+            &config.data_dir,
+            &config.overrides_url
+        )
+        .await
+    }
+
+    fn load(config: &Config) -> Result<Self, LoadError> {
+        let started_at = Instant::now();
+        let posixinfo = config.data_path(POSIXINFO_FILE);
+        let zonetab = config.data_path(ZONETAB_FILE);
+        let mut db = match (open_file(&posixinfo), open_file(&zonetab)) {
+            (Ok(posixinfo_file), Ok(zonetab_file)) => {
+                info!(
+                    "Loading timezones from {} and {}",
+                    posixinfo.display(),
+                    zonetab.display()
+                );
+                Self::parse(posixinfo_file, zonetab_file, &config.zone_prefix_allow)?
+            }
+            #[cfg(feature = "embedded-data")]
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("Could not open timezone data files: {}", err);
+                warn!(
+                    "Loading timezones from embedded data, which is only as recent as this build"
+                );
+                Self::parse(
+                    EMBEDDED_POSIXINFO,
+                    EMBEDDED_ZONETAB,
+                    &config.zone_prefix_allow,
+                )?
+            }
+            // Timezone names can still be looked up without countries
+            #[cfg(not(feature = "embedded-data"))]
+            (Ok(posixinfo_file), Err(err)) => {
+                warn!("Could not open {}: {}", zonetab.display(), err);
+                warn!(
+                    "Loading timezones from {} without countries. Every country request will \
+                     return '{}'",
+                    posixinfo.display(),
+                    String::from_utf8_lossy(request::ERR_COUNTRY_NOT_FOUND)
+                );
+                Self::parse(posixinfo_file, io::empty(), &config.zone_prefix_allow)?
+            }
+            #[cfg(not(feature = "embedded-data"))]
+            (Err(err), _) => return Err(LoadError::from_io(&posixinfo, err)),
+        };
+
+        // Read operator overrides, which are optional. Remote overrides are applied last, so they
+        // take precedence over local ones.
+        db.load_overrides(&config.data_path(OVERRIDES_FILE));
+        if !config.overrides_url.is_empty() {
+            db.load_overrides(&config.data_path(REMOTE_OVERRIDES_FILE));
+        }
+
+        db.load_links(&config.data_path(BACKWARD_FILE));
+        db.load_previous(&config.data_path(PREVIOUS_POSIXINFO_FILE));
+
+        // Read names to return in place of the tzdata ones, if configured
+        if !config.response_alias_map.as_os_str().is_empty() {
+            let path = &config.response_alias_map;
+            match read_file_lines(path) {
+                Ok(lines) => {
+                    info!("Loading response aliases from {}", path.display());
+                    for line in lines {
+                        if line.starts_with('#') || line.trim().is_empty() {
+                            continue;
+                        }
+                        let result = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                            [olson, alias] => db.set_response_alias(olson, alias),
+                            _ => Err(format!(
+                                "response alias entry is improperly formatted: {}",
+                                line
+                            )),
+                        };
+                        if let Err(err) = result {
+                            warn!("{}, skipping", err);
+                        }
+                    }
+                }
+                Err(err) => warn!(
+                    "Could not load response aliases from {}: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+
+        db.load_duration = started_at.elapsed();
+        debug!("Timezone database loaded in {:?}", db.load_duration);
+        Ok(db)
+    }
+
+    // Applies the overrides in a file, skipping invalid entries so a bad file can't prevent the
+    // timezone database from loading
+    fn load_overrides(&mut self, path: &Path) {
+        let lines = match read_file_lines(path) {
+            Ok(lines) => lines,
+            Err(err) => {
+                debug!("No overrides loaded from {}: {}", path.display(), err);
+                return;
+            }
+        };
+        info!("Loading overrides from {}", path.display());
+        let mut applied = 0;
+        for line in lines {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let result = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["PRIMARY", country, olson] => self.set_country_primary(country, olson),
+                _ => Err(format!("overrides entry is improperly formatted: {}", line)),
+            };
+            match result {
+                Ok(()) => applied += 1,
+                Err(err) => warn!("{}, skipping", err),
+            }
+        }
+        info!("Applied {} overrides from {}", applied, path.display());
+    }
+
+    // Reads the links from deprecated timezone names to current ones, which are optional. Links to
+    // timezones that don't exist are skipped.
+    fn load_links(&mut self, path: &Path) {
+        let lines = match read_file_lines(path) {
+            Ok(lines) => lines,
+            Err(err) => {
+                debug!("No timezone links loaded from {}: {}", path.display(), err);
+                return;
+            }
+        };
+        for line in lines {
+            // Links are in the form "L <target> <link>", with an optional comment after them
+            if let ["L", target, link, ..] = line.split_whitespace().collect::<Vec<_>>()[..] {
+                if let Some(index) = self.olson_map.get(&normalize_string(target)) {
+                    self.link_map.insert(normalize_string(link), *index);
+                }
+            }
+        }
+        info!(
+            "{} timezone links loaded from {}",
+            self.link_map.len(),
+            path.display()
+        );
+    }
+
+    // Reads the POSIX strings from before the last refresh, which are optional
+    fn load_previous(&mut self, path: &Path) {
+        let lines = match read_file_lines(path) {
+            Ok(lines) => lines,
+            Err(err) => {
+                debug!(
+                    "No previous timezones loaded from {}: {}",
+                    path.display(),
+                    err
+                );
+                return;
+            }
+        };
+        for line in lines {
+            if let [olson, posix] = line.split_whitespace().collect::<Vec<_>>()[..] {
+                self.previous_posix_map
+                    .insert(normalize_string(olson), posix.to_owned());
+            }
+        }
+        info!(
+            "{} previous timezones loaded from {}",
+            self.previous_posix_map.len(),
+            path.display()
+        );
+    }
+
+    // Parses the timezone data, keeping only timezones whose normalized names start with one of
+    // zone_prefixes, or every timezone if there are none
+    fn parse(
+        posixinfo: impl BufRead,
+        zonetab: impl BufRead,
+        zone_prefixes: &[String],
+    ) -> Result<Self, LoadError> {
+        let allowed = |olson: &str| {
+            let olson = normalize_string(olson);
+            zone_prefixes.is_empty() || zone_prefixes.iter().any(|prefix| olson.starts_with(prefix))
+        };
+        let mut db = TimezoneDb {
+            timezones: Vec::new(),
+            olson_map: HashMap::new(),
+            city_map: HashMap::new(),
+            country_map: HashMap::new(),
+            country_name_map: COUNTRY_NAMES
+                .iter()
+                .map(|(name, code)| (normalize_string(name), *code))
+                .collect(),
+            link_map: HashMap::new(),
+            previous_posix_map: HashMap::new(),
+            loaded_at: SystemTime::now(),
+            load_duration: Duration::ZERO,
+        };
+
+        // Read timezones
+        let mut filtered = 0;
+        for (index, line) in read_lines(posixinfo).enumerate() {
+            let [olson, posix] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                warn!(
+                    "posixinfo entry is improperly formatted, skipping: {}",
+                    line
+                );
+                continue;
+            };
+            if !allowed(olson) {
+                filtered += 1;
+                continue;
+            }
+            db.add_timezone(olson, posix)
+                .map_err(|reason| LoadError::Parse {
+                    line: index + 1,
+                    reason,
+                })?;
+        }
+        info!("{} timezones loaded", db.timezones.len());
+        if filtered > 0 {
+            info!(
+                "{} timezones filtered out by TZD_ZONE_PREFIX_ALLOW",
+                filtered
+            );
+        }
+
+        // Read countries
+        for line in read_lines(zonetab) {
+            if line.starts_with('#') {
+                continue;
+            }
+            let [countries, coordinates, olson, ref comments @ ..] =
+                line.split('\t').collect::<Vec<_>>()[..]
+            else {
+                warn!(
+                    "zone1970.tab entry is improperly formatted, skipping: {}",
+                    line
+                );
+                continue;
+            };
+            // Countries of filtered timezones only have their other timezones
+            if !allowed(olson) {
+                continue;
+            }
+            for country in countries.split(',') {
+                if let Err(err) = db.add_country_timezone(country, olson) {
+                    warn!("{}, skipping", err);
+                }
+            }
+            if let Some(tz) = db.lookup_olson_mut(&normalize_string(olson)) {
+                tz.description = comments.first().map(|comment| comment.to_string());
+                tz.coordinates = parse_coordinates(coordinates);
+            }
+        }
+        info!("{} countries loaded", db.country_map.len());
+
+        // Custom timezone rules, currently copied as-is from eztime
+
+        if let Some(gb) = db.country_map.get("GB") {
+            // https://github.com/ropg/ezTime/blob/7b3c8aa020be818ac149e0762543ac5e81ccfabe/server/server#L112
+            debug!("Aliasing 'UK' to 'GB'");
+            db.country_map.insert("UK".into(), gb.clone());
+        }
+
+        if let Some(index) = db
+            .olson_map
+            .get("EUROPE/BERLIN")
+            .filter(|_| db.country_map.contains_key("DE"))
+        {
+            // https://github.com/ropg/ezTime/blob/7b3c8aa020be818ac149e0762543ac5e81ccfabe/server/server#L113
+            debug!("Overriding 'DE' to 'Europe/Berlin'");
+            db.country_map.insert(
+                "DE".into(),
+                Country {
+                    timezones: vec![*index],
+                    primary: None,
+                },
+            );
+        }
+
+        if let Some(dublin) = db.lookup_olson_mut("EUROPE/DUBLIN") {
+            // https://github.com/ropg/ezTime/blob/7b3c8aa020be818ac149e0762543ac5e81ccfabe/server/server#L152
+            // https://github.com/ropg/ezTime/issues/65
+            // https://github.com/ropg/ezTime/issues/159
+            debug!("Rewriting timezone 'Europe/Dublin'");
+            dublin.posix_override = Some("GMT0IST,M3.5.0/1,M10.5.0".into());
+        }
+
+        Ok(db)
+    }
+
+    fn refreshed_at(config: &Config) -> Option<SystemTime> {
+        file_last_modified(config.data_path(POSIXINFO_FILE)).ok()
+    }
+
+    // Whether the data is older than max_data_age. Data of unknown age, like embedded data, is
+    // considered stale.
+    fn is_stale(config: &Config) -> bool {
+        let Some(max_age) = config.max_data_age else {
+            return false;
+        };
+        Self::refreshed_at(config)
+            .and_then(|time| SystemTime::now().duration_since(time).ok())
+            .is_none_or(|age| age > max_age)
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record_metrics(&self) {
+        metrics::gauge!("timezoned_timezones_loaded", self.timezones.len() as f64);
+        metrics::gauge!("timezoned_countries_loaded", self.country_map.len() as f64);
+        metrics::gauge!(
+            "timezoned_tzdb_load_duration_seconds",
+            self.load_duration.as_secs_f64()
+        );
+    }
+
+    fn add_timezone(&mut self, olson: &str, posix: &str) -> Result<(), String> {
+        let entry = Timezone {
+            olson: olson.to_owned(),
+            posix: normalize_posix(posix),
+            posix_override: None,
+            description: None,
+            coordinates: None,
+            response_alias: None,
+        };
+        let key = normalize_string(olson);
+        if self.olson_map.contains_key(&key) {
+            return Err(format!("Timezone '{}' already added to database", olson));
+        }
+
+        debug!("Adding timezone {} {}", olson, posix);
+        self.timezones.push(entry);
+        self.olson_map.insert(key, self.timezones.len() - 1);
+        if let Some((_, city)) = olson.rsplit_once('/') {
+            self.city_map
+                .entry(normalize_string(city))
+                .or_default()
+                .push(self.timezones.len() - 1);
+        }
+        Ok(())
+    }
+
+    fn add_country_timezone(&mut self, country: &str, olson: &str) -> Result<(), String> {
+        let index = self.olson_map.get(&normalize_string(olson)).ok_or(format!(
+            "Attempted to add country '{}' to nonexistent timezone '{}'",
+            country, olson
+        ))?;
+
+        let key = normalize_string(country);
+        let entry = self.country_map.entry(key).or_default();
+        if entry.timezones.contains(index) {
+            return Err(format!(
+                "Country '{}' already contains timezone '{}'",
+                country, olson
+            ));
+        }
+
+        debug!("Adding country {} to {}", country, olson);
+        entry.timezones.push(*index);
+        Ok(())
+    }
+
+    fn set_country_primary(&mut self, country: &str, olson: &str) -> Result<(), String> {
+        let index = self.olson_map.get(&normalize_string(olson)).ok_or(format!(
+            "Attempted to set primary timezone of country '{}' to nonexistent timezone '{}'",
+            country, olson
+        ))?;
+        let entry = self
+            .country_map
+            .get_mut(&normalize_string(country))
+            .ok_or(format!(
+                "Attempted to set primary timezone of nonexistent country '{}'",
+                country
+            ))?;
+        if !entry.timezones.contains(index) {
+            return Err(format!(
+                "Country '{}' does not contain timezone '{}'",
+                country, olson
+            ));
+        }
+
+        debug!(
+            "Setting primary timezone of country {} to {}",
+            country, olson
+        );
+        entry.primary = Some(*index);
+        Ok(())
+    }
+
+    // Makes a timezone respond with alias as its name, and makes it available under that name too
+    fn set_response_alias(&mut self, olson: &str, alias: &str) -> Result<(), String> {
+        let index = *self.olson_map.get(&normalize_string(olson)).ok_or(format!(
+            "Attempted to alias nonexistent timezone '{}' as '{}'",
+            olson, alias
+        ))?;
+        match self.olson_map.get(&normalize_string(alias)) {
+            Some(existing) if *existing != index => {
+                return Err(format!(
+                    "Cannot alias timezone '{}' as existing timezone '{}'",
+                    olson, alias
+                ))
+            }
+            Some(_) => {}
+            None => {
+                self.olson_map.insert(normalize_string(alias), index);
+            }
+        }
+
+        debug!("Responding to timezone {} as {}", olson, alias);
+        self.timezones[index].response_alias = Some(alias.to_owned());
+        Ok(())
+    }
+
+    // Timezones are keyed by their normalized name, but keep their name as it is in tzdata, so
+    // responses must use the name of the timezone that was found rather than the key
+    fn lookup_olson(&self, normalized_olson: &str) -> Option<&Timezone> {
+        self.olson_map
+            .get(normalized_olson)
+            .and_then(|index| self.timezones.get(*index))
+    }
+
+    fn lookup_link(&self, normalized_link: &str) -> Option<&Timezone> {
+        self.link_map
+            .get(normalized_link)
+            .and_then(|index| self.timezones.get(*index))
+    }
+
+    fn lookup_previous_posix(&self, normalized_olson: &str) -> Option<&str> {
+        self.previous_posix_map
+            .get(normalized_olson)
+            .map(String::as_str)
+    }
+
+    fn lookup_olson_mut(&mut self, normalized_olson: &str) -> Option<&mut Timezone> {
+        self.olson_map
+            .get(normalized_olson)
+            .and_then(|index| self.timezones.get_mut(*index))
+    }
+
+    fn lookup_city(&self, normalized_city: &str) -> Option<Vec<&Timezone>> {
+        self.city_map.get(normalized_city).map(|indices| {
+            indices
+                .iter()
+                .filter_map(|index| self.timezones.get(*index))
+                .collect::<Vec<_>>()
+        })
+    }
+
+    // Every timezone with the given UTC offset at a unix time, sorted by name
+    fn lookup_offset(&self, offset: i32, time: i64) -> Vec<&Timezone> {
+        let mut tzs = self
+            .timezones
+            .iter()
+            .filter(|tz| tz.rule().is_some_and(|rule| rule.offset_at(time) == offset))
+            .collect::<Vec<_>>();
+        tzs.sort_by(|a, b| a.olson.cmp(&b.olson));
+        tzs
+    }
+
+    // Every timezone grouped by its UTC offset at a unix time, sorted by offset and then by name
+    fn offset_groups(&self, time: i64) -> Vec<(i32, Vec<&Timezone>)> {
+        let mut groups = BTreeMap::<i32, Vec<&Timezone>>::new();
+        for tz in &self.timezones {
+            if let Some(rule) = tz.rule() {
+                groups.entry(rule.offset_at(time)).or_default().push(tz);
+            }
+        }
+        groups
+            .into_iter()
+            .map(|(offset, mut tzs)| {
+                tzs.sort_by(|a, b| a.olson.cmp(&b.olson));
+                (offset, tzs)
+            })
+            .collect()
+    }
+
+    fn lookup_country(&self, normalized_country: &str) -> Option<Vec<&Timezone>> {
+        self.country_map.get(normalized_country).map(|country| {
+            country
+                .timezones
+                .iter()
+                .filter_map(|index| self.timezones.get(*index))
+                .collect::<Vec<_>>()
+        })
+    }
+
+    // Codes of every country that uses a timezone, sorted. This scans every country, which is fine
+    // since there are only a few hundred.
+    fn lookup_timezone_countries(&self, normalized_olson: &str) -> Option<Vec<&str>> {
+        let index = self.olson_map.get(normalized_olson)?;
+        let mut countries = self
+            .country_map
+            .iter()
+            .filter(|(_, country)| country.timezones.contains(index))
+            .map(|(code, _)| code.as_str())
+            .collect::<Vec<_>>();
+        countries.sort_unstable();
+        Some(countries)
+    }
+
+    fn lookup_country_name(&self, normalized_name: &str) -> Option<&'static str> {
+        self.country_name_map.get(normalized_name).copied()
+    }
+
+    fn lookup_country_primary(&self, normalized_country: &str) -> Option<&Timezone> {
+        self.country_map
+            .get(normalized_country)
+            .and_then(|country| country.primary)
+            .and_then(|index| self.timezones.get(index))
+    }
+}
+
+struct GeoIpLocation<'a> {
+    timezone: Option<&'a str>,
+    // ISO 3166 country code
+    country: Option<&'a str>,
+    // ISO 3166-2 code of the largest subdivision, like a US state, without the country code
+    subdivision: Option<&'a str>,
+}
+
+// Schema of a GeoIP database. Country databases don't have timezones, so lookups fall back to the
+// timezone of the country.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GeoIpDbType {
+    City,
+    Country,
+}
+
+// Where GeoIP records are looked up, which is a MaxMind database outside of tests
+trait GeoIpReader {
+    fn location(&self, addr: IpAddr) -> Option<GeoIpLocation<'_>>;
+    // The whole record for an address, in whatever format the database uses
+    fn record(&self, addr: IpAddr) -> Option<serde_json::Value>;
+}
+
+struct MmdbReader {
+    reader: maxminddb::Reader<maxminddb::Mmap>,
+    db_type: GeoIpDbType,
+}
+
+impl GeoIpReader for MmdbReader {
+    fn location(&self, addr: IpAddr) -> Option<GeoIpLocation<'_>> {
+        match self.db_type {
+            GeoIpDbType::City => {
+                let city = self.reader.lookup::<geoip2::City>(addr).ok()?;
+                Some(GeoIpLocation {
+                    timezone: city.location.and_then(|location| location.time_zone),
+                    country: city.country.and_then(|country| country.iso_code),
+                    subdivision: city
+                        .subdivisions
+                        .and_then(|subdivisions| subdivisions.first()?.iso_code),
+                })
+            }
+            GeoIpDbType::Country => {
+                let country = self.reader.lookup::<geoip2::Country>(addr).ok()?;
+                Some(GeoIpLocation {
+                    timezone: None,
+                    country: country.country.and_then(|country| country.iso_code),
+                    subdivision: None,
+                })
+            }
+        }
+    }
+
+    fn record(&self, addr: IpAddr) -> Option<serde_json::Value> {
+        self.reader.lookup::<serde_json::Value>(addr).ok()
+    }
+}
+
+struct GeoIpDb {
+    reader: Box<dyn GeoIpReader>,
+}
+
+impl GeoIpDb {
+    async fn update(config: &Config) -> Result<(), Box<dyn Error>> {
+        info!("Updating GeoIP database...");
+        sh!(
+            config.update_timeout,
+            UPDATE_MMDB_SH_PATH,
+            &config.data_dir,
+            &config.mmdb_url;
+            // Passed in the environment, since arguments can be seen by every user in ps
+            "MAXMIND_LICENSE_KEY" => &config.maxmind_license_key.0
+        )
+        .await
+    }
+
+    fn load(config: &Config) -> Result<Self, LoadError> {
+        let path = config.data_path(MMDB_CITY_FILE);
+        let new_path = config.data_path(format!("{}.new", MMDB_CITY_FILE));
+        info!("Loading GeoIP database from {}", path.display());
+        if new_path.exists() {
+            info!("Replacing database with {}", new_path.display());
+            if let Err(err) = fs::rename(&new_path, &path) {
+                error!("Failed to replace {}: {}", path.display(), err);
+                error!("The existing database will be used instead");
+            }
+        }
+        // The reader's errors don't say whether the file exists
+        if let Err(err) = fs::metadata(&path) {
+            return Err(LoadError::from_io(&path, err));
+        }
+        let reader = maxminddb::Reader::open_mmap(path).map_err(LoadError::Mmdb)?;
+        // Like GeoLite2-Country or DBIP-Country-Lite
+        let db_type = if reader.metadata.database_type.contains("Country") {
+            GeoIpDbType::Country
+        } else {
+            GeoIpDbType::City
+        };
+        info!(
+            "GeoIP database type is {}, treating it as a {:?} database",
+            reader.metadata.database_type, db_type
+        );
+        Ok(GeoIpDb::new(MmdbReader { reader, db_type }))
+    }
+
+    fn new(reader: impl GeoIpReader + 'static) -> Self {
+        GeoIpDb {
+            reader: Box::new(reader),
+        }
+    }
+
+    fn refreshed_at(config: &Config) -> Option<SystemTime> {
+        file_last_modified(config.data_path(format!("{}.new", MMDB_CITY_FILE)))
+            .or_else(|_| file_last_modified(config.data_path(MMDB_CITY_FILE)))
+            .ok()
+    }
+
+    // Lookups run synchronously on the request loop, which answers one request at a time, so
+    // they never contend with each other and there's nothing for a concurrency limit to guard
+    fn lookup(&self, addr: IpAddr) -> Option<GeoIpLocation<'_>> {
+        // Some databases only have an address in one of its representations
+        self.reader
+            .location(addr)
+            .or_else(|| self.reader.location(alternate_ip(addr)?))
+    }
+
+    fn lookup_raw(&self, addr: IpAddr) -> Option<serde_json::Value> {
+        self.reader
+            .record(addr)
+            .or_else(|| self.reader.record(alternate_ip(addr)?))
+    }
+}
+
+// Normalizes timezone names and country codes for lookups. This isn't suitable for POSIX strings,
+// which should use normalize_posix instead.
+fn normalize_string(request: &str) -> String {
+    request.trim().to_uppercase().replace(' ', "_")
+}
+
+// Normalizes POSIX strings in requests. Zone names are case sensitive, and signs, colons, commas
+// and angle brackets are all part of the grammar, so only the surrounding whitespace is removed.
+fn normalize_posix(request: &str) -> String {
+    request.trim().to_owned()
+}
+
+// Parses ISO 6709 coordinates from zone1970.tab, like +4723+00832 or +404251-0740023, into degrees
+// of latitude and longitude
+fn parse_coordinates(s: &str) -> Option<(f64, f64)> {
+    let split = s.get(1..)?.find(['+', '-'])? + 1;
+    let (latitude, longitude) = s.split_at(split);
+    Some((parse_degrees(latitude, 2)?, parse_degrees(longitude, 3)?))
+}
+
+// Parses a signed angle with the given number of degree digits, followed by minutes and optionally
+// seconds
+fn parse_degrees(s: &str, degree_digits: usize) -> Option<f64> {
+    let (sign, digits) = s.split_at_checked(1)?;
+    if !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (degrees, rest) = digits.split_at_checked(degree_digits)?;
+    let (minutes, seconds) = match rest.len() {
+        2 => (rest, "0"),
+        4 => rest.split_at(2),
+        _ => return None,
+    };
+    let angle = degrees.parse::<f64>().ok()?
+        + minutes.parse::<f64>().ok()? / 60.0
+        + seconds.parse::<f64>().ok()? / 3600.0;
+    match sign {
+        "+" => Some(angle),
+        "-" => Some(-angle),
+        _ => None,
+    }
+}
+
+fn open_file<P: AsRef<Path>>(filename: P) -> io::Result<io::BufReader<fs::File>> {
+    fs::File::open(filename.as_ref()).map(io::BufReader::new)
+}
+
+fn read_lines(reader: impl BufRead) -> impl Iterator<Item = String> {
+    reader.lines().map_while(Result::ok)
+}
+
+fn read_file_lines<P: AsRef<Path>>(filename: P) -> io::Result<impl Iterator<Item = String>> {
+    Ok(read_lines(open_file(filename)?))
+}
+
+// IPv4 clients of a dual-stack socket have IPv4-mapped IPv6 addresses (::ffff:1.2.3.4), which are
+// converted back to IPv4 so they're treated the same as clients of an IPv4 socket
+fn canonical_ip(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+// The other representation of an IPv4 address, or of an IPv4-mapped IPv6 address
+fn alternate_ip(addr: IpAddr) -> Option<IpAddr> {
+    match addr {
+        IpAddr::V4(addr) => Some(addr.to_ipv6_mapped().into()),
+        IpAddr::V6(addr) => addr.to_ipv4_mapped().map(IpAddr::V4),
+    }
+}
+
+fn file_last_modified<P: AsRef<Path>>(filename: P) -> io::Result<SystemTime> {
+    fs::metadata(filename.as_ref()).and_then(|metadata| metadata.modified())
+}
+
+// Combines the stdout and stderr of an update script for logging, keeping only the end of it if
+// it's too long, since that's where the reason a script failed usually is
+fn script_output(stdout: &[u8], stderr: &[u8]) -> String {
+    let output = String::from_utf8_lossy(&[stdout, stderr].concat()).into_owned();
+    let output = output.trim_end();
+    if output.len() <= MAX_LOGGED_SCRIPT_OUTPUT {
+        return output.to_owned();
+    }
+    let mut start = output.len() - MAX_LOGGED_SCRIPT_OUTPUT;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("(truncated)...{}", &output[start..])
+}
+
+// String that is redacted from debug output, used for credentials
+#[derive(Default, Clone, PartialEq)]
+struct Secret(String);
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_owned()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "\"\"")
+        } else {
+            write!(f, "***")
+        }
+    }
+}
+
+// What to do when the startup self-test fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SelfTest {
+    Off,
+    Warn,
+    Fail,
+}
+
+impl FromStr for SelfTest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(SelfTest::Off),
+            "warn" => Ok(SelfTest::Warn),
+            "fail" => Ok(SelfTest::Fail),
+            _ => Err(format!("unknown self-test mode '{}'", s)),
+        }
+    }
+}
+
+// IP versions the UDP socket serves
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum IpFamily {
+    V4,
+    V6,
+    // An IPv6 socket that also serves IPv4 clients, from IPv4-mapped addresses
+    Dual,
+}
+
+impl FromStr for IpFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "v4" => Ok(IpFamily::V4),
+            "v6" => Ok(IpFamily::V6),
+            "dual" => Ok(IpFamily::Dual),
+            _ => Err(format!("unknown IP family '{}'", s)),
+        }
+    }
+}
+
+// Bytes appended to every response, for clients that read responses as lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ResponseTerminator {
+    None,
+    Lf,
+    Crlf,
+}
+
+impl ResponseTerminator {
+    fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            ResponseTerminator::None => b"",
+            ResponseTerminator::Lf => b"\n",
+            ResponseTerminator::Crlf => b"\r\n",
+        }
+    }
+}
+
+impl FromStr for ResponseTerminator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(ResponseTerminator::None),
+            "lf" => Ok(ResponseTerminator::Lf),
+            "crlf" => Ok(ResponseTerminator::Crlf),
+            _ => Err(format!("unknown response terminator '{}'", s)),
+        }
+    }
+}
+
+// Where the Prometheus metrics service listens
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, PartialEq)]
+enum MetricsBind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+#[cfg(feature = "metrics")]
+impl FromStr for MetricsBind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(MetricsBind::Unix(path.into())),
+            Some(_) => Err("missing Unix socket path".into()),
+            None => s
+                .parse()
+                .map(MetricsBind::Tcp)
+                .map_err(|_| format!("'{}' is neither host:port nor unix:/path", s)),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl fmt::Display for MetricsBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricsBind::Tcp(addr) => write!(f, "{}", addr),
+            MetricsBind::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Range of IP addresses in CIDR notation, like 192.0.2.0/24. A single address is its own range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, canonical_ip(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map(canonical_ip)
+            .map_err(|_| format!("invalid address in '{}'", s))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            None => max_prefix_len,
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("invalid prefix length in '{}'", s))?,
+        };
+        Ok(IpNet { addr, prefix_len })
+    }
+}
+
+// Requests in different classes are rate limited independently, so each class can have its own
+// window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RateLimitClass {
+    Default,
+    GeoIp,
+}
+
+// A client that was recently responded to over UDP or DTLS
+struct Client {
+    // Time the last response was sent, for rate limiting
+    responded_at: Instant,
+    // Whether the client has been told it's rate limited since then
+    notified: bool,
+    // Last request that was looked up, its response, and when, kept for TZD_DEDUP_MS
+    last_lookup: Option<(Request, Vec<u8>, Instant)>,
+}
+
+// What to do with a request, given the client's earlier requests
+#[derive(Debug, PartialEq)]
+enum Admission {
+    LookUp,
+    // A retransmitted request within the dedup window, answered with the last response
+    Duplicate(Vec<u8>),
+    DropDuplicate,
+    // notify is whether the client should be told it's rate limited
+    RateLimited { notify: bool },
+}
+
+// Maps IP addresses and rate limit classes to the clients that were recently responded to
+#[derive(Default)]
+struct Clients(HashMap<(IpAddr, RateLimitClass), Client>);
+
+impl Clients {
+    // Decides what to do with a request, counting it against the client's rate limit unless it's
+    // rate limited or dropped
+    fn admit(&mut self, ip: IpAddr, request: &Request, now: Instant, config: &Config) -> Admission {
+        // Don't respond to rate limited clients, unless they're exempt
+        let class = config.rate_limit_class(request);
+        if let Some(client) = self
+            .0
+            .get_mut(&(ip, class))
+            .filter(|_| !config.is_rate_limit_exempt(ip))
+        {
+            if now - client.responded_at < config.rate_limit_window(class) {
+                // Only one notice is sent per window, and it doesn't restart the window, so it
+                // can't be used to make the server send more than one response per window. The
+                // original ezTime server has no such response, so none are sent in compatibility
+                // mode.
+                let notify = config.rate_limit_notify && !config.eztime_compat && !client.notified;
+                client.notified |= notify;
+                return Admission::RateLimited { notify };
+            }
+        }
+        // Clients are counted by address, whichever rate limit classes they're in
+        #[cfg(feature = "metrics")]
+        if [RateLimitClass::Default, RateLimitClass::GeoIp]
+            .iter()
+            .all(|class| !self.0.contains_key(&(ip, *class)))
+        {
+            metrics::increment_counter!("timezoned_unique_clients");
+        }
+        let client = self.0.entry((ip, class)).or_insert(Client {
+            responded_at: now,
+            notified: false,
+            last_lookup: None,
+        });
+
+        // Retransmitted requests within the dedup window are answered with the last response
+        // instead of being looked up again, or dropped. The window starts when a request is looked
+        // up, so repeating a request can't extend it.
+        let duplicate = client
+            .last_lookup
+            .as_ref()
+            .filter(|(last_request, _, looked_up_at)| {
+                now - *looked_up_at < config.dedup_window && last_request == request
+            })
+            .map(|(_, response, _)| response.clone());
+        if duplicate.is_some() && config.dedup_drop {
+            return Admission::DropDuplicate;
+        }
+        client.responded_at = now;
+        client.notified = false;
+        match duplicate {
+            Some(response) => Admission::Duplicate(response),
+            None => Admission::LookUp,
+        }
+    }
+
+    // Keeps the response to a request that was looked up, to answer retransmissions with
+    fn record(
+        &mut self,
+        ip: IpAddr,
+        request: Request,
+        response: &[u8],
+        now: Instant,
+        config: &Config,
+    ) {
+        if config.dedup_window.is_zero() {
+            return;
+        }
+        let class = config.rate_limit_class(&request);
+        if let Some(client) = self.0.get_mut(&(ip, class)) {
+            client.last_lookup = Some((request, response.to_vec(), now));
+        }
+    }
+
+    // Removes clients that haven't sent requests within the rate limit or dedup window, so they
+    // don't use excessive RAM
+    fn prune(&mut self, now: Instant, config: &Config) {
+        self.0.retain(|(_, class), client| {
+            let window = config.rate_limit_window(*class).max(config.dedup_window);
+            now - client.responded_at < window
+        });
+        #[cfg(feature = "metrics")]
+        {
+            let ips = self.0.keys().map(|(ip, _)| ip).collect::<HashSet<_>>();
+            metrics::gauge!("timezoned_active_clients", ips.len() as f64);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Config {
+    rate_limit: Duration,
+    rate_limit_geoip: Option<Duration>,
+    // Whether rate limited clients are told so once per window, instead of being ignored
+    rate_limit_notify: bool,
+    // Clients that are never rate limited
+    rate_limit_exempt: Vec<IpNet>,
+    // How long identical requests from a client are answered with the last response, or zero to
+    // look up every request
+    dedup_window: Duration,
+    // Whether identical requests are dropped instead
+    dedup_drop: bool,
+    client_prune_period: Duration,
+    // None if refreshing is disabled
+    tz_refresh_period: Option<Duration>,
+    geoip_refresh_period: Option<Duration>,
+    update_timeout: Duration,
+    // Maximum random delay added to refreshes, as a percentage of their period
+    refresh_jitter_percent: u32,
+    // Whether the timezone and GeoIP refresh scripts have to take turns instead of running at once
+    serialize_refresh: bool,
+    // None if lookups should never be refused because the data is too old
+    max_data_age: Option<Duration>,
+    data_dir: PathBuf,
+    // Archive of data files to serve instead of the ones in data_dir, or empty if there is none
+    data_tar: PathBuf,
+    host: String,
+    port: u16,
+    // None to use the family of the address TZD_HOST resolves to first
+    ip_family: Option<IpFamily>,
+    so_rcvbuf: usize,
+    // Whether the UDP socket is passed by systemd socket activation instead of bound by the server
+    systemd_socket: bool,
+    // Maximum ratio of response size to request size, or 0 for no limit
+    max_amplification: usize,
+    // Requests shorter than this after trimming whitespace are dropped
+    min_request_size: usize,
+    response_terminator: ResponseTerminator,
+    unix_socket: PathBuf,
+    // User and group to switch to once sockets are bound, or empty to keep running as the current
+    // one
+    user: String,
+    group: String,
+    response_alias_map: PathBuf,
+    // Normalized prefixes of the timezones that are loaded, or empty to load every timezone
+    zone_prefix_allow: Vec<String>,
+    // URL of overrides downloaded with the timezone database, or empty if there are none
+    overrides_url: String,
+    multicast_group: Option<IpAddr>,
+    // Port UDP responses are sent to instead of the port requests came from, if set
+    reply_port: Option<u16>,
+    // None if the metrics service is disabled
+    #[cfg(feature = "metrics")]
+    metrics_bind: Option<MetricsBind>,
+    // Prometheus push gateway URL, or empty if metrics aren't pushed
+    #[cfg(feature = "metrics")]
+    metrics_push_url: String,
+    #[cfg(feature = "metrics")]
+    metrics_push_period: Duration,
+    // DTLS is disabled if the port is 0
+    #[cfg(feature = "dtls")]
+    dtls_port: u16,
+    #[cfg(feature = "dtls")]
+    dtls_cert: PathBuf,
+    #[cfg(feature = "dtls")]
+    dtls_key: PathBuf,
+    geoip_enabled: bool,
+    // Normalized name of the timezone returned when GeoIP can't find one
+    geoip_default: Option<String>,
+    // Normalized name of the timezone returned when a timezone name isn't found
+    default_timezone: Option<String>,
+    // Whether empty requests also get the default timezone
+    default_timezone_empty: bool,
+    geoip_cache_size: usize,
+    geoip_cache_flush_period: Duration,
+    response_cache_size: usize,
+    mmdb_url: String,
+    maxmind_license_key: Secret,
+    // Key required for admin commands, or empty if they're disabled
+    admin_key: Secret,
+    // Whether errors are limited to the ones the original ezTime server returns
+    eztime_compat: bool,
+    strict_commands: bool,
+    // Whether timezone names that aren't found are looked up as country names
+    fuzzy_country: bool,
+    // Whether 2-letter requests that aren't country codes are looked up as timezone names
+    country_fallback_olson: bool,
+    myip_enabled: bool,
+    log_unknown: bool,
+    // Only one in this many unknown requests is logged
+    log_unknown_sample: u64,
+    selftest: SelfTest,
+    selftest_probes: Vec<String>,
+}
+
+impl Config {
+    fn load() -> Result<Self, String> {
+        Self::from_file(&Self::read_file()?)
+    }
+
+    // Reads the config from the environment, falling back to the options from the config file
+    fn from_file(file: &HashMap<String, String>) -> Result<Self, String> {
+        let maxmind_license_key =
+            Self::getenv::<Secret>(file, "TZD_MAXMIND_LICENSE_KEY", Some(Secret::default()))?;
+        let mut mmdb_url = Self::getenv::<String>(file, "TZD_MMDB_URL", Some("".into()))?;
+        if mmdb_url.is_empty() && !maxmind_license_key.0.is_empty() {
+            mmdb_url = MAXMIND_CITY_URL.into();
+        }
+
+        Ok(Config {
+            rate_limit: Duration::from_millis(Self::getenv(file, "TZD_RATELIMIT_MS", Some(3000))?),
+            rate_limit_geoip: Self::getenv_opt::<u64>(file, "TZD_RATELIMIT_GEOIP_MS")?
+                .map(Duration::from_millis),
+            rate_limit_notify: Self::getenv(file, "TZD_RATELIMIT_NOTIFY", Some(false))?,
+            rate_limit_exempt: Self::getenv::<String>(
+                file,
+                "TZD_RATELIMIT_EXEMPT",
+                Some("".into()),
+            )?
+            .split(',')
+            .map(str::trim)
+            .filter(|net| !net.is_empty())
+            .map(|net| {
+                net.parse::<IpNet>()
+                    .map_err(|err| format!("TZD_RATELIMIT_EXEMPT is invalid: {}", err))
+            })
+            .collect::<Result<_, _>>()?,
+            dedup_window: Duration::from_millis(Self::getenv(file, "TZD_DEDUP_MS", Some(0))?),
+            dedup_drop: Self::getenv(file, "TZD_DEDUP_DROP", Some(false))?,
+            client_prune_period: Duration::from_secs(Self::getenv(
+                file,
+                "TZD_CLIENT_PRUNE_SECONDS",
+                Some(10),
+            )?),
+            tz_refresh_period: days_or_disabled(Self::getenv(
+                file,
+                "TZD_TZ_REFRESH_DAYS",
+                Some(7),
+            )?),
+            geoip_refresh_period: days_or_disabled(Self::getenv(
+                file,
+                "TZD_GEOIP_REFRESH_DAYS",
+                Some(7),
+            )?),
+            update_timeout: Duration::from_secs(Self::getenv(
+                file,
+                "TZD_UPDATE_TIMEOUT_SECONDS",
+                Some(600),
+            )?),
+            refresh_jitter_percent: Self::getenv::<u32>(
+                file,
+                "TZD_REFRESH_JITTER_PERCENT",
+                Some(0),
+            )?
+            .min(100),
+            serialize_refresh: Self::getenv::<bool>(file, "TZD_SERIALIZE_REFRESH", Some(false))?,
+            max_data_age: days_or_disabled(Self::getenv(file, "TZD_MAX_DATA_AGE_DAYS", Some(0))?),
+            data_dir: Self::getenv::<PathBuf>(
+                file,
+                "TZD_DATA_DIR",
+                Some("/home/timezoned".into()),
+            )?,
+            data_tar: Self::getenv::<PathBuf>(file, "TZD_DATA_TAR", Some(PathBuf::new()))?,
+            host: Self::getenv::<String>(file, "TZD_HOST", Some("0.0.0.0".into()))?,
+            port: Self::getenv::<u16>(file, "TZD_PORT", Some(2342))?,
+            ip_family: Self::getenv_opt::<IpFamily>(file, "TZD_IP_FAMILY")?,
+            so_rcvbuf: Self::getenv::<usize>(file, "TZD_SO_RCVBUF", Some(0))?,
+            systemd_socket: Self::getenv::<bool>(file, "TZD_SYSTEMD_SOCKET", Some(false))?,
+            max_amplification: Self::getenv::<usize>(file, "TZD_MAX_AMPLIFICATION", Some(0))?,
+            min_request_size: Self::getenv::<usize>(file, "TZD_MIN_REQUEST_SIZE", Some(0))?,
+            response_terminator: Self::getenv::<ResponseTerminator>(
+                file,
+                "TZD_RESPONSE_TERMINATOR",
+                Some(ResponseTerminator::None),
+            )?,
+            unix_socket: Self::getenv::<PathBuf>(file, "TZD_UNIX_SOCKET", Some(PathBuf::new()))?,
+            user: Self::getenv::<String>(file, "TZD_USER", Some("".into()))?,
+            group: Self::getenv::<String>(file, "TZD_GROUP", Some("".into()))?,
+            zone_prefix_allow: Self::getenv::<String>(
+                file,
+                "TZD_ZONE_PREFIX_ALLOW",
+                Some("".into()),
+            )?
+            .split(',')
+            .map(str::trim)
+            .filter(|prefix| !prefix.is_empty())
+            .map(normalize_string)
+            .collect(),
+            overrides_url: Self::getenv::<String>(file, "TZD_OVERRIDES_URL", Some("".into()))?,
+            response_alias_map: Self::getenv::<PathBuf>(
+                file,
+                "TZD_RESPONSE_ALIAS_MAP",
+                Some(PathBuf::new()),
+            )?,
+            reply_port: Self::getenv_opt::<u16>(file, "TZD_REPLY_PORT")?,
+            multicast_group: match Self::getenv_opt::<IpAddr>(file, "TZD_MULTICAST_GROUP")? {
+                Some(group) if !group.is_multicast() => {
+                    return Err(format!(
+                        "TZD_MULTICAST_GROUP {} is not a multicast address",
+                        group
+                    ));
+                }
+                group => group,
+            },
+            // TZD_METRICS_BIND takes precedence over TZD_METRICS_HOST and TZD_METRICS_PORT
+            #[cfg(feature = "metrics")]
+            metrics_bind: match Self::getenv_opt::<MetricsBind>(file, "TZD_METRICS_BIND")? {
+                Some(bind) => Some(bind),
+                None => {
+                    let host = Self::getenv::<IpAddr>(
+                        file,
+                        "TZD_METRICS_HOST",
+                        Some(Ipv4Addr::UNSPECIFIED.into()),
+                    )?;
+                    let port = Self::getenv::<u16>(file, "TZD_METRICS_PORT", Some(0))?;
+                    (port > 0).then(|| MetricsBind::Tcp(SocketAddr::new(host, port)))
+                }
+            },
+            #[cfg(feature = "metrics")]
+            metrics_push_url: Self::getenv::<String>(
+                file,
+                "TZD_METRICS_PUSH_URL",
+                Some("".into()),
+            )?,
+            #[cfg(feature = "metrics")]
+            metrics_push_period: Duration::from_secs(
+                Self::getenv::<u64>(file, "TZD_METRICS_PUSH_SECONDS", Some(15))?.max(1),
+            ),
+            #[cfg(feature = "dtls")]
+            dtls_port: Self::getenv::<u16>(file, "TZD_DTLS_PORT", Some(0))?,
+            #[cfg(feature = "dtls")]
+            dtls_cert: Self::getenv::<PathBuf>(file, "TZD_DTLS_CERT", Some(PathBuf::new()))?,
+            #[cfg(feature = "dtls")]
+            dtls_key: Self::getenv::<PathBuf>(file, "TZD_DTLS_KEY", Some(PathBuf::new()))?,
+            geoip_enabled: Self::getenv::<bool>(file, "TZD_GEOIP_ENABLED", Some(true))?,
+            geoip_default: Self::getenv_opt::<String>(file, "TZD_GEOIP_DEFAULT")?
+                .map(|olson| normalize_string(&olson)),
+            default_timezone: Self::getenv_opt::<String>(file, "TZD_DEFAULT_TIMEZONE")?
+                .map(|olson| normalize_string(&olson)),
+            default_timezone_empty: Self::getenv::<bool>(
+                file,
+                "TZD_DEFAULT_TIMEZONE_EMPTY",
+                Some(false),
+            )?,
+            geoip_cache_size: Self::getenv::<usize>(file, "TZD_GEOIP_CACHE_SIZE", Some(0))?,
+            geoip_cache_flush_period: Duration::from_secs(Self::getenv(
+                file,
+                "TZD_GEOIP_CACHE_FLUSH_SECONDS",
+                Some(300),
+            )?),
+            response_cache_size: Self::getenv::<usize>(file, "TZD_RESPONSE_CACHE_SIZE", Some(0))?,
+            mmdb_url,
+            maxmind_license_key,
+            admin_key: Self::getenv::<Secret>(file, "TZD_ADMIN_KEY", Some(Secret::default()))?,
+            eztime_compat: Self::getenv::<bool>(file, "TZD_EZTIME_COMPAT", Some(false))?,
+            strict_commands: Self::getenv::<bool>(file, "TZD_STRICT_COMMANDS", Some(false))?,
+            fuzzy_country: Self::getenv::<bool>(file, "TZD_FUZZY_COUNTRY", Some(false))?,
+            country_fallback_olson: Self::getenv::<bool>(
+                file,
+                "TZD_COUNTRY_FALLBACK_OLSON",
+                Some(false),
+            )?,
+            myip_enabled: Self::getenv::<bool>(file, "TZD_ENABLE_MYIP", Some(true))?,
+            log_unknown: Self::getenv::<bool>(file, "TZD_LOG_UNKNOWN", Some(false))?,
+            log_unknown_sample: Self::getenv::<u64>(file, "TZD_LOG_UNKNOWN_SAMPLE", Some(1))?
+                .max(1),
+            selftest: Self::getenv::<SelfTest>(file, "TZD_SELFTEST", Some(SelfTest::Warn))?,
+            selftest_probes: Self::getenv::<String>(
+                file,
+                "TZD_SELFTEST_PROBES",
+                Some("Europe/London,America/New_York,GB".into()),
+            )?
+            .split(',')
+            .map(str::trim)
+            .filter(|probe| !probe.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        })
+    }
+
+    fn data_path<P: AsRef<Path>>(&self, p: P) -> PathBuf {
+        self.data_dir.join(p)
+    }
+
+    // Serves the data files extracted from data_tar to dir, which are never refreshed
+    fn use_data_tar_dir(&mut self, dir: &Path) {
+        self.data_dir = dir.to_owned();
+        self.tz_refresh_period = None;
+        self.geoip_refresh_period = None;
+    }
+
+    // Responses that are too much larger than the request aren't sent, since they make the server
+    // more useful for reflection attacks
+    fn allows_response(&self, request_len: usize, response_len: usize) -> bool {
+        self.max_amplification == 0 || response_len <= request_len * self.max_amplification
+    }
+
+    // Sent instead of responses that are too large for a datagram. The original ezTime server has
+    // no such error, and never sends responses that large, so its catch-all error is used instead.
+    fn response_too_large(&self) -> Vec<u8> {
+        let err = if self.eztime_compat {
+            ERR_TIMEZONE_NOT_FOUND
+        } else {
+            ERR_RESPONSE_TOO_LARGE
+        };
+        [err, self.response_terminator.as_bytes()].concat()
+    }
+
+    // Sent to rate limited clients, if they're notified
+    fn rate_limited(&self) -> Vec<u8> {
+        [ERR_RATE_LIMITED, self.response_terminator.as_bytes()].concat()
+    }
+
+    fn warn_min_request_size(&self) {
+        if self.min_request_size > MIN_VALID_REQUEST_SIZE {
+            warn!(
+                "TZD_MIN_REQUEST_SIZE is {}, so valid requests shorter than that will be dropped, \
+                 except for country codes",
+                self.min_request_size
+            );
+        }
+    }
+
+    // Requests only get their own class when it has a rate limit configured, otherwise they share
+    // the global one
+    fn rate_limit_class(&self, request: &Request) -> RateLimitClass {
+        match request {
+            Request::GeoIp(_) | Request::GeoIpInfo if self.rate_limit_geoip.is_some() => {
+                RateLimitClass::GeoIp
+            }
+            _ => RateLimitClass::Default,
+        }
+    }
+
+    // Address a UDP response to a request from addr is sent to
+    fn reply_addr(&self, addr: SocketAddr) -> SocketAddr {
+        match self.reply_port {
+            Some(port) => SocketAddr::new(addr.ip(), port),
+            None => addr,
+        }
+    }
+
+    fn is_rate_limit_exempt(&self, addr: IpAddr) -> bool {
+        self.rate_limit_exempt.iter().any(|net| net.contains(addr))
+    }
+
+    fn rate_limit_window(&self, class: RateLimitClass) -> Duration {
+        match class {
+            RateLimitClass::Default => self.rate_limit,
+            RateLimitClass::GeoIp => self.rate_limit_geoip.unwrap_or(self.rate_limit),
+        }
+    }
+
+    // Copies the settings that can be changed while the server is running from new
+    fn with_live_settings(&self, new: &Config) -> Config {
+        Config {
+            rate_limit: new.rate_limit,
+            rate_limit_geoip: new.rate_limit_geoip,
+            rate_limit_notify: new.rate_limit_notify,
+            rate_limit_exempt: new.rate_limit_exempt.clone(),
+            dedup_window: new.dedup_window,
+            dedup_drop: new.dedup_drop,
+            client_prune_period: new.client_prune_period,
+            max_amplification: new.max_amplification,
+            min_request_size: new.min_request_size,
+            strict_commands: new.strict_commands,
+            fuzzy_country: new.fuzzy_country,
+            country_fallback_olson: new.country_fallback_olson,
+            myip_enabled: new.myip_enabled,
+            log_unknown: new.log_unknown,
+            log_unknown_sample: new.log_unknown_sample,
+            ..self.clone()
+        }
+    }
+
+    // Reads the options in the TOML file in TZD_CONFIG_FILE, if there is one
+    fn read_file() -> Result<HashMap<String, String>, String> {
+        let Ok(path) = std::env::var("TZD_CONFIG_FILE") else {
+            return Ok(HashMap::new());
+        };
+        let table = fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| {
+                contents
+                    .parse::<toml::Table>()
+                    .map_err(|err| err.to_string())
+            })
+            .map_err(|err| format!("Could not read config file {}: {}", path, err))?;
+        table
+            .into_iter()
+            .map(|(key, value)| match value {
+                toml::Value::String(value) => Ok((key, value)),
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                    Ok((key, value.to_string()))
+                }
+                _ => Err(format!(
+                    "{} in config file {} must be a string, number, or boolean",
+                    key, path
+                )),
+            })
+            .collect()
+    }
+
+    // Like getenv, but for options that have no default
+    fn getenv_opt<T: FromStr>(
+        file: &HashMap<String, String>,
+        key: &str,
+    ) -> Result<Option<T>, String> {
+        if std::env::var(key).is_ok() || file.contains_key(key) {
+            Self::getenv(file, key, None).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Reads an option from the environment, falling back to the config file
+    fn getenv<T: FromStr>(
+        file: &HashMap<String, String>,
+        key: &str,
+        default: Option<T>,
+    ) -> Result<T, String> {
+        match std::env::var(key).ok().or_else(|| file.get(key).cloned()) {
+            Some(value) => value.parse::<T>().map_err(|_| {
+                format!(
+                    "{} is configured with invalid value '{}', expected {}",
+                    key,
+                    value,
+                    std::any::type_name::<T>()
+                )
+            }),
+            None => {
+                if let Some(default) = default {
+                    Ok(default)
+                } else {
+                    Err(format!("{} was not specified", key))
+                }
+            }
+        }
+    }
+}
+
+// Checks whether the timezone data has become too old to serve, logging whenever that changes
+fn check_data_stale(config: &Config, was_stale: bool) -> bool {
+    let stale = TimezoneDb::is_stale(config);
+    if stale && !was_stale {
+        error!(
+            "Timezone data is older than TZD_MAX_DATA_AGE_DAYS. Every lookup will return '{}' \
+             until it's refreshed",
+            String::from_utf8_lossy(ERR_DATA_TOO_STALE)
+        );
+    } else if !stale && was_stale {
+        info!("Timezone data has been refreshed, lookups will be served again");
+    }
+    stale
+}
+
+// Converts a number of days from the config to a duration, where 0 days disables the feature
+fn days_or_disabled(days: u64) -> Option<Duration> {
+    (days > 0).then(|| Duration::from_secs(days * SECONDS_PER_DAY))
+}
+
+fn interval(last_ran_at: Option<SystemTime>, period: Duration) -> Interval {
+    interval_after(time_until_run(last_ran_at, period), period)
+}
+
+fn interval_after(delay: Duration, period: Duration) -> Interval {
+    let mut interval = interval_at(Instant::now() + delay, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    interval
+}
+
+// Time until a database that was last refreshed at last_ran_at is next due to be refreshed, moved
+// earlier or later by a random fraction of up to jitter_percent of period, so servers that were
+// started at the same time don't all refresh at once. Refreshes that are already due aren't
+// delayed, and jitter never moves a refresh before now.
+fn refresh_delay(
+    last_ran_at: Option<SystemTime>,
+    period: Duration,
+    jitter_percent: u32,
+    rng: &mut fastrand::Rng,
+) -> Duration {
+    let delay = time_until_run(last_ran_at, period);
+    if delay.is_zero() {
+        return delay;
+    }
+    let jitter = period.as_secs_f64() * jitter_percent as f64 / 100.0 * (rng.f64() * 2.0 - 1.0);
+    Duration::from_secs_f64((delay.as_secs_f64() + jitter).max(0.0))
+}
+
+// Waits until no other refresh is running if refreshes are serialized, returning a guard that lets
+// the next one run when it's dropped
+async fn refresh_turn<'a>(lock: Option<&'a Mutex<()>>, name: &str) -> Option<MutexGuard<'a, ()>> {
+    let lock = lock?;
+    match lock.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(_) => {
+            info!("{} refresh is waiting for another refresh to finish", name);
+            Some(lock.lock().await)
+        }
+    }
+}
+
+// Time until a task that last ran at last_ran_at is next due to run every period, which is
+// immediately if it never ran or is overdue
+fn time_until_run(last_ran_at: Option<SystemTime>, period: Duration) -> Duration {
+    let time_since_run = match last_ran_at {
+        Some(time) => SystemTime::now().duration_since(time).unwrap_or(period),
+        None => period,
+    };
+    period.saturating_sub(time_since_run)
+}
+
+// Parses a request received from any socket
+fn parse_request(request: &[u8], config: &Config) -> Request {
+    match Request::parse(&String::from_utf8_lossy(request)) {
+        // Without strict command checking, unknown commands are looked up as timezone names
+        Request::Unknown(name, format) if !config.strict_commands => Request::Olson(name, format),
+        request => request,
+    }
+}
+
+// Responds to a request received from any socket, where raw is the request as it was received
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+fn handle_request(
+    raw: &[u8],
+    request: &Request,
+    received_at: Instant,
+    ctx: &Context,
+    addr: Option<IpAddr>,
+) -> Vec<u8> {
+    let raw = redact_request(raw);
+    let cache = ctx
+        .response_cache
+        .filter(|_| !ctx.data_stale && request.is_cacheable());
+    let cached = cache.and_then(|cache| cache.borrow_mut().get(request).cloned());
+    #[cfg(feature = "metrics")]
+    if cache.is_some() {
+        let result = if cached.is_some() { "hit" } else { "miss" };
+        metrics::increment_counter!("timezoned_cache_requests", "cache" => "response", "result" => result);
+    }
+    let mut response = match cached {
+        Some(response) => {
+            log_request!(request.kind(), "cache" => "hit");
+            response
+        }
+        None => {
+            let response = request.respond(ctx, addr);
+            // Only successful responses are cached, so requests for things that don't exist
+            // can't push everything else out of the cache
+            if let Some(cache) = cache.filter(|_| !response.starts_with(b"ERROR")) {
+                cache.borrow_mut().put(request.clone(), response.clone());
+            }
+            response
+        }
+    };
+    // Unknown timezone names get the default timezone instead. This is done after caching so
+    // every unknown name doesn't end up in the cache.
+    if let Some(default) = respond_default(ctx, request, &response) {
+        response = default;
+    }
+    if log::log_enabled!(target: logger::ACCESS_LOG_TARGET, log::Level::Debug) {
+        log_access(raw, &response, received_at, addr);
+    }
+    if is_unknown_request(raw, &response) {
+        #[cfg(feature = "metrics")]
+        metrics::increment_counter!("timezoned_not_found_requests", "type" => request.kind());
+        if ctx.config.log_unknown {
+            log_unknown_request(raw, ctx.config, addr);
+        }
+    }
+    #[cfg(feature = "metrics")]
+    metrics::histogram!("timezoned_request_duration_seconds", received_at.elapsed(), "type" => request.kind());
+    // Logs and metrics keep the actual error, for debugging
+    if ctx.config.eztime_compat {
+        response = request.eztime_response(response);
+    }
+    response.extend_from_slice(ctx.config.response_terminator.as_bytes());
+    response
+}
+
+// Parses and responds to a request like the server does for a request from the Unix socket, with
+// the fixture timezone data and default settings, for fuzzing. Returns None for requests the server
+// would drop.
+#[cfg(any(test, fuzzing))]
+pub fn parse_and_respond(request: &[u8]) -> Option<Vec<u8>> {
+    static DATA: std::sync::OnceLock<(Config, TimezoneDb)> = std::sync::OnceLock::new();
+    if request.len() > MAX_REQUEST_SIZE {
+        return None;
+    }
+    let (config, timezones) = DATA.get_or_init(|| (fixtures::config(), fixtures::timezone_db()));
+    let ctx = fixtures::context(config, timezones);
+    let parsed = parse_request(request, config);
+    Some(handle_request(request, &parsed, Instant::now(), &ctx, None))
+}
+
+// Checks that the timezones named in the config exist and that the timezone database passes the
+// self-test, before the server starts answering requests with it
+fn check_timezones(config: &Config, timezones: &TimezoneDb) -> Result<(), String> {
+    if let Some(olson) = &config.geoip_default {
+        if timezones.lookup_olson(olson).is_none() {
+            return Err(format!(
+                "TZD_GEOIP_DEFAULT timezone '{}' was not found",
+                olson
+            ));
+        }
+    }
+    if let Some(olson) = &config.default_timezone {
+        if timezones.lookup_olson(olson).is_none() {
+            return Err(format!(
+                "TZD_DEFAULT_TIMEZONE timezone '{}' was not found",
+                olson
+            ));
+        }
+    }
+
+    // Make sure the timezone database can actually answer requests
+    if config.selftest != SelfTest::Off {
+        let ctx = Context {
+            config,
+            timezones,
+            geoip: None,
+            geoip_cache: None,
+            response_cache: None,
+            reloading: false,
+            data_stale: false,
+            tz_refresh_at: None,
+            geoip_refresh_at: None,
+            geoip_reloaded: None,
+            now: SystemTime::now(),
+        };
+        let failures = self_test(&ctx);
+        if failures.is_empty() {
+            info!("Self-test passed ({} probes)", config.selftest_probes.len());
+        } else if config.selftest == SelfTest::Fail {
+            for failure in &failures {
+                error!("Self-test failed: {}", failure);
+            }
+            return Err("Timezone database failed the self-test".into());
+        } else {
+            for failure in &failures {
+                warn!("Self-test failed: {}", failure);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Logs a request and its result to the access log
+fn log_access(request: &[u8], response: &[u8], received_at: Instant, addr: Option<IpAddr>) {
+    let ip = match addr {
+        Some(addr) => addr.to_string(),
+        None => "unix".into(),
+    };
+    let request = escape_request(request);
+    let result = match response.strip_prefix(b"ERROR ") {
+        Some(err) => String::from_utf8_lossy(err).into_owned(),
+        None => "OK".into(),
+    };
+    let duration_us = received_at.elapsed().as_micros() as u64;
+    debug!(
+        target: logger::ACCESS_LOG_TARGET,
+        ip = ip.as_str(), request = request.as_str(), result = result.as_str(), duration_us;
+        "{} {} {} {}us", ip, request, result, duration_us
+    );
+}
+
+// Logs a response that couldn't be sent in full. Returns true if it failed because it was too large
+// to fit in a datagram, in which case ERR_RESPONSE_TOO_LARGE should be sent instead.
+fn send_failed_too_large(result: io::Result<usize>, len: usize, dest: impl fmt::Display) -> bool {
+    match result {
+        Ok(sent) if sent == len => false,
+        Ok(sent) => {
+            log_request!("send_error");
+            warn!(
+                "Sent only {} of {} bytes of response to {}",
+                sent, len, dest
+            );
+            false
+        }
+        Err(err) => {
+            log_request!("send_error");
+            warn!("Could not send {} byte response to {}: {}", len, dest, err);
+            err.raw_os_error() == Some(libc::EMSGSIZE)
+        }
+    }
+}
+
+// Whether a UDP request is dropped without a response, which is logged if it is. Requests shorter
+// than min_request_size are mostly from scanners, but anything that looks like a country code is
+// still answered, since they're the shortest valid requests.
+fn drop_request(request: &[u8], config: &Config) -> bool {
+    let trimmed = request.trim_ascii();
+    if request.len() > MAX_REQUEST_SIZE {
+        log_request!("too_large");
+        true
+    } else if trimmed.len() < config.min_request_size
+        && !(trimmed.len() == MIN_VALID_REQUEST_SIZE && trimmed.iter().all(u8::is_ascii_alphabetic))
+    {
+        log_request!("too_small");
+        true
+    } else {
+        false
+    }
+}
+
+// Logs a sample of the requests that got a not found response, to find out what clients are asking
+// for that isn't supported
+fn log_unknown_request(request: &[u8], config: &Config, addr: Option<IpAddr>) {
+    let count = UNKNOWN_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    if !count.is_multiple_of(config.log_unknown_sample) {
+        return;
+    }
+    let request = escape_request(request);
+    match addr {
+        Some(addr) => info!(
+            target: logger::REQUEST_LOG_TARGET,
+            ip = addr, request = request.as_str();
+            "Unknown request from {}: {}", addr, request
+        ),
+        None => info!(
+            target: logger::REQUEST_LOG_TARGET,
+            ip = "unix", request = request.as_str();
+            "Unknown request from Unix socket: {}", request
+        ),
+    }
+}
+
+// Admin command a request is for, going by its first word whether or not the rest of the request
+// is valid
+fn admin_command(request: &[u8]) -> Option<&'static str> {
+    let command = request
+        .trim_ascii_start()
+        .split(|&c| c.is_ascii_whitespace() || c == b'?')
+        .next()
+        .unwrap_or_default();
+    request::ADMIN_COMMANDS
+        .iter()
+        .find(|admin| command.eq_ignore_ascii_case(admin.as_bytes()))
+        .copied()
+}
+
+// Whether a request was for something that doesn't exist, so it's counted and can be logged.
+// Admin commands' errors are about their key or arguments, so they're never counted.
+fn is_unknown_request(request: &[u8], response: &[u8]) -> bool {
+    admin_command(request).is_none() && is_not_found(response)
+}
+
+// Requests for admin commands are only logged as their command, so admin keys aren't leaked
+fn redact_request(request: &[u8]) -> &[u8] {
+    admin_command(request).map_or(request, str::as_bytes)
+}
+
+// Requests can contain anything, so they're truncated and escaped before being logged
+fn escape_request(request: &[u8]) -> String {
+    String::from_utf8_lossy(request)
+        .trim()
+        .chars()
+        .take(MAX_LOGGED_REQUEST_CHARS)
+        .flat_map(char::escape_default)
+        .collect()
+}
+
+// Runs each self-test probe as a request, returning a description of every probe that didn't
+// get an OK response
+fn self_test(ctx: &Context) -> Vec<String> {
+    ctx.config
+        .selftest_probes
+        .iter()
+        .filter_map(|probe| {
+            let response = parse_request(probe.as_bytes(), ctx.config).respond(ctx, None);
+            (!response.starts_with(b"OK ")).then(|| {
+                format!(
+                    "'{}' returned '{}'",
+                    probe,
+                    String::from_utf8_lossy(&response)
+                )
+            })
+        })
+        .collect()
+}
+
+// Loads the GeoIP database again, replacing the current one if the new one loads
+fn reload_geoip(
+    config: &Config,
+    geoip: &mut Option<GeoIpDb>,
+    geoip_cache: Option<&RefCell<GeoIpCache>>,
+    response_cache: Option<&RefCell<LruCache<Request, Vec<u8>>>>,
+) -> Result<(), Box<dyn Error>> {
+    geoip.replace(GeoIpDb::load(config)?);
+    // Cached results may be different in the new database
+    if let Some(geoip_cache) = geoip_cache {
+        geoip_cache.borrow_mut().clear();
+    }
+    if let Some(response_cache) = response_cache {
+        response_cache.borrow_mut().clear();
+    }
+    Ok(())
+}
+
+// Reloads the GeoIP database for a RELOADGEOIP request with the admin key, returning whether it
+// was reloaded, or None for any other request
+fn reload_geoip_for(
+    request: &Request,
+    config: &Config,
+    geoip: &mut Option<GeoIpDb>,
+    geoip_cache: Option<&RefCell<GeoIpCache>>,
+    response_cache: Option<&RefCell<LruCache<Request, Vec<u8>>>>,
+) -> Option<bool> {
+    let Request::ReloadGeoIp(key) = request else {
+        return None;
+    };
+    if !config.geoip_enabled || !request::is_admin(config, key) {
+        return None;
+    }
+    match reload_geoip(config, geoip, geoip_cache, response_cache) {
+        Ok(()) => {
+            info!("GeoIP database reloaded by request");
+            Some(true)
+        }
+        Err(err) => {
+            error!("GeoIP database could not be reloaded by request: {}", err);
+            Some(false)
+        }
+    }
+}
+
+fn save_geoip_cache(cache: &GeoIpCache, config: &Config) {
+    let path = config.data_path(GEOIP_CACHE_FILE);
+    match cache.save(&path) {
+        Ok(()) => debug!(
+            "Saved {} GeoIP cache entries to {}",
+            cache.len(),
+            path.display()
+        ),
+        Err(err) => warn!("Could not save GeoIP cache to {}: {}", path.display(), err),
+    }
+}
+
+// Receives a decrypted request from the DTLS socket if there is one, otherwise never completes
+#[cfg_attr(not(feature = "dtls"), allow(unused_variables))]
+async fn recv_dtls(
+    server: Option<&mut DtlsServer>,
+    buf: &mut [u8],
+) -> io::Result<(usize, std::net::SocketAddr)> {
+    match server {
+        #[cfg(feature = "dtls")]
+        Some(server) => server.recv(buf).await,
+        _ => std::future::pending().await,
+    }
+}
+
+// Encrypts and sends a response over the DTLS socket, if there is one
+#[cfg_attr(not(feature = "dtls"), allow(unused_variables))]
+fn send_dtls(server: Option<&mut DtlsServer>, response: &[u8], addr: std::net::SocketAddr) {
+    #[cfg(feature = "dtls")]
+    if let Some(server) = server {
+        server.send(response, addr);
+    }
+}
+
+// Receives from the Unix socket if there is one, otherwise never completes
+async fn recv_unix(
+    socket: Option<&UnixDatagram>,
+    buf: &mut [u8],
+) -> io::Result<(usize, unix::SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Waits for future to complete, meanwhile responding to every request with ERR_INITIALIZING.
+// Requests aren't rate limited, since the response is no larger than most requests.
+async fn respond_initializing<F: Future>(
+    future: F,
+    socket: &UdpSocket,
+    unix_socket: Option<&UnixDatagram>,
+    mut dtls: Option<&mut DtlsServer>,
+    config: &Config,
+) -> F::Output {
+    // The original ezTime server has no such error, so in compatibility mode requests go
+    // unanswered, as if the server weren't running yet
+    if config.eztime_compat {
+        return future.await;
+    }
+    pin!(future);
+    let response = [ERR_INITIALIZING, config.response_terminator.as_bytes()].concat();
+    let mut buf = [0u8; RECV_BUFFER_SIZE];
+    let mut unix_buf = [0u8; RECV_BUFFER_SIZE];
+    let mut dtls_buf = [0u8; RECV_BUFFER_SIZE];
+    loop {
+        select! {
+            output = &mut future => return output,
+            Ok((len, addr)) = socket.recv_from(&mut buf) => {
+                if drop_request(&buf[..len], config) {
+                    continue;
+                }
+                if !config.allows_response(len, response.len()) {
+                    log_request!("amplification_limited");
+                    continue;
+                }
+                log_request!("initializing");
+                let addr = config.reply_addr(addr);
+                if let Err(err) = socket.send_to(&response, addr).await {
+                    debug!("Could not send response to {}: {}", addr, err);
+                }
+            },
+            Ok((_, addr)) = recv_dtls(dtls.as_deref_mut(), &mut dtls_buf) => {
+                log_request!("initializing");
+                send_dtls(dtls.as_deref_mut(), &response, addr);
+            },
+            Ok((_, addr)) = recv_unix(unix_socket, &mut unix_buf) => {
+                if let (Some(unix_socket), Some(path)) = (unix_socket, addr.as_pathname()) {
+                    log_request!("initializing");
+                    if let Err(err) = unix_socket.send_to(&response, path).await {
+                        debug!("Could not send response to {}: {}", path.display(), err);
+                    }
+                }
+            },
+        }
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    info!("Initializing");
+
+    // Load config
+    let mut config = Config::load()?;
+    debug!("{:#?}", config);
+    if config.rate_limit.is_zero() {
+        warn!("Rate-limiting is disabled");
+    }
+    config.warn_min_request_size();
+
+    // Sockets are bound before the timezone database is loaded, so clients can be told the server
+    // is initializing if it has to be downloaded first
+    let socket = socket::bind_udp(&config).await?;
+    // Receive buffer
+    let mut buf = [0u8; RECV_BUFFER_SIZE];
+
+    let unix_socket = if config.unix_socket.as_os_str().is_empty() {
+        None
+    } else {
+        remove_stale_socket(&config.unix_socket)?;
+        info!("Binding Unix socket {}", config.unix_socket.display());
+        Some(UnixDatagram::bind(&config.unix_socket)?)
+    };
+    let mut unix_buf = [0u8; RECV_BUFFER_SIZE];
+
+    #[cfg(feature = "dtls")]
+    let mut dtls = match config.dtls_port {
+        0 => None,
+        _ => Some(DtlsServer::bind(&config).await?),
+    };
+    #[cfg(not(feature = "dtls"))]
+    let mut dtls: Option<DtlsServer> = None;
+    let mut dtls_buf = [0u8; RECV_BUFFER_SIZE];
+
+    #[cfg(feature = "metrics")]
+    let mut metrics_pusher = None;
+    #[cfg(feature = "metrics")]
+    if config.metrics_bind.is_some() || !config.metrics_push_url.is_empty() {
+        let builder = metrics_exporter_prometheus::PrometheusBuilder::new();
+        let handle = match &config.metrics_bind {
+            Some(MetricsBind::Tcp(addr)) => {
+                info!("Initializing prometheus exporter on {}/metrics", addr);
+                let (recorder, exporter) = builder.with_http_listener(*addr).build()?;
+                let handle = recorder.handle();
+                tokio::spawn(exporter);
+                metrics::set_boxed_recorder(Box::new(recorder))?;
+                handle
+            }
+            Some(MetricsBind::Unix(path)) => {
+                info!(
+                    "Initializing prometheus exporter on unix:{}/metrics",
+                    path.display()
+                );
+                remove_stale_socket(path)?;
+                let handle = builder.install_recorder()?;
+                metrics_unix::spawn(path, handle.clone())?;
+                handle
+            }
+            None => builder.install_recorder()?,
+        };
+
+        if !config.metrics_push_url.is_empty() {
+            info!(
+                "Pushing metrics to {} every {:?}",
+                config.metrics_push_url, config.metrics_push_period
+            );
+            let pusher = metrics_push::MetricsPusher::new(handle, &config.metrics_push_url)?;
+            pusher.spawn(config.metrics_push_period);
+            metrics_pusher = Some(pusher);
+        }
+
+        metrics::describe_counter!(
+            "timezoned_requests",
+            "Total requests received by the server"
+        );
+        metrics::describe_histogram!(
+            "timezoned_request_duration_seconds",
+            metrics::Unit::Seconds,
+            "Time taken to process requests, from being received to the response being sent"
+        );
+        metrics::describe_counter!(
+            "timezoned_not_found_requests",
+            "Requests for a timezone, country, command, or format that doesn't exist"
+        );
+        metrics::describe_counter!(
+            "timezoned_cache_requests",
+            "Lookups in the response and GeoIP caches, labeled by cache and whether they were hits"
+        );
+        metrics::describe_counter!(
+            "timezoned_unique_clients",
+            "Client addresses that sent a request without one in the rate limit or dedup window"
+        );
+        #[cfg(target_os = "linux")]
+        metrics::describe_counter!(
+            "timezoned_udp_rx_dropped_total",
+            "UDP packets dropped by the kernel, usually because the socket receive buffer was full"
+        );
+        metrics::describe_gauge!(
+            "timezoned_active_clients",
+            "Number of addresses that sent a request within the rate limit or dedup window"
+        );
+        metrics::describe_gauge!(
+            "timezoned_timezones_loaded",
+            "Number of timezones in the timezone database"
+        );
+        metrics::describe_gauge!(
+            "timezoned_countries_loaded",
+            "Number of countries in the timezone database"
+        );
+        metrics::describe_gauge!(
+            "timezoned_tzdb_load_duration_seconds",
+            metrics::Unit::Seconds,
+            "Time taken to load the timezone database the last time it was loaded"
+        );
+    }
+
+    // Every socket is bound by now, so a user that can't bind privileged ports can take over. This
+    // happens before any data is loaded or downloaded, so data files are only ever written by that
+    // user.
+    if !config.user.is_empty() || !config.group.is_empty() {
+        privileges::drop_privileges(&config.user, &config.group)?;
+    }
+
+    // Data files from an archive are extracted to a temporary directory, which is removed when the
+    // server shuts down
+    let data_tar_dir = if config.data_tar.as_os_str().is_empty() {
+        None
+    } else {
+        let dir = extract_data_tar(&config).await?;
+        config.use_data_tar_dir(&dir);
+        info!(
+            "Serving data from {}, refreshes are disabled",
+            config.data_tar.display()
+        );
+        Some(dir)
+    };
+
+    // Load timezone database
+    let mut timezones = match TimezoneDb::load(&config) {
+        Ok(timezones) => timezones,
+        Err(err) if data_tar_dir.is_some() => {
+            return Err(format!(
+                "Could not load timezone database from {}: {}",
+                config.data_tar.display(),
+                err
+            )
+            .into());
+        }
+        // Missing data is downloaded, but data that is there and can't be read needs to be fixed
+        // by the operator, since a refresh may not replace it
+        Err(err @ LoadError::FileNotFound(_)) => {
+            warn!("Could not load timezone database: {}", err);
+            warn!(
+                "Timezone database must first be loaded before the server can accept requests. \
+                 Every request will return '{}' until then",
+                String::from_utf8_lossy(ERR_INITIALIZING)
+            );
+            let update = TimezoneDb::update(&config);
+            respond_initializing(
+                update,
+                &socket,
+                unix_socket.as_ref(),
+                dtls.as_mut(),
+                &config,
+            )
+            .await
+            .map_err(|err| format!("Timezone database refresh failed: {}", err))?;
+            TimezoneDb::load(&config)
+                .map_err(|err| format!("Could not initialize timezone database: {}", err))?
+        }
+        Err(err) => return Err(format!("Could not load timezone database: {}", err).into()),
+    };
+    #[cfg(feature = "metrics")]
+    timezones.record_metrics();
+
+    check_timezones(&config, &timezones)?;
+
+    // Whether lookups are refused because the timezone data is older than max_data_age, which is
+    // checked every client_prune_interval and after every refresh
+    let mut data_stale = check_data_stale(&config, false);
+
+    // Config used by the refresh tasks, which only use settings that can't change while running
+    let refresh_config = config.clone();
+
+    // Seeded per process, so servers started together get different jitter
+    let mut rng = fastrand::Rng::new();
+
+    // Held by the refresh tasks while their scripts run, if they have to take turns
+    let refresh_lock = config.serialize_refresh.then(|| Mutex::new(()));
+
+    // Create task to refresh the timezone database every tz_refresh_period
+    let timezone_refresh_enabled = config.tz_refresh_period.is_some();
+    if !timezone_refresh_enabled {
+        info!("Timezone database refresh is disabled");
+    }
+    let timezone_reloading = Cell::new(false);
+    // Wall clock time of the next refresh, for SCHEDULE requests
+    let timezone_refresh = config.tz_refresh_period.map(|period| {
+        let last_ran_at = TimezoneDb::refreshed_at(&config);
+        let delay = refresh_delay(last_ran_at, period, config.refresh_jitter_percent, &mut rng);
+        (period, delay)
+    });
+    let timezone_refresh_at =
+        Cell::new(timezone_refresh.map(|(_, delay)| SystemTime::now() + delay));
+    let timezone_refresh_task = unfold(
+        timezone_refresh.map(|(period, delay)| interval_after(delay, period)),
+        |interval| async {
+            // The task ends immediately if refreshing is disabled
+            let mut interval = interval?;
+            interval.tick().await;
+            timezone_refresh_at.set(Some(SystemTime::now() + interval.period()));
+            let _turn = refresh_turn(refresh_lock.as_ref(), "Timezone database").await;
+            timezone_reloading.set(true);
+            Some((TimezoneDb::update(&refresh_config).await, Some(interval)))
+        },
+    );
+    pin!(timezone_refresh_task);
+
+    // Load GeoIP database
+    let geoip_refresh_enabled = config.geoip_enabled
+        && !config.mmdb_url.is_empty()
+        && config.geoip_refresh_period.is_some();
+    let mut geoip = if !config.geoip_enabled {
+        info!(
+            "GeoIP is disabled. Every GeoIP request will return '{}'",
+            String::from_utf8_lossy(ERR_GEOIP_DISABLED)
+        );
+        None
+    } else {
+        match GeoIpDb::load(&config) {
+            Ok(geoip) => Some(geoip),
+            Err(err) => {
+                warn!("Could not load GeoIP database: {}", err);
+                if !geoip_refresh_enabled {
+                    warn!(
+                        "GeoIP database refresh is disabled. Every GeoIP request will return '{}'",
+                        String::from_utf8_lossy(ERR_GEOIP_UNAVAILABLE)
+                    );
+                } else {
+                    warn!(
+                        "Until the GeoIP database is loaded, every GeoIP request will return '{}'",
+                        String::from_utf8_lossy(ERR_GEOIP_UNAVAILABLE)
+                    );
+                    // Refreshes are scheduled from when the database file was last modified
+                    match err {
+                        LoadError::FileNotFound(_) => {
+                            warn!("A GeoIP refresh will be scheduled for immediately after the server has started")
+                        }
+                        _ => {
+                            warn!(
+                                "The GeoIP database will be replaced by the next scheduled refresh"
+                            )
+                        }
+                    }
+                }
+                None
+            }
+        }
+    };
+
+    // Create task to refresh the GeoIP database every geoip_refresh_period
+    if config.geoip_enabled && config.geoip_refresh_period.is_none() {
+        info!("GeoIP database refresh is disabled");
+    }
+    let geoip_reloading = Cell::new(false);
+    let geoip_refresh = config
+        .geoip_refresh_period
+        .filter(|_| geoip_refresh_enabled)
+        .map(|period| {
+            let last_ran_at = GeoIpDb::refreshed_at(&config);
+            let delay = refresh_delay(last_ran_at, period, config.refresh_jitter_percent, &mut rng);
+            (period, delay)
+        });
+    let geoip_refresh_at = Cell::new(geoip_refresh.map(|(_, delay)| SystemTime::now() + delay));
+    let geoip_refresh_task = unfold(
+        geoip_refresh.map(|(period, delay)| interval_after(delay, period)),
+        |interval| async {
+            // The task ends immediately if refreshing is disabled
+            let mut interval = interval?;
+            interval.tick().await;
+            geoip_refresh_at.set(Some(SystemTime::now() + interval.period()));
+            let _turn = refresh_turn(refresh_lock.as_ref(), "GeoIP database").await;
+            geoip_reloading.set(true);
+            Some((GeoIpDb::update(&refresh_config).await, Some(interval)))
+        },
+    );
+    pin!(geoip_refresh_task);
+
+    // Cache of GeoIP results, which is saved every geoip_cache_flush_period so it survives restarts
+    let geoip_cache = match NonZeroUsize::new(config.geoip_cache_size) {
+        Some(size) if config.geoip_enabled => {
+            let mut cache = GeoIpCache::new(size);
+            let path = config.data_path(GEOIP_CACHE_FILE);
+            match cache.load(&path) {
+                Ok(()) => info!(
+                    "Loaded {} GeoIP cache entries from {}",
+                    cache.len(),
+                    path.display()
+                ),
+                Err(err) => info!("No GeoIP cache loaded from {}: {}", path.display(), err),
+            }
+            Some(RefCell::new(cache))
+        }
+        _ => None,
+    };
+    let mut geoip_cache_flush_interval =
+        interval(Some(SystemTime::now()), config.geoip_cache_flush_period);
+
+    // Cache of responses to requests that only depend on the databases, which is cleared whenever
+    // they or the config are reloaded
+    let response_cache = NonZeroUsize::new(config.response_cache_size)
+        .map(|size| RefCell::new(LruCache::<Request, Vec<u8>>::new(size)));
+
+    let mut clients = Clients::default();
+    // This interval triggers a task to prune clients that haven't sent a message within the rate limit window,
+    // to prevent using excessive RAM
+    let mut client_prune_interval = interval(Some(SystemTime::now()), config.client_prune_period);
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    info!("Server is ready");
+
+    loop {
+        select! {
+            biased;
+            _ = sigint.recv() => break,
+            _ = sigterm.recv() => break,
+            // Reload settings that can be changed while running from the config file
+            _ = sighup.recv() => {
+                info!("Reloading config");
+                match Config::load() {
+                    Ok(mut new_config) => {
+                        if let Some(dir) = &data_tar_dir {
+                            new_config.use_data_tar_dir(dir);
+                        }
+                        let updated = config.with_live_settings(&new_config);
+                        if updated != new_config {
+                            warn!("Some changed settings will only take effect after a restart");
+                        }
+                        if updated.client_prune_period != config.client_prune_period {
+                            client_prune_interval = interval(Some(SystemTime::now()), updated.client_prune_period);
+                        }
+                        config = updated;
+                        // Cached responses may depend on settings that changed
+                        if let Some(response_cache) = &response_cache {
+                            response_cache.borrow_mut().clear();
+                        }
+                        debug!("{:#?}", config);
+                        config.warn_min_request_size();
+                    },
+                    Err(err) => error!("Could not reload config: {}", err),
+                }
+            },
+            // Reload timezone data
+            Some(result) = timezone_refresh_task.next(), if timezone_refresh_enabled => {
+                timezone_reloading.set(false);
+                match result {
+                    Ok(()) => match TimezoneDb::load(&config) {
+                        Ok(new_timezones) => {
+                            info!("Timezone database refresh complete");
+                            timezones = new_timezones;
+                            if let Some(response_cache) = &response_cache {
+                                response_cache.borrow_mut().clear();
+                            }
+                            #[cfg(feature = "metrics")]
+                            timezones.record_metrics();
+                            data_stale = check_data_stale(&config, data_stale);
+                        },
+                        Err(err) => {
+                            error!("Timezone database refresh completed successfully, but the new data could not be loaded");
+                            error!("Cause: {}", err);
+                        },
+                    },
+                    Err(err) => error!("Timezone database refresh failed: {}", err),
+                }
+            },
+            // Reload GeoIP data
+            Some(result) = geoip_refresh_task.next(), if geoip_refresh_enabled => {
+                geoip_reloading.set(false);
+                match result {
+                    Ok(()) => match reload_geoip(
+                        &config,
+                        &mut geoip,
+                        geoip_cache.as_ref(),
+                        response_cache.as_ref(),
+                    ) {
+                        Ok(()) => info!("GeoIP database refresh complete"),
+                        Err(err) => {
+                            error!("GeoIP database refresh completed successfully, but the new data could not be loaded");
+                            error!("Cause: {}", err);
+                        },
+                    },
+                    Err(err) => error!("GeoIP database refresh failed: {}", err),
+                }
+            },
+            // Save the GeoIP cache every geoip_cache_flush_interval
+            _ = geoip_cache_flush_interval.tick(), if geoip_cache.is_some() => {
+                if let Some(geoip_cache) = &geoip_cache {
+                    save_geoip_cache(&geoip_cache.borrow(), &config);
+                }
+            },
+            // Prune clients that haven't sent requests within the rate limit or dedup window every
+            // client_prune_interval
+            now = client_prune_interval.tick() => {
+                clients.prune(now, &config);
+                #[cfg(feature = "dtls")]
+                if let Some(dtls) = &mut dtls {
+                    dtls.prune(now);
+                }
+                // The kernel keeps its own count, so it's copied rather than incremented
+                #[cfg(all(feature = "metrics", target_os = "linux"))]
+                match socket::dropped_packets(&socket) {
+                    Ok(drops) => {
+                        metrics::absolute_counter!("timezoned_udp_rx_dropped_total", drops)
+                    }
+                    Err(err) => debug!("Could not read dropped UDP packets: {}", err),
+                }
+                data_stale = check_data_stale(&config, data_stale);
+            },
+            // UDP request handler
+            Ok((len, addr)) = socket.recv_from(&mut buf) => {
+                // Don't respond to requests over MAX_REQUEST_SIZE or too short to be useful
+                if drop_request(&buf[..len], &config) {
+                    continue;
+                }
+
+                // Don't respond to rate limited clients, unless they're exempt. The request is
+                // parsed first, since the rate limit depends on its type.
+                let now = Instant::now();
+                let ip = canonical_ip(addr.ip());
+                let reply_addr = config.reply_addr(addr);
+                let request = parse_request(&buf[..len], &config);
+                let response = match clients.admit(ip, &request, now, &config) {
+                    Admission::RateLimited { notify } => {
+                        log_request!("rate_limited");
+                        let response = config.rate_limited();
+                        if notify && config.allows_response(len, response.len()) {
+                            if let Err(err) = socket.send_to(&response, reply_addr).await {
+                                debug!(
+                                    "Could not send rate limit notice to {}: {}",
+                                    reply_addr, err
+                                );
+                            }
+                        }
+                        continue;
+                    }
+                    Admission::DropDuplicate => {
+                        log_request!("duplicate", "result" => "dropped");
+                        continue;
+                    }
+                    Admission::Duplicate(response) => {
+                        log_request!("duplicate", "result" => "answered");
+                        response
+                    }
+                    Admission::LookUp => {
+                        let geoip_reloaded = reload_geoip_for(
+                            &request,
+                            &config,
+                            &mut geoip,
+                            geoip_cache.as_ref(),
+                            response_cache.as_ref(),
+                        );
+                        let ctx = Context {
+                            config: &config,
+                            timezones: &timezones,
+                            geoip: geoip.as_ref(),
+                            geoip_cache: geoip_cache.as_ref(),
+                            response_cache: response_cache.as_ref(),
+                            reloading: timezone_reloading.get() || geoip_reloading.get(),
+                            data_stale,
+                            tz_refresh_at: timezone_refresh_at.get(),
+                            geoip_refresh_at: geoip_refresh_at.get(),
+                            geoip_reloaded,
+                            now: SystemTime::now(),
+                        };
+                        let response = handle_request(&buf[..len], &request, now, &ctx, Some(ip));
+                        clients.record(ip, request, &response, now, &config);
+                        response
+                    }
+                };
+                if !config.allows_response(len, response.len()) {
+                    log_request!("amplification_limited");
+                    continue;
+                }
+                let result = socket.send_to(&response, reply_addr).await;
+                if send_failed_too_large(result, response.len(), reply_addr) {
+                    let response = config.response_too_large();
+                    let result = socket.send_to(&response, reply_addr).await;
+                    send_failed_too_large(result, response.len(), reply_addr);
+                }
+            },
+            // DTLS request handler, rate limited the same way as UDP. Responses aren't limited by
+            // max_amplification, since the handshake has already confirmed the client's address.
+            Ok((len, addr)) = recv_dtls(dtls.as_mut(), &mut dtls_buf) => {
+                if len > MAX_REQUEST_SIZE {
+                    log_request!("too_large");
+                    continue;
+                }
+                let now = Instant::now();
+                let ip = canonical_ip(addr.ip());
+                let request = parse_request(&dtls_buf[..len], &config);
+                let response = match clients.admit(ip, &request, now, &config) {
+                    Admission::RateLimited { notify } => {
+                        log_request!("rate_limited");
+                        if notify {
+                            send_dtls(dtls.as_mut(), &config.rate_limited(), addr);
+                        }
+                        continue;
+                    }
+                    Admission::DropDuplicate => {
+                        log_request!("duplicate", "result" => "dropped");
+                        continue;
+                    }
+                    Admission::Duplicate(response) => {
+                        log_request!("duplicate", "result" => "answered");
+                        response
+                    }
+                    Admission::LookUp => {
+                        let geoip_reloaded = reload_geoip_for(
+                            &request,
+                            &config,
+                            &mut geoip,
+                            geoip_cache.as_ref(),
+                            response_cache.as_ref(),
+                        );
+                        let ctx = Context {
+                            config: &config,
+                            timezones: &timezones,
+                            geoip: geoip.as_ref(),
+                            geoip_cache: geoip_cache.as_ref(),
+                            response_cache: response_cache.as_ref(),
+                            reloading: timezone_reloading.get() || geoip_reloading.get(),
+                            data_stale,
+                            tz_refresh_at: timezone_refresh_at.get(),
+                            geoip_refresh_at: geoip_refresh_at.get(),
+                            geoip_reloaded,
+                            now: SystemTime::now(),
+                        };
+                        let response =
+                            handle_request(&dtls_buf[..len], &request, now, &ctx, Some(ip));
+                        clients.record(ip, request, &response, now, &config);
+                        response
+                    }
+                };
+                send_dtls(dtls.as_mut(), &response, addr);
+            },
+            // Unix socket request handler. There is no rate limiting, since clients are local.
+            Ok((len, addr)) = recv_unix(unix_socket.as_ref(), &mut unix_buf) => {
+                if len > MAX_REQUEST_SIZE {
+                    log_request!("too_large");
+                    continue;
+                }
+                let Some(path) = addr.as_pathname() else {
+                    debug!(
+                        target: logger::REQUEST_LOG_TARGET,
+                        "Ignoring request from unbound Unix socket"
+                    );
+                    continue;
+                };
+
+                let request = parse_request(&unix_buf[..len], &config);
+                let geoip_reloaded = reload_geoip_for(
+                    &request,
+                    &config,
+                    &mut geoip,
+                    geoip_cache.as_ref(),
+                    response_cache.as_ref(),
+                );
+                let ctx = Context {
+                    config: &config,
+                    timezones: &timezones,
+                    geoip: geoip.as_ref(),
+                    geoip_cache: geoip_cache.as_ref(),
+                    response_cache: response_cache.as_ref(),
+                    reloading: timezone_reloading.get() || geoip_reloading.get(),
+                    data_stale,
+                    tz_refresh_at: timezone_refresh_at.get(),
+                    geoip_refresh_at: geoip_refresh_at.get(),
+                    geoip_reloaded,
+                    now: SystemTime::now(),
+                };
+                let response = handle_request(&unix_buf[..len], &request, Instant::now(), &ctx, None);
+                if let Some(unix_socket) = &unix_socket {
+                    let result = unix_socket.send_to(&response, path).await;
+                    if send_failed_too_large(result, response.len(), path.display()) {
+                        let response = config.response_too_large();
+                        let result = unix_socket.send_to(&response, path).await;
+                        send_failed_too_large(result, response.len(), path.display());
+                    }
+                }
+            },
+        };
+    }
+
+    if let Some(geoip_cache) = &geoip_cache {
+        save_geoip_cache(&geoip_cache.borrow(), &config);
+    }
+    // Push the final values, since they would be lost otherwise
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_pusher) = &metrics_pusher {
+        metrics_pusher.push().await;
+    }
+    if !config.unix_socket.as_os_str().is_empty() {
+        remove_socket(&config.unix_socket);
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(MetricsBind::Unix(path)) = &config.metrics_bind {
+        remove_socket(path);
+    }
+    if let Some(dir) = &data_tar_dir {
+        if let Err(err) = fs::remove_dir_all(dir) {
+            warn!("Could not remove {}: {}", dir.display(), err);
+        }
+    }
+    Ok(())
+}
+
+// Extracts the data files in TZD_DATA_TAR to a new temporary directory, returning its path
+async fn extract_data_tar(config: &Config) -> Result<PathBuf, Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("timezoned_rs-{}", std::process::id()));
+    // A directory left behind by an earlier process with the same PID may have other files in it
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir(&dir)?;
+    info!(
+        "Extracting {} to {}",
+        config.data_tar.display(),
+        dir.display()
+    );
+    let result: Result<(), Box<dyn Error>> = sh!(
+        config.update_timeout,
+        EXTRACT_DATA_SH_PATH,
+        &dir,
+        &config.data_tar
+    )
+    .await;
+    if let Err(err) = result {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(format!("Could not extract {}: {}", config.data_tar.display(), err).into());
+    }
+    Ok(dir)
+}
+
+fn remove_socket(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        warn!("Could not remove {}: {}", path.display(), err);
+    }
+}
+
+// Removes a Unix socket left behind by a server that didn't shut down cleanly, so it can be bound
+// again. Anything else at the path is left alone, and binding will fail.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        info!("Removing stale Unix socket {}", path.display());
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// Runs the server until it's shut down, logging why if it couldn't start
+pub async fn serve() {
+    if let Err(err) = logger::init() {
+        error!("{}", err);
+        return;
+    }
+
+    match run().await {
+        Ok(_) => info!("Server has shut down"),
+        Err(err) => error!("{}", err),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "metrics")]
+    use metrics::{Counter, Gauge, Histogram, Key, KeyName, SharedString, Unit};
+    use request::ResponseFormat;
+    #[cfg(feature = "metrics")]
+    use std::sync::atomic::{AtomicU64, Ordering};
+    #[cfg(feature = "metrics")]
+    use std::sync::Arc;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_string("europe/berlin"), "EUROPE/BERLIN");
+        assert_eq!(normalize_string("America/New_York"), "AMERICA/NEW_YORK");
+        assert_eq!(normalize_string(" \tnew york\r\n"), "NEW_YORK");
+        // Only spaces inside are replaced
+        assert_eq!(normalize_string("new  york"), "NEW__YORK");
+        assert_eq!(normalize_string("new\tyork"), "NEW\tYORK");
+        assert_eq!(normalize_string("Zürich"), "ZÜRICH");
+        assert_eq!(normalize_string("Côte d'Ivoire"), "CÔTE_D'IVOIRE");
+        assert_eq!(normalize_string("straße"), "STRASSE");
+        assert_eq!(normalize_string(""), "");
+    }
+
+    #[test]
+    fn normalize_posix_round_trip() {
+        for posix in [
+            "<+0545>-5:45",
+            "<-03>3",
+            "NST3:30NDT,M3.2.0,M11.1.0",
+            "Cet-1Cest,M3.5.0,M10.5.0/3",
+            "<+1030>-10:30<+11>-11,M10.1.0,M4.1.0",
+            "IST-1GMT0,M10.5.0,M3.5.0/1",
+        ] {
+            let normalized = normalize_posix(&format!(" \t{posix}\r\n"));
+            assert_eq!(normalized, posix);
+            assert_eq!(Posix::parse(&normalized), Posix::parse(posix));
+        }
+        // Olson name normalization would change the zone names
+        assert_ne!(
+            Posix::parse(&normalize_string("Cet-1Cest,M3.5.0,M10.5.0/3")),
+            Posix::parse("Cet-1Cest,M3.5.0,M10.5.0/3")
+        );
+    }
+
+    #[test]
+    fn country_primary() {
+        let mut db = TimezoneDb {
+            timezones: Vec::new(),
+            olson_map: HashMap::new(),
+            city_map: HashMap::new(),
+            country_name_map: HashMap::new(),
+            link_map: HashMap::new(),
+            country_map: HashMap::new(),
+            previous_posix_map: HashMap::new(),
+            loaded_at: SystemTime::now(),
+            load_duration: Duration::ZERO,
+        };
+        db.add_timezone("Australia/Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3")
+            .unwrap();
+        db.add_timezone("Australia/Brisbane", "AEST-10").unwrap();
+        db.add_timezone("America/New_York", "EST5EDT,M3.2.0,M11.1.0")
+            .unwrap();
+        db.add_timezone("America/Chicago", "CST6CDT,M3.2.0,M11.1.0")
+            .unwrap();
+        for (country, olson) in [
+            ("AU", "Australia/Sydney"),
+            ("AU", "Australia/Brisbane"),
+            ("US", "America/New_York"),
+            ("US", "America/Chicago"),
+        ] {
+            db.add_country_timezone(country, olson).unwrap();
+        }
+
+        db.set_country_primary("AU", "Australia/Sydney").unwrap();
+        // Primary timezones have to be one of the country's own
+        assert!(db.set_country_primary("US", "Australia/Sydney").is_err());
+        assert!(db.set_country_primary("XX", "Australia/Sydney").is_err());
+
+        let primary = db.lookup_country_primary("AU").unwrap();
+        assert_eq!(primary.olson, "Australia/Sydney");
+        assert!(db.lookup_country_primary("US").is_none());
+    }
+
+    #[test]
+    fn secret_redacted() {
+        assert_eq!(format!("{:?}", Secret("license".into())), "***");
+        assert_eq!(format!("{:?}", Secret::default()), "\"\"");
+    }
+
+    #[test]
+    fn strict_commands() {
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        let respond = |config: &Config, request: &[u8]| {
+            let ctx = fixtures::context(config, &db);
+            let parsed = parse_request(request, config);
+            let response = handle_request(request, &parsed, Instant::now(), &ctx, None);
+            String::from_utf8(response).unwrap()
+        };
+        assert_eq!(respond(&config, b"UTC"), "ERROR Timezone Not Found");
+        config.strict_commands = true;
+        assert_eq!(respond(&config, b"UTC"), "ERROR Unknown Command");
+        // Known commands are still answered
+        assert_eq!(respond(&config, b"GEOIP"), "ERROR GeoIP Unavailable");
+    }
+
+    #[test]
+    fn response_cache() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let cache = RefCell::new(LruCache::new(NonZeroUsize::new(8).unwrap()));
+        let respond = |request: &[u8], data_stale: bool| {
+            let ctx = Context {
+                response_cache: Some(&cache),
+                data_stale,
+                ..fixtures::context(&config, &db)
+            };
+            let parsed = parse_request(request, &config);
+            handle_request(request, &parsed, Instant::now(), &ctx, None)
+        };
+
+        assert_eq!(
+            respond(b"Europe/Berlin", false),
+            b"OK Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        let key = Request::Olson("EUROPE/BERLIN".into(), ResponseFormat::Default);
+        assert!(cache.borrow().contains(&key));
+        // Cached responses are returned without looking the timezone up again
+        cache.borrow_mut().put(key, b"OK cached".to_vec());
+        assert_eq!(respond(b"Europe/Berlin", false), b"OK cached");
+        assert_ne!(respond(b"Europe/Berlin", true), b"OK cached");
+
+        // Errors and responses that depend on the time aren't cached
+        respond(b"Europe/Atlantis", false);
+        respond(b"Europe/Berlin?offset", false);
+        assert_eq!(cache.borrow().len(), 1);
+    }
+
+    #[test]
+    fn response_terminator() {
+        let file = HashMap::from([("TZD_RESPONSE_TERMINATOR".to_owned(), "CRLF".to_owned())]);
+        let config = Config::from_file(&file).unwrap();
+        assert_eq!(config.response_terminator, ResponseTerminator::Crlf);
+        let file = HashMap::from([("TZD_RESPONSE_TERMINATOR".to_owned(), "cr".to_owned())]);
+        assert!(Config::from_file(&file).is_err());
+
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        config.response_terminator = ResponseTerminator::Lf;
+        config.log_unknown = true;
+        let ctx = fixtures::context(&config, &db);
+        let respond = |request: &[u8]| {
+            let parsed = parse_request(request, &config);
+            handle_request(request, &parsed, Instant::now(), &ctx, None)
+        };
+        assert_eq!(respond(b"Asia/Kolkata"), b"OK Asia/Kolkata IST-5:30\n");
+        assert_eq!(respond(b"Europe/Atlantis"), b"ERROR Timezone Not Found\n");
+    }
+
+    #[test]
+    fn config_file() {
+        let file = HashMap::from([
+            ("TZD_PORT".to_owned(), "2400".to_owned()),
+            ("TZD_RATELIMIT_MS".to_owned(), "500".to_owned()),
+            ("TZD_STRICT_COMMANDS".to_owned(), "true".to_owned()),
+        ]);
+        let config = Config::from_file(&file).unwrap();
+        assert_eq!(config.port, 2400);
+        assert_eq!(config.rate_limit, Duration::from_millis(500));
+        assert!(config.strict_commands);
+        // Options missing from the file get their defaults
+        assert_eq!(config.host, "0.0.0.0");
+
+        let file = HashMap::from([("TZD_PORT".to_owned(), "port".to_owned())]);
+        assert_eq!(
+            Config::from_file(&file),
+            Err("TZD_PORT is configured with invalid value 'port', expected u16".into())
+        );
+    }
+
+    #[test]
+    fn live_settings() {
+        let config = fixtures::config();
+        let mut new = fixtures::config();
+        new.rate_limit = Duration::from_millis(500);
+        new.strict_commands = true;
+        new.port = 2400;
+        let updated = config.with_live_settings(&new);
+        assert_eq!(updated.rate_limit, Duration::from_millis(500));
+        assert!(updated.strict_commands);
+        // Sockets are only bound at startup
+        assert_eq!(updated.port, config.port);
+        assert_ne!(updated, new);
+    }
+
+    // Responses to the requests the ezTime server supports, which clients may match byte for byte
+    #[test]
+    fn eztime_responses() {
+        let mut config = fixtures::config();
+        let db = fixtures::timezone_db();
+        let geoip = fixtures::geoip([]);
+        let respond = |config: &Config, geoip, request: &str| {
+            let mut ctx = fixtures::context(config, &db);
+            ctx.geoip = geoip;
+            let addr = Some("192.0.2.1".parse().unwrap());
+            let parsed = parse_request(request.as_bytes(), config);
+            let response = handle_request(request.as_bytes(), &parsed, Instant::now(), &ctx, addr);
+            String::from_utf8(response).unwrap()
+        };
+        // Both modes
+        for eztime_compat in [false, true] {
+            config.eztime_compat = eztime_compat;
+            for (request, response) in [
+                (
+                    "Europe/Berlin",
+                    "OK Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3",
+                ),
+                ("DE", "OK Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3"),
+                ("Europe/Atlantis", "ERROR Timezone Not Found"),
+                ("XX", "ERROR Country Not Found"),
+                ("US", "ERROR Country Spans Multiple Timezones"),
+                ("GEOIP", "ERROR GeoIP Lookup Failed"),
+                ("LISTCOUNTRY CH", "OK Europe/Zurich"),
+            ] {
+                assert_eq!(respond(&config, Some(&geoip), request), response);
+            }
+        }
+
+        // Other errors only without TZD_EZTIME_COMPAT
+        config.eztime_compat = false;
+        assert_eq!(respond(&config, None, "GEOIP"), "ERROR GeoIP Unavailable");
+        assert_eq!(respond(&config, None, "BYOFFSET x"), "ERROR Invalid Offset");
+        assert_eq!(respond(&config, None, "DE?bogus"), "ERROR Unknown Format");
+        assert_eq!(config.response_too_large(), request::ERR_RESPONSE_TOO_LARGE);
+        config.geoip_enabled = false;
+        assert_eq!(respond(&config, None, "GEOIP"), "ERROR GeoIP Disabled");
+
+        config.eztime_compat = true;
+        assert_eq!(respond(&config, None, "GEOIP"), "ERROR GeoIP Lookup Failed");
+        assert_eq!(
+            respond(&config, None, "GEOIPINFO"),
+            "ERROR GeoIP Lookup Failed"
+        );
+        config.geoip_enabled = true;
+        assert_eq!(respond(&config, None, "GEOIP"), "ERROR GeoIP Lookup Failed");
+        assert_eq!(
+            respond(&config, None, "BYOFFSET x"),
+            "ERROR Timezone Not Found"
+        );
+        assert_eq!(
+            respond(&config, None, "DE?bogus"),
+            "ERROR Timezone Not Found"
+        );
+        assert_eq!(config.response_too_large(), ERR_TIMEZONE_NOT_FOUND);
+        config.max_data_age = Some(Duration::ZERO);
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.data_stale = true;
+        let request = parse_request(b"DE", &config);
+        let response = handle_request(b"DE", &request, Instant::now(), &ctx, None);
+        assert_eq!(response, request::ERR_COUNTRY_NOT_FOUND);
+    }
+
+    #[test]
+    fn min_request_size() {
+        // Nothing is dropped for being short by default
+        let mut config = fixtures::config();
+        assert!(!drop_request(b"", &config));
+        assert!(!drop_request(b"  ", &config));
+        assert!(!drop_request(&[b'a'; MAX_REQUEST_SIZE], &config));
+        assert!(drop_request(&[b'a'; MAX_REQUEST_SIZE + 1], &config));
+
+        config.min_request_size = 6;
+        assert!(!drop_request(b"Europe/Berlin", &config));
+        // Country codes are exempt, but nothing else shorter is
+        assert!(!drop_request(b"de", &config));
+        assert!(!drop_request(b" DE\n", &config));
+        assert!(drop_request(b"GEOIP", &config));
+        assert!(drop_request(b"UTC", &config));
+        assert!(drop_request(b"12", &config));
+        assert!(drop_request(b"d", &config));
+        assert!(drop_request(b"  ", &config));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_bind() {
+        let bind = |vars: &[(&str, &str)]| {
+            let file = vars
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            Config::from_file(&file).map(|config| config.metrics_bind)
+        };
+        assert_eq!(bind(&[]), Ok(None));
+        assert_eq!(
+            bind(&[("TZD_METRICS_PORT", "9100")]),
+            Ok(Some(MetricsBind::Tcp("0.0.0.0:9100".parse().unwrap())))
+        );
+        // TZD_METRICS_BIND overrides the host and port
+        assert_eq!(
+            bind(&[
+                ("TZD_METRICS_BIND", "unix:/run/timezoned/metrics.sock"),
+                ("TZD_METRICS_PORT", "9100"),
+            ]),
+            Ok(Some(MetricsBind::Unix(
+                "/run/timezoned/metrics.sock".into()
+            )))
+        );
+        assert_eq!(
+            bind(&[("TZD_METRICS_BIND", "[::1]:9100")]),
+            Ok(Some(MetricsBind::Tcp("[::1]:9100".parse().unwrap())))
+        );
+        assert!(bind(&[("TZD_METRICS_BIND", "unix:")]).is_err());
+        assert!(bind(&[("TZD_METRICS_BIND", "localhost")]).is_err());
+    }
+
+    #[test]
+    fn time_until_run() {
+        let day = Duration::from_secs(SECONDS_PER_DAY);
+        assert_eq!(super::time_until_run(None, day), Duration::ZERO);
+        let ran_at = SystemTime::now() - Duration::from_secs(3600);
+        let until = super::time_until_run(Some(ran_at), day);
+        assert!(until <= day - Duration::from_secs(3600));
+        assert!(until > day - Duration::from_secs(3660));
+        // Overdue tasks run immediately
+        let ran_at = SystemTime::now() - 2 * day;
+        assert_eq!(super::time_until_run(Some(ran_at), day), Duration::ZERO);
+    }
+
+    #[test]
+    fn overrides() {
+        let (config, _dir) = fixtures::config_with_data_dir();
+        let local = config.data_path(OVERRIDES_FILE);
+        let remote = config.data_path(REMOTE_OVERRIDES_FILE);
+        fs::write(
+            &local,
+            "# Comment\nPRIMARY US America/Chicago\nPRIMARY AU\n",
+        )
+        .unwrap();
+        fs::write(&remote, "PRIMARY US America/New_York\n").unwrap();
+
+        let mut db = fixtures::timezone_db();
+        db.load_overrides(&local);
+        let primary = db.lookup_country_primary("US").unwrap();
+        assert_eq!(primary.olson, "America/Chicago");
+        // Remote overrides are applied last
+        db.load_overrides(&remote);
+        let primary = db.lookup_country_primary("US").unwrap();
+        assert_eq!(primary.olson, "America/New_York");
+        // Missing files are skipped
+        db.load_overrides(&config.data_path("missing"));
+    }
+
+    #[test]
+    fn coordinates() {
+        assert_eq!(
+            parse_coordinates("+4723+00832"),
+            Some((47.0 + 23.0 / 60.0, 8.0 + 32.0 / 60.0))
+        );
+        let (latitude, longitude) = parse_coordinates("+404251-0740023").unwrap();
+        assert!((latitude - 40.714167).abs() < 1e-6);
+        assert!((longitude + 74.006389).abs() < 1e-6);
+        assert_eq!(
+            parse_coordinates("-3352+15113").map(|(lat, _)| lat < 0.0),
+            Some(true)
+        );
+        for invalid in [
+            "",
+            "+4723",
+            "+4723+0083",
+            "4723+00832",
+            "+47a3+00832",
+            "+4723*00832",
+        ] {
+            assert_eq!(parse_coordinates(invalid), None, "{}", invalid);
+        }
+    }
+
+    // With embedded data, that would be loaded instead
+    #[cfg(not(feature = "embedded-data"))]
+    #[test]
+    fn load_posixinfo_without_zonetab() {
+        let (config, _dir) = fixtures::config_with_data_dir();
+        fs::write(config.data_path(POSIXINFO_FILE), fixtures::POSIXINFO).unwrap();
+        let db = TimezoneDb::load(&config).unwrap();
+        assert!(db.lookup_olson("EUROPE/BERLIN").is_some());
+        assert!(db.lookup_country("IN").is_none());
+        let ctx = fixtures::context(&config, &db);
+        let respond = |request| String::from_utf8(Request::parse(request).respond(&ctx, None));
+        assert_eq!(
+            respond("Europe/Berlin").unwrap(),
+            "OK Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        assert_eq!(respond("IN").unwrap(), "ERROR Country Not Found");
+    }
+
+    #[test]
+    fn refresh_disabled() {
+        let file = HashMap::from([
+            ("TZD_TZ_REFRESH_DAYS".to_owned(), "0".to_owned()),
+            ("TZD_GEOIP_REFRESH_DAYS".to_owned(), "2".to_owned()),
+        ]);
+        let config = Config::from_file(&file).unwrap();
+        assert_eq!(config.tz_refresh_period, None);
+        assert_eq!(
+            config.geoip_refresh_period,
+            Some(Duration::from_secs(2 * SECONDS_PER_DAY))
+        );
+    }
+
+    #[test]
+    fn escaped_requests() {
+        assert_eq!(escape_request(b" Europe/London\n"), "Europe/London");
+        assert_eq!(escape_request(b"GEOIP\t\"x\""), "GEOIP\\t\\\"x\\\"");
+        assert_eq!(escape_request(b"\xff\x01"), "\\u{fffd}\\u{1}");
+        let long = "A".repeat(MAX_LOGGED_REQUEST_CHARS * 2);
+        assert_eq!(
+            escape_request(long.as_bytes()).len(),
+            MAX_LOGGED_REQUEST_CHARS
+        );
+    }
+
+    #[test]
+    fn redacted_requests() {
+        assert_eq!(redact_request(b"GEOIPRAW key 192.0.2.1"), b"GEOIPRAW");
+        // Malformed admin requests still contain the key
+        assert_eq!(redact_request(b" geoipraw key not-an-ip\n"), b"GEOIPRAW");
+        assert_eq!(redact_request(b"GeoIpRaw key"), b"GEOIPRAW");
+        assert_eq!(redact_request(b"GEOIPRAW\tkey?json"), b"GEOIPRAW");
+        assert_eq!(redact_request(b"GEOIPRAW?key"), b"GEOIPRAW");
+        assert_eq!(redact_request(b"reloadgeoip key"), b"RELOADGEOIP");
+        assert_eq!(redact_request(b"RELOADGEOIP key extra"), b"RELOADGEOIP");
+        assert_eq!(redact_request(b"dump key 10"), b"DUMP");
+        assert_eq!(redact_request(b"DUMP key xyz"), b"DUMP");
+        assert_eq!(redact_request(b"GEOIP"), b"GEOIP");
+        assert_eq!(redact_request(b"GEOIPRAWKEY"), b"GEOIPRAWKEY");
+        assert_eq!(redact_request(b"Europe/London"), b"Europe/London");
+    }
+
+    #[test]
+    fn unknown_requests() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        let unknown = |raw: &[u8]| {
+            let response = parse_request(raw, &config).respond(&ctx, None);
+            is_unknown_request(redact_request(raw), &response)
+        };
+        assert!(unknown(b"Europe/Atlantis"));
+        assert!(unknown(b"BYOFFSET xyz"));
+        assert!(!unknown(b"Europe/London"));
+        // Malformed admin requests get the same errors, but aren't counted
+        assert!(!unknown(b"DUMP key xyz"));
+        assert!(!unknown(b"dump"));
+        assert!(!unknown(b"GEOIPRAW key not-an-ip"));
+    }
+
+    #[test]
+    fn geoip_schemas() {
+        let (config, _dir) = fixtures::config_with_data_dir();
+        let db = fixtures::timezone_db();
+        let path = config.data_path(MMDB_CITY_FILE);
+        let respond = |geoip: &GeoIpDb, addr: &str| {
+            let mut ctx = fixtures::context(&config, &db);
+            ctx.geoip = Some(geoip);
+            let response = Request::parse("GEOIP").respond(&ctx, Some(addr.parse().unwrap()));
+            String::from_utf8(response).unwrap()
+        };
+
+        fs::copy(fixtures::testdata("GeoLite2-City.mmdb"), &path).unwrap();
+        let geoip = GeoIpDb::load(&config).unwrap();
+        let location = geoip.lookup("192.0.2.1".parse().unwrap()).unwrap();
+        assert_eq!(location.timezone, Some("Europe/Zurich"));
+        assert_eq!(location.country, Some("CH"));
+        assert_eq!(
+            respond(&geoip, "2001:db8::1"),
+            "OK America/Chicago CST6CDT,M3.2.0,M11.1.0"
+        );
+        assert_eq!(respond(&geoip, "198.51.100.1"), "OK Asia/Kolkata IST-5:30");
+        assert_eq!(respond(&geoip, "203.0.113.1"), "ERROR GeoIP Lookup Failed");
+
+        // Country databases are stored under the same name, and only have countries
+        drop(geoip);
+        fs::copy(fixtures::testdata("GeoLite2-Country.mmdb"), &path).unwrap();
+        let geoip = GeoIpDb::load(&config).unwrap();
+        let location = geoip.lookup("192.0.2.1".parse().unwrap()).unwrap();
+        assert_eq!(location.timezone, None);
+        assert_eq!(location.country, Some("IN"));
+        assert_eq!(respond(&geoip, "192.0.2.1"), "OK Asia/Kolkata IST-5:30");
+        assert_eq!(respond(&geoip, "198.51.100.1"), "ERROR GeoIP Lookup Failed");
+    }
+
+    #[test]
+    fn data_stale() {
+        let (mut config, _dir) = fixtures::config_with_data_dir();
+        // Data of unknown age is only stale if there's a maximum age
+        assert!(!TimezoneDb::is_stale(&config));
+        config.max_data_age = Some(Duration::from_secs(2 * SECONDS_PER_DAY));
+        assert!(TimezoneDb::is_stale(&config));
+
+        let file = fs::File::create(config.data_path(POSIXINFO_FILE)).unwrap();
+        assert!(!TimezoneDb::is_stale(&config));
+        let three_days_ago = SystemTime::now() - Duration::from_secs(3 * SECONDS_PER_DAY);
+        file.set_modified(three_days_ago).unwrap();
+        assert!(TimezoneDb::is_stale(&config));
+        assert!(check_data_stale(&config, false));
+        config.max_data_age = None;
+        assert!(!check_data_stale(&config, true));
+    }
+
+    #[test]
+    fn send_failures() {
+        assert!(!send_failed_too_large(Ok(10), 10, "client"));
+        assert!(!send_failed_too_large(Ok(5), 10, "client"));
+        let too_large = io::Error::from_raw_os_error(libc::EMSGSIZE);
+        assert!(send_failed_too_large(Err(too_large), 10, "client"));
+        let refused = io::Error::from_raw_os_error(libc::ECONNREFUSED);
+        assert!(!send_failed_too_large(Err(refused), 10, "client"));
+    }
+
+    #[test]
+    fn max_amplification() {
+        let mut config = fixtures::config();
+        assert!(config.allows_response(1, request::MAX_RESPONSE_SIZE));
+        config.max_amplification = 4;
+        assert!(config.allows_response(5, 20));
+        assert!(!config.allows_response(5, 21));
+    }
+
+    #[test]
+    fn rate_limit_classes() {
+        let mut config = fixtures::config();
+        config.rate_limit = Duration::from_secs(1);
+        config.rate_limit_geoip = Some(Duration::from_secs(10));
+        let mut clients = Clients::default();
+        let ip = "192.0.2.1".parse().unwrap();
+        let (olson, geoip) = (Request::parse("Europe/Berlin"), Request::parse("GEOIP"));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs_f64(secs);
+        let limited = Admission::RateLimited { notify: false };
+
+        assert_eq!(
+            clients.admit(ip, &geoip, at(0.0), &config),
+            Admission::LookUp
+        );
+        // Each class has its own window, which only restarts when a request is answered
+        assert_eq!(
+            clients.admit(ip, &olson, at(0.5), &config),
+            Admission::LookUp
+        );
+        assert_eq!(clients.admit(ip, &olson, at(1.0), &config), limited);
+        assert_eq!(clients.admit(ip, &geoip, at(1.0), &config), limited);
+        assert_eq!(
+            clients.admit(ip, &olson, at(1.5), &config),
+            Admission::LookUp
+        );
+        assert_eq!(clients.admit(ip, &geoip, at(9.5), &config), limited);
+        assert_eq!(
+            clients.admit(ip, &geoip, at(10.0), &config),
+            Admission::LookUp
+        );
+
+        // Clients are only pruned once the window of their class has passed
+        clients.prune(at(12.0), &config);
+        assert_eq!(clients.0.len(), 1);
+        clients.prune(at(20.0), &config);
+        assert!(clients.0.is_empty());
+
+        // Without a GEOIP rate limit, every request shares the same window
+        config.rate_limit_geoip = None;
+        assert_eq!(
+            clients.admit(ip, &geoip, at(30.0), &config),
+            Admission::LookUp
+        );
+        assert_eq!(clients.admit(ip, &olson, at(30.5), &config), limited);
+    }
+
+    #[test]
+    fn rate_limit_notify() {
+        let mut config = fixtures::config();
+        config.rate_limit = Duration::from_secs(1);
+        let mut clients = Clients::default();
+        let ip = "192.0.2.1".parse().unwrap();
+        let request = Request::parse("Europe/Berlin");
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs_f64(secs);
+
+        let limited = |notify| Admission::RateLimited { notify };
+
+        // Clients are never told by default
+        assert_eq!(
+            clients.admit(ip, &request, at(0.0), &config),
+            Admission::LookUp
+        );
+        assert_eq!(
+            clients.admit(ip, &request, at(0.1), &config),
+            limited(false)
+        );
+
+        // Only once per window, and the notice doesn't restart it
+        config.rate_limit_notify = true;
+        assert_eq!(clients.admit(ip, &request, at(0.2), &config), limited(true));
+        assert_eq!(
+            clients.admit(ip, &request, at(0.3), &config),
+            limited(false)
+        );
+        assert_eq!(
+            clients.admit(ip, &request, at(1.0), &config),
+            Admission::LookUp
+        );
+        assert_eq!(clients.admit(ip, &request, at(1.1), &config), limited(true));
+        assert_eq!(config.rate_limited(), b"ERROR Rate Limited");
+
+        // The original ezTime server has no such response
+        config.eztime_compat = true;
+        assert_eq!(
+            clients.admit(ip, &request, at(2.0), &config),
+            Admission::LookUp
+        );
+        assert_eq!(
+            clients.admit(ip, &request, at(2.1), &config),
+            limited(false)
+        );
+    }
+
+    #[test]
+    fn load_without_data_files() {
+        let (config, _dir) = fixtures::config_with_data_dir();
+        let db = TimezoneDb::load(&config);
+        // Without the files, only embedded data can be loaded
+        #[cfg(not(feature = "embedded-data"))]
+        assert!(db.is_err());
+        #[cfg(feature = "embedded-data")]
+        assert!(db.unwrap().lookup_olson("EUROPE/BERLIN").is_some());
+
+        fs::write(config.data_path(POSIXINFO_FILE), fixtures::POSIXINFO).unwrap();
+        fs::write(config.data_path(ZONETAB_FILE), fixtures::ZONETAB).unwrap();
+        let db = TimezoneDb::load(&config).unwrap();
+        assert_eq!(db.timezones.len(), 18);
+    }
+
+    #[test]
+    fn self_test_fails_startup() {
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        config.selftest = SelfTest::Fail;
+        assert_eq!(check_timezones(&config, &db), Ok(()));
+
+        config.selftest_probes.push("Europe/Atlantis".into());
+        assert_eq!(
+            check_timezones(&config, &db),
+            Err("Timezone database failed the self-test".into())
+        );
+        // Failures are only logged unless the self-test is set to fail
+        config.selftest = SelfTest::Warn;
+        assert_eq!(check_timezones(&config, &db), Ok(()));
+    }
+
+    #[test]
+    fn geoip_default_must_exist() {
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        config.geoip_default = Some(normalize_string("Europe/Atlantis"));
+        assert_eq!(
+            check_timezones(&config, &db),
+            Err("TZD_GEOIP_DEFAULT timezone 'EUROPE/ATLANTIS' was not found".into())
+        );
+        config.geoip_default = Some(normalize_string("Europe/London"));
+        assert_eq!(check_timezones(&config, &db), Ok(()));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        let ip = |addr: &str| addr.parse::<IpAddr>().unwrap();
+        assert_eq!(canonical_ip(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(canonical_ip(ip("192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(canonical_ip(ip("2001:db8::1")), ip("2001:db8::1"));
+    }
+
+    #[tokio::test]
+    async fn script_timeout() {
+        let started = Instant::now();
+        let result: Result<(), Box<dyn Error>> =
+            sh!(Duration::from_millis(100), "-c", "sleep 10").await;
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let result: Result<(), Box<dyn Error>> = sh!(Duration::from_secs(5), "-c", "exit 0").await;
+        assert!(result.is_ok());
+        let result: Result<(), Box<dyn Error>> =
+            sh!(Duration::from_secs(5), "-c", "echo failed >&2; exit 3").await;
+        assert!(result.unwrap_err().to_string().contains("exit status: 3"));
+    }
+
+    #[tokio::test]
+    async fn initializing_responses() {
+        let mut config = fixtures::config();
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr().unwrap()).await.unwrap();
+        let mut buf = [0u8; RECV_BUFFER_SIZE];
+
+        client.send(b"Europe/Berlin").await.unwrap();
+        let loading = tokio::time::sleep(Duration::from_millis(500));
+        let output = respond_initializing(
+            async {
+                loading.await;
+                7
+            },
+            &server,
+            None,
+            None,
+            &config,
+        );
+        assert_eq!(output.await, 7);
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], ERR_INITIALIZING);
+
+        // Compatibility mode doesn't answer until the database is loaded
+        config.eztime_compat = true;
+        client.send(b"Europe/Berlin").await.unwrap();
+        let loading = tokio::time::sleep(Duration::from_millis(200));
+        let output = respond_initializing(
+            async {
+                loading.await;
+                7
+            },
+            &server,
+            None,
+            None,
+            &config,
+        );
+        assert_eq!(output.await, 7);
+        let received = tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buf));
+        assert!(received.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn data_tar() {
+        let (mut config, dir) = fixtures::config_with_data_dir();
+        config.tz_refresh_period = Some(Duration::from_secs(60));
+        let files = dir.path().join("data");
+        fs::create_dir(&files).unwrap();
+        fs::write(
+            files.join(POSIXINFO_FILE),
+            "Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3\n",
+        )
+        .unwrap();
+        fs::write(files.join(ZONETAB_FILE), "DE\t+5230+01322\tEurope/Berlin\n").unwrap();
+        fs::copy(
+            fixtures::testdata("GeoLite2-Country.mmdb"),
+            files.join("GeoLite2-Country.mmdb"),
+        )
+        .unwrap();
+        // The files can be inside of a directory in the archive
+        let tar = dir.path().join("data.tar.gz");
+        config.data_tar = tar.clone();
+        let archive = || {
+            let status = std::process::Command::new("tar")
+                .arg("czf")
+                .arg(&tar)
+                .arg("-C")
+                .arg(dir.path())
+                .arg("data")
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        archive();
+
+        let extracted = extract_data_tar(&config).await.unwrap();
+        config.use_data_tar_dir(&extracted);
+        assert_eq!(config.tz_refresh_period, None);
+        let db = TimezoneDb::load(&config).unwrap();
+        assert!(db.lookup_olson("EUROPE/BERLIN").is_some());
+        // Country databases are renamed, since the type is detected when loading
+        assert!(GeoIpDb::load(&config).is_ok());
+        fs::remove_dir_all(&extracted).unwrap();
+
+        // Archives without the required files are refused, and nothing is left behind
+        fs::remove_file(files.join(ZONETAB_FILE)).unwrap();
+        archive();
+        assert!(extract_data_tar(&config).await.is_err());
+        assert!(!extracted.exists());
+    }
+
+    #[tokio::test]
+    async fn serialized_refreshes() {
+        assert!(refresh_turn(None, "Timezone database").await.is_none());
+
+        // A refresh waits for the one holding the lock to finish
+        let lock = Mutex::new(());
+        let turn = refresh_turn(Some(&lock), "Timezone database").await;
+        assert!(turn.is_some());
+        let waiting = refresh_turn(Some(&lock), "GeoIP database");
+        pin!(waiting);
+        let timeout = tokio::time::timeout(Duration::from_millis(50), &mut waiting);
+        assert!(timeout.await.is_err());
+        drop(turn);
+        assert!(waiting.await.is_some());
+    }
+
+    #[test]
+    fn refresh_jitter() {
+        let period = Duration::from_secs(1000);
+        // Due in about 500s
+        let last_ran_at = Some(SystemTime::now() - Duration::from_secs(500));
+        let (mut earlier, mut later) = (false, false);
+        for seed in 0..1000 {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            let delay = refresh_delay(last_ran_at, period, 10, &mut rng).as_secs_f64();
+            assert!((399.0..=600.0).contains(&delay), "{}", delay);
+            earlier |= delay < 490.0;
+            later |= delay > 510.0;
+        }
+        assert!(earlier && later);
+
+        // Jitter doesn't move refreshes before now, or delay ones that are due
+        let mut rng = fastrand::Rng::with_seed(0);
+        let last_ran_at = Some(SystemTime::now() - Duration::from_secs(990));
+        for _ in 0..1000 {
+            let delay = refresh_delay(last_ran_at, period, 50, &mut rng);
+            assert!(delay <= Duration::from_secs(510));
+        }
+        assert_eq!(refresh_delay(None, period, 50, &mut rng), Duration::ZERO);
+        let mut rng = fastrand::Rng::with_seed(0);
+        let a = refresh_delay(last_ran_at, period, 50, &mut rng);
+        let mut rng = fastrand::Rng::with_seed(0);
+        let b = refresh_delay(last_ran_at, period, 50, &mut rng);
+        assert!(a.abs_diff(b) < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn script_output() {
+        assert_eq!(super::script_output(b"out\n", b"err\n"), "out\nerr");
+        assert_eq!(super::script_output(b"", b" \n"), "");
+        // Only the end is kept, without splitting a character
+        let stdout = "é".repeat(MAX_LOGGED_SCRIPT_OUTPUT);
+        let output = super::script_output(stdout.as_bytes(), b"done");
+        assert!(output.starts_with("(truncated)...é"));
+        assert!(output.ends_with("édone"));
+        assert!(output.len() <= "(truncated)...".len() + MAX_LOGGED_SCRIPT_OUTPUT);
+    }
+
+    // Records the value of every gauge by name, and of every counter by name and labels, ignoring
+    // histograms
+    #[cfg(feature = "metrics")]
+    #[derive(Default)]
+    struct Recorded {
+        gauges: std::sync::Mutex<HashMap<String, Arc<AtomicU64>>>,
+        counters: std::sync::Mutex<HashMap<String, Arc<AtomicU64>>>,
+    }
+
+    #[cfg(feature = "metrics")]
+    impl Recorded {
+        fn gauge(&self, name: &str) -> Option<f64> {
+            let gauges = self.gauges.lock().unwrap();
+            let value = gauges.get(name)?.load(Ordering::Relaxed);
+            Some(f64::from_bits(value))
+        }
+
+        // Counters are keyed like name{label=value,...}
+        fn counter(&self, key: &str) -> u64 {
+            let counters = self.counters.lock().unwrap();
+            counters
+                .get(key)
+                .map_or(0, |counter| counter.load(Ordering::Relaxed))
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    impl metrics::Recorder for Recorded {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key) -> Counter {
+            let labels = key
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect::<Vec<_>>();
+            let key = format!("{}{{{}}}", key.name(), labels.join(","));
+            let mut counters = self.counters.lock().unwrap();
+            Counter::from_arc(counters.entry(key).or_default().clone())
+        }
+
+        fn register_gauge(&self, key: &Key) -> Gauge {
+            let mut gauges = self.gauges.lock().unwrap();
+            Gauge::from_arc(gauges.entry(key.name().to_owned()).or_default().clone())
+        }
+
+        fn register_histogram(&self, _: &Key) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    // This is the only test that installs a recorder, since there can only be one per process.
+    // Other tests may record metrics while it runs, so counters are only checked for increases.
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn recorded_metrics() {
+        let recorded: &'static Recorded = Box::leak(Box::default());
+        metrics::set_recorder(recorded).unwrap();
+        let db = fixtures::timezone_db();
+        db.record_metrics();
+        assert_eq!(recorded.gauge("timezoned_timezones_loaded"), Some(18.0));
+        // 36 countries in zone1970.tab, and UK
+        assert_eq!(recorded.gauge("timezoned_countries_loaded"), Some(37.0));
+        assert_eq!(
+            recorded.gauge("timezoned_tzdb_load_duration_seconds"),
+            Some(db.load_duration.as_secs_f64())
+        );
+
+        let config = fixtures::config();
+        let cache = RefCell::new(LruCache::new(NonZeroUsize::new(8).unwrap()));
+        let ctx = Context {
+            response_cache: Some(&cache),
+            ..fixtures::context(&config, &db)
+        };
+        let hits = "timezoned_cache_requests{cache=response,result=hit}";
+        let misses = "timezoned_cache_requests{cache=response,result=miss}";
+        let (hits_before, misses_before) = (recorded.counter(hits), recorded.counter(misses));
+        let request = parse_request(b"Asia/Kolkata", &config);
+        handle_request(b"Asia/Kolkata", &request, Instant::now(), &ctx, None);
+        handle_request(b"Asia/Kolkata", &request, Instant::now(), &ctx, None);
+        assert!(recorded.counter(hits) > hits_before);
+        assert!(recorded.counter(misses) > misses_before);
+
+        let mut clients = Clients::default();
+        let unique = "timezoned_unique_clients{}";
+        let unique_before = recorded.counter(unique);
+        let (olson, geoip) = (Request::parse("Europe/Berlin"), Request::parse("GEOIP"));
+        let now = Instant::now();
+        for ip in ["192.0.2.1", "192.0.2.2"] {
+            clients.admit(ip.parse().unwrap(), &olson, now, &config);
+        }
+        assert!(recorded.counter(unique) >= unique_before + 2);
+        clients.admit("192.0.2.1".parse().unwrap(), &geoip, now, &config);
+        clients.prune(now, &config);
+        assert!(recorded.gauge("timezoned_active_clients").is_some());
+    }
+
+    #[test]
+    fn fuzz_corpus() {
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/parse_and_respond");
+        for entry in fs::read_dir(corpus).unwrap() {
+            let request = fs::read(entry.unwrap().path()).unwrap();
+            let response = parse_and_respond(&request).unwrap();
+            assert!(response.len() <= request::MAX_RESPONSE_SIZE);
+        }
+        assert_eq!(parse_and_respond(&[b'a'; MAX_REQUEST_SIZE + 1]), None);
+    }
+
+    #[test]
+    fn dedup() {
+        let mut config = fixtures::config();
+        config.rate_limit = Duration::ZERO;
+        config.dedup_window = Duration::from_secs(2);
+        let mut clients = Clients::default();
+        let ip = "192.0.2.1".parse().unwrap();
+        let (berlin, london) = (
+            Request::parse("Europe/Berlin"),
+            Request::parse("Europe/London"),
+        );
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs_f64(secs);
+
+        assert_eq!(
+            clients.admit(ip, &berlin, at(0.0), &config),
+            Admission::LookUp
+        );
+        clients.record(ip, berlin.clone(), b"OK 1", at(0.0), &config);
+        assert_eq!(
+            clients.admit(ip, &berlin, at(1.0), &config),
+            Admission::Duplicate(b"OK 1".to_vec())
+        );
+        // Repeating the request doesn't extend the window
+        assert_eq!(
+            clients.admit(ip, &berlin, at(1.9), &config),
+            Admission::Duplicate(b"OK 1".to_vec())
+        );
+        assert_eq!(
+            clients.admit(ip, &berlin, at(2.0), &config),
+            Admission::LookUp
+        );
+        clients.record(ip, berlin.clone(), b"OK 2", at(2.0), &config);
+        assert_eq!(
+            clients.admit(ip, &london, at(2.5), &config),
+            Admission::LookUp
+        );
+        clients.record(ip, london, b"OK 3", at(2.5), &config);
+        assert_eq!(
+            clients.admit(ip, &berlin, at(3.0), &config),
+            Admission::LookUp
+        );
+
+        config.dedup_drop = true;
+        clients.record(ip, berlin.clone(), b"OK 4", at(3.0), &config);
+        assert_eq!(
+            clients.admit(ip, &berlin, at(4.0), &config),
+            Admission::DropDuplicate
+        );
+        assert_eq!(
+            clients.admit(ip, &berlin, at(5.0), &config),
+            Admission::LookUp
+        );
+    }
+
+    #[test]
+    fn reload_geoip_from_new_file() {
+        let (mut config, _dir) = fixtures::config_with_data_dir();
+        config.admin_key = Secret("key".into());
+        let path = config.data_path(MMDB_CITY_FILE);
+        fs::copy(fixtures::testdata("GeoLite2-Country.mmdb"), &path).unwrap();
+        let mut geoip = Some(GeoIpDb::load(&config).unwrap());
+        let cache = RefCell::new(GeoIpCache::new(NonZeroUsize::new(10).unwrap()));
+        let addr = "192.0.2.1".parse().unwrap();
+        cache.borrow_mut().insert(addr, "Asia/Kolkata");
+        let country = |geoip: &Option<GeoIpDb>| {
+            let location = geoip.as_ref().unwrap().lookup(addr).unwrap();
+            location.country.map(str::to_owned)
+        };
+        assert_eq!(country(&geoip).as_deref(), Some("IN"));
+
+        // The new file replaces the database, and cached results from the old one are cleared
+        let new_path = config.data_path(format!("{}.new", MMDB_CITY_FILE));
+        fs::copy(fixtures::testdata("GeoLite2-City.mmdb"), &new_path).unwrap();
+        let request = Request::parse("RELOADGEOIP key");
+        let reloaded = reload_geoip_for(&request, &config, &mut geoip, Some(&cache), None);
+        assert_eq!(reloaded, Some(true));
+        assert_eq!(country(&geoip).as_deref(), Some("CH"));
+        assert!(!new_path.exists());
+        assert_eq!(cache.borrow().len(), 0);
+
+        // Without the key, or a database to load, nothing is reloaded
+        let request = Request::parse("RELOADGEOIP wrong");
+        assert_eq!(
+            reload_geoip_for(&request, &config, &mut geoip, None, None),
+            None
+        );
+        fs::remove_file(&path).unwrap();
+        let request = Request::parse("RELOADGEOIP key");
+        let reloaded = reload_geoip_for(&request, &config, &mut geoip, None, None);
+        assert_eq!(reloaded, Some(false));
+        assert_eq!(country(&geoip).as_deref(), Some("CH"));
+    }
+
+    #[test]
+    fn max_request_size() {
+        let config = fixtures::config();
+        // Padded to exactly the maximum size, which is still answered
+        let mut request = b"Europe/Berlin".to_vec();
+        request.resize(MAX_REQUEST_SIZE, b' ');
+        assert_eq!(
+            parse_and_respond(&request).unwrap(),
+            b"OK Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        request.push(b' ');
+        assert_eq!(parse_and_respond(&request), None);
+
+        // The receive buffer has room to tell a maximum size request from a larger one that was
+        // truncated to fit
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buf = [0; RECV_BUFFER_SIZE];
+        for size in [
+            MAX_REQUEST_SIZE,
+            MAX_REQUEST_SIZE + 1,
+            MAX_REQUEST_SIZE + 100,
+        ] {
+            socket
+                .send_to(&vec![b'a'; size], socket.local_addr().unwrap())
+                .unwrap();
+            let len = socket.recv(&mut buf).unwrap();
+            assert_eq!(drop_request(&buf[..len], &config), size > MAX_REQUEST_SIZE);
+        }
+    }
+
+    #[test]
+    fn ip_nets() {
+        let net = |net: &str| net.parse::<IpNet>();
+        let ip = |addr: &str| addr.parse::<IpAddr>().unwrap();
+        assert!(net("192.0.2.0/24").unwrap().contains(ip("192.0.2.255")));
+        assert!(!net("192.0.2.0/24").unwrap().contains(ip("192.0.3.0")));
+        assert!(net("192.0.2.0/24")
+            .unwrap()
+            .contains(ip("::ffff:192.0.2.1")));
+        assert!(net("192.0.2.10").unwrap().contains(ip("192.0.2.10")));
+        assert!(!net("192.0.2.10").unwrap().contains(ip("192.0.2.11")));
+        assert!(net("0.0.0.0/0").unwrap().contains(ip("198.51.100.1")));
+        assert!(!net("0.0.0.0/0").unwrap().contains(ip("2001:db8::1")));
+        assert!(net("2001:db8::/32")
+            .unwrap()
+            .contains(ip("2001:db8:ffff::1")));
+        assert!(!net("2001:db8::/32").unwrap().contains(ip("2001:db9::1")));
+        assert!(net("192.0.2.0/33").is_err());
+        assert!(net("2001:db8::/129").is_err());
+        assert!(net("192.0.2.0/").is_err());
+        assert!(net("example.com").is_err());
+    }
+
+    #[test]
+    fn rate_limit_exempt() {
+        let mut config = fixtures::config();
+        config.rate_limit = Duration::from_secs(3);
+        config.rate_limit_exempt = vec!["192.0.2.0/24".parse().unwrap()];
+        let mut clients = Clients::default();
+        let request = Request::parse("Europe/Berlin");
+        let now = Instant::now();
+        let exempt = "192.0.2.1".parse().unwrap();
+        assert_eq!(
+            clients.admit(exempt, &request, now, &config),
+            Admission::LookUp
+        );
+        assert_eq!(
+            clients.admit(exempt, &request, now, &config),
+            Admission::LookUp
+        );
+        let not_exempt = "198.51.100.1".parse().unwrap();
+        assert_eq!(
+            clients.admit(not_exempt, &request, now, &config),
+            Admission::LookUp
+        );
+        assert_eq!(
+            clients.admit(not_exempt, &request, now, &config),
+            Admission::RateLimited { notify: false }
+        );
+    }
+
+    #[test]
+    fn reply_port() {
+        let addr = "192.0.2.1:2342".parse::<SocketAddr>().unwrap();
+        let v6_addr = "[2001:db8::1]:2342".parse::<SocketAddr>().unwrap();
+        let config = fixtures::config();
+        assert_eq!(config.reply_addr(addr), addr);
+
+        // Responses go to the same address on the configured port
+        let file = HashMap::from([("TZD_REPLY_PORT".to_owned(), "4242".to_owned())]);
+        let config = Config::from_file(&file).unwrap();
+        assert_eq!(config.reply_addr(addr), "192.0.2.1:4242".parse().unwrap());
+        assert_eq!(
+            config.reply_addr(v6_addr),
+            "[2001:db8::1]:4242".parse().unwrap()
+        );
+
+        for port in ["70000", "-1", "port"] {
+            let file = HashMap::from([("TZD_REPLY_PORT".to_owned(), port.to_owned())]);
+            assert!(Config::from_file(&file).is_err());
+        }
+    }
+
+    #[test]
+    fn load_errors() {
+        let (mut config, _dir) = fixtures::config_with_data_dir();
+        let path = config.data_path(MMDB_CITY_FILE);
+        assert!(matches!(
+            GeoIpDb::load(&config),
+            Err(LoadError::FileNotFound(missing)) if missing == path
+        ));
+
+        let posixinfo = "Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3\nEurope/Berlin CET-1\n";
+        let err = TimezoneDb::parse(posixinfo.as_bytes(), io::empty(), &[]).unwrap_err();
+        assert!(matches!(err, LoadError::Parse { line: 2, .. }), "{}", err);
+        assert_eq!(
+            err.to_string(),
+            "posixinfo line 2: Timezone 'Europe/Berlin' already added to database"
+        );
+
+        fs::write(&path, "not a GeoIP database").unwrap();
+        assert!(matches!(GeoIpDb::load(&config), Err(LoadError::Mmdb(_))));
+
+        // A data directory that's a file can't be read, which isn't the same as a missing database
+        config.data_dir = path.clone();
+        let err = GeoIpDb::load(&config).err().unwrap();
+        assert!(matches!(&err, LoadError::Io(..)), "{}", err);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "could not read {}: Not a directory (os error 20)",
+                path.join(MMDB_CITY_FILE).display()
+            )
+        );
+    }
+
+    #[test]
+    fn default_timezone() {
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        config.default_timezone = Some("EUROPE/BERLIN".into());
+        let berlin = "OK Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3";
+        let respond = |config: &Config, request: &str| {
+            let ctx = fixtures::context(config, &db);
+            let parsed = parse_request(request.as_bytes(), config);
+            let response = handle_request(request.as_bytes(), &parsed, Instant::now(), &ctx, None);
+            String::from_utf8(response).unwrap()
+        };
+        assert_eq!(respond(&config, "Europe/Atlantis"), berlin);
+        assert_eq!(respond(&config, "FROBNICATE"), berlin);
+        assert_eq!(
+            respond(&config, "Europe/London"),
+            "OK Europe/London GMT0BST,M3.5.0/1,M10.5.0"
+        );
+        // Only timezone name requests fall back to it
+        assert_eq!(respond(&config, "XX"), "ERROR Country Not Found");
+        assert_eq!(
+            respond(&config, "HASDST Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+        // Empty requests only get it if that's enabled too
+        assert_eq!(respond(&config, ""), "ERROR Timezone Not Found");
+        config.default_timezone_empty = true;
+        assert_eq!(respond(&config, " \n"), berlin);
+        config.strict_commands = true;
+        assert_eq!(respond(&config, "FROBNICATE"), "ERROR Unknown Command");
+        assert_eq!(respond(&config, ""), berlin);
+
+        assert_eq!(check_timezones(&config, &db), Ok(()));
+        config.default_timezone = Some("EUROPE/ATLANTIS".into());
+        assert_eq!(
+            check_timezones(&config, &db),
+            Err("TZD_DEFAULT_TIMEZONE timezone 'EUROPE/ATLANTIS' was not found".into())
+        );
+    }
+}
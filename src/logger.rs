@@ -0,0 +1,268 @@
+use env_logger::fmt::Formatter;
+use env_logger::{Builder, Target};
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+use serde_json::{Map, Value as JsonValue};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+// Log target of messages about individual requests, so they can be filtered with TZD_LOG_REQUESTS
+pub const REQUEST_LOG_TARGET: &str = "timezoned_rs::requests";
+// Log target of the access log, which is logged at debug level so it's off by default
+pub const ACCESS_LOG_TARGET: &str = "timezoned_rs::requests::access";
+
+// Initializes the logger, filtered by TZD_LOG and TZD_LOG_REQUESTS, and formatted according to
+// TZD_LOG_FORMAT. Logs are written to TZD_LOG_FILE if it's set, or stderr otherwise. If the log
+// file can't be used, the logger writes to stderr and an error is returned.
+pub fn init() -> Result<(), String> {
+    let filters = filters(
+        std::env::var("TZD_LOG").ok(),
+        std::env::var("TZD_LOG_REQUESTS").ok(),
+    );
+    let format = std::env::var("TZD_LOG_FORMAT").unwrap_or_default();
+    let mut builder = match format.to_lowercase().as_str() {
+        "json" => {
+            let mut builder = Builder::new();
+            builder.format(format_json);
+            builder
+        }
+        _ => pretty_env_logger::formatted_builder(),
+    };
+    builder.parse_filters(&filters);
+    let file = match std::env::var("TZD_LOG_FILE") {
+        Ok(path) if !path.is_empty() => Some(open_log_file(path.into())),
+        _ => None,
+    };
+    let result = match file {
+        Some(Ok(file)) => {
+            builder.target(Target::Pipe(Box::new(file)));
+            Ok(())
+        }
+        Some(Err(err)) => Err(err),
+        None => Ok(()),
+    };
+    builder.init();
+    if !["", "json", "pretty"].contains(&format.to_lowercase().as_str()) {
+        log::warn!("Unknown TZD_LOG_FORMAT '{}', using pretty", format);
+    }
+    result
+}
+
+// Log file that is rotated once it reaches TZD_LOG_FILE_MAX_MB, keeping TZD_LOG_FILE_KEEP old files
+// named like timezoned.log.1, where .1 is the newest
+struct RotatingFile {
+    path: PathBuf,
+    // 0 if the file is never rotated
+    max_size: u64,
+    keep: u32,
+    file: File,
+    size: u64,
+}
+
+// Opens TZD_LOG_FILE, rotated according to TZD_LOG_FILE_MAX_MB and TZD_LOG_FILE_KEEP
+fn open_log_file(path: PathBuf) -> Result<RotatingFile, String> {
+    let max_mb = getenv_u64("TZD_LOG_FILE_MAX_MB", 10)?;
+    let keep = getenv_u64("TZD_LOG_FILE_KEEP", 5)?;
+    let keep = keep.try_into().unwrap_or(u32::MAX);
+    RotatingFile::open(path.clone(), max_mb.saturating_mul(1024 * 1024), keep)
+        .map_err(|err| format!("Could not open log file {}: {}", path.display(), err))
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: u32) -> io::Result<Self> {
+        let file = Self::open_file(&path)?;
+        let size = file.metadata().map_or(0, |metadata| metadata.len());
+        Ok(RotatingFile {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn open_file(path: &PathBuf) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotated_path(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // The oldest file is overwritten
+            for n in (1..self.keep).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = Self::open_file(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    // Records are written one at a time, so a record is never split between two files
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            // There's nowhere to log the error, so it's written to stderr and logging continues in
+            // the current file
+            if let Err(err) = self.rotate() {
+                eprintln!("Could not rotate log file {}: {}", self.path.display(), err);
+            }
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn getenv_u64(key: &str, default: u64) -> Result<u64, String> {
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("{} is configured with invalid value '{}'", key, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+// Combines TZD_LOG and TZD_LOG_REQUESTS into env_logger filter directives
+fn filters(log: Option<String>, log_requests: Option<String>) -> String {
+    let mut filters = log.unwrap_or_else(|| "info".into());
+    // The most specific directive matching a target is used, so this overrides any level TZD_LOG
+    // sets for the whole crate
+    if let Some(level) = log_requests {
+        filters.push_str(&format!(",{}={}", REQUEST_LOG_TARGET, level));
+    }
+    filters
+}
+
+// Formats a record as a single line JSON object. Key-value pairs attached to the record are added
+// as keys of their own.
+fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut object = Map::new();
+    object.insert(
+        "timestamp".into(),
+        buf.timestamp_millis().to_string().into(),
+    );
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("message".into(), record.args().to_string().into());
+    // Fields can't fail to be collected, so errors can be ignored
+    let _ = record.key_values().visit(&mut JsonFields(&mut object));
+    writeln!(buf, "{}", JsonValue::Object(object))
+}
+
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        // Numbers and bools are kept as JSON types, and everything else is a string
+        let value = if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_f64() {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env_logger::filter;
+    use log::{Level, Metadata};
+
+    #[test]
+    fn request_filters() {
+        let enabled = |filters: &str, target: &str, level: Level| {
+            let filter = filter::Builder::new().parse(filters).build();
+            filter.enabled(&Metadata::builder().target(target).level(level).build())
+        };
+
+        let directives = filters(None, None);
+        assert!(enabled(&directives, "timezoned_rs", Level::Info));
+        assert!(enabled(&directives, REQUEST_LOG_TARGET, Level::Info));
+        assert!(!enabled(&directives, ACCESS_LOG_TARGET, Level::Debug));
+
+        let directives = filters(Some("debug".into()), Some("off".into()));
+        assert!(enabled(&directives, "timezoned_rs", Level::Debug));
+        assert!(!enabled(&directives, REQUEST_LOG_TARGET, Level::Error));
+        assert!(!enabled(&directives, ACCESS_LOG_TARGET, Level::Debug));
+
+        let directives = filters(Some("warn".into()), Some("debug".into()));
+        assert!(!enabled(&directives, "timezoned_rs", Level::Info));
+        assert!(enabled(&directives, ACCESS_LOG_TARGET, Level::Debug));
+    }
+
+    #[test]
+    fn rotating_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("timezoned.log");
+        let read = |n: u32| {
+            let mut path = path.clone().into_os_string();
+            if n > 0 {
+                path.push(format!(".{}", n));
+            }
+            fs::read_to_string(path).ok()
+        };
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        // Records aren't split, even if they're larger than the limit on their own
+        file.write_all(b"first\n").unwrap();
+        file.write_all(b"second\n").unwrap();
+        file.write_all(b"third record\n").unwrap();
+        file.write_all(b"fourth\n").unwrap();
+        assert_eq!(read(0).as_deref(), Some("fourth\n"));
+        assert_eq!(read(1).as_deref(), Some("third record\n"));
+        assert_eq!(read(2).as_deref(), Some("second\n"));
+        // Only keep files are kept
+        assert_eq!(read(3), None);
+
+        // Sizes of existing files are counted, and nothing is kept with keep=0
+        let mut file = RotatingFile::open(path.clone(), 10, 0).unwrap();
+        file.write_all(b"fifth\n").unwrap();
+        assert_eq!(read(0).as_deref(), Some("fifth\n"));
+        assert_eq!(read(1).as_deref(), Some("third record\n"));
+    }
+
+    #[test]
+    fn json_fields() {
+        let fields = [
+            ("ip", Value::from("192.0.2.1")),
+            ("duration_us", Value::from(42u64)),
+            ("offset", Value::from(-3600i64)),
+            ("stale", Value::from(true)),
+        ];
+        let record = Record::builder().key_values(&fields).build();
+        let mut object = Map::new();
+        record
+            .key_values()
+            .visit(&mut JsonFields(&mut object))
+            .unwrap();
+        assert_eq!(
+            JsonValue::Object(object).to_string(),
+            r#"{"duration_us":42,"ip":"192.0.2.1","offset":-3600,"stale":true}"#
+        );
+    }
+}
@@ -0,0 +1,58 @@
+// Simple macro to run a shell script using async_process, which is killed if it doesn't finish
+// within a timeout. The script's output is logged, at error level if it fails.
+macro_rules! sh {
+    // Environment variables for the script follow the arguments, like `sh!(t, path, arg; "K" => v)`
+    ($timeout:expr, $path:expr, $($arg:expr),*; $($key:expr => $value:expr),*) => {
+        async {
+            use std::os::unix::process::CommandExt;
+            // The script runs in its own process group, so that commands it started can be
+            // killed along with it
+            let mut command = std::process::Command::new("sh");
+            command.arg($path)$(.arg($arg))*$(.env($key, $value))*.process_group(0);
+            let child = async_process::Command::from(command)
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .spawn()?;
+            let pid = child.id();
+            let result = tokio::time::timeout($timeout, child.output()).await;
+            match result {
+                Ok(output) => {
+                    let output = output?;
+                    let log = $crate::script_output(&output.stdout, &output.stderr);
+                    if !output.status.success() {
+                        if !log.is_empty() {
+                            log::error!("{} output:\n{}", $path, log);
+                        }
+                        Err(format!("{}", output.status).into())
+                    } else {
+                        if !log.is_empty() {
+                            log::debug!("{} output:\n{}", $path, log);
+                        }
+                        Ok(())
+                    }
+                }
+                Err(_) => {
+                    // SAFETY: kill has no memory safety requirements
+                    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+                    Err(format!("{} timed out after {:?}", $path, $timeout).into())
+                }
+            }
+        }
+    };
+    ($timeout:expr, $path:expr, $($arg:expr),*) => {
+        sh!($timeout, $path, $($arg),*;)
+    };
+}
+
+// Macro to increment a prometheus counter under timezoned_requests
+macro_rules! log_request {
+    ($type:expr$(, $label:expr => $value:expr)*) => {
+        #[cfg(feature = "metrics")]
+        metrics::increment_counter!("timezoned_requests", "type" => $type$(, $label => $value)*);
+        // Without metrics, label values are still considered used, but never evaluated
+        #[cfg(not(feature = "metrics"))]
+        let _ = || {
+            $(let _ = &$value;)*
+        };
+    };
+}
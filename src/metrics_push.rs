@@ -0,0 +1,107 @@
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use log::{debug, warn};
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::error::Error;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+// Pushes metrics to a Prometheus push gateway, for deployments that can't be scraped
+#[derive(Clone)]
+pub struct MetricsPusher {
+    client: Client<HttpConnector>,
+    handle: PrometheusHandle,
+    url: Uri,
+}
+
+impl MetricsPusher {
+    pub fn new(handle: PrometheusHandle, url: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(MetricsPusher {
+            client: Client::new(),
+            handle,
+            url: url.parse()?,
+        })
+    }
+
+    // Starts a task that pushes metrics every period
+    pub fn spawn(&self, period: Duration) {
+        let pusher = self.clone();
+        tokio::spawn(async move {
+            let mut interval = interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // The first tick completes immediately, and there's nothing worth pushing yet
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                pusher.push().await;
+            }
+        });
+    }
+
+    // Pushes the current value of every metric, replacing the ones previously pushed
+    pub async fn push(&self) {
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(self.url.clone())
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(Body::from(self.handle.render()));
+        let result = match request {
+            Ok(request) => self.client.request(request).await,
+            Err(err) => {
+                warn!("Could not build metrics push request: {}", err);
+                return;
+            }
+        };
+        match result {
+            Ok(response) if response.status().is_success() => {
+                debug!("Pushed metrics to {}", self.url)
+            }
+            Ok(response) => warn!(
+                "Metrics push to {} failed with status {}",
+                self.url,
+                response.status()
+            ),
+            Err(err) => warn!("Could not push metrics to {}: {}", self.url, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn push() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/metrics/job/timezoned",
+            listener.local_addr().unwrap()
+        );
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let pusher = MetricsPusher::new(handle, &url).unwrap();
+
+        let gateway = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let len = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        });
+        pusher.push().await;
+
+        let request = gateway.await.unwrap();
+        assert!(request.starts_with("PUT /metrics/job/timezoned HTTP/1.1\r\n"));
+        assert!(request.contains("content-type: text/plain; version=0.0.4\r\n"));
+    }
+
+    #[test]
+    fn invalid_url() {
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        assert!(MetricsPusher::new(handle, "not a url").is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{debug, warn};
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::convert::Infallible;
+use std::io;
+use std::path::Path;
+use tokio::net::UnixListener;
+
+// Serves metrics over HTTP on a Unix socket, for scraping by a local agent. The Prometheus exporter
+// can only listen on TCP, so this is a minimal replacement for its listener.
+pub fn spawn(path: &Path, handle: PrometheusHandle) -> io::Result<()> {
+    let listener = UnixListener::bind(path)?;
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("Could not accept metrics connection: {}", err);
+                    continue;
+                }
+            };
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                let service = service_fn(|request| {
+                    let response = respond(&handle, &request);
+                    async { Ok::<_, Infallible>(response) }
+                });
+                if let Err(err) = Http::new()
+                    .http1_only(true)
+                    .serve_connection(stream, service)
+                    .await
+                {
+                    debug!("Metrics connection failed: {}", err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn respond(handle: &PrometheusHandle, request: &Request<Body>) -> Response<Body> {
+    let mut response = Response::default();
+    if request.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+    } else if request.method() != Method::GET {
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    } else {
+        response
+            .headers_mut()
+            .insert("content-type", "text/plain; version=0.0.4".parse().unwrap());
+        *response.body_mut() = Body::from(handle.render());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn responses() {
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let request = |method: Method, path: &str| {
+            let request = Request::builder().method(method).uri(path);
+            respond(&handle, &request.body(Body::empty()).unwrap())
+        };
+        let response = request(Method::GET, "/metrics");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; version=0.0.4"
+        );
+        assert_eq!(
+            request(Method::POST, "/metrics").status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(request(Method::GET, "/").status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn serve() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.sock");
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        spawn(&path, handle).unwrap();
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+}
@@ -0,0 +1,540 @@
+// Parser and evaluator for POSIX TZ strings, as described in
+// https://pubs.opengroup.org/onlinepubs/9699919799/basedefs/V1_chap08.html
+// with the extensions from RFC 8536 section 3.3.1 used by tzdata.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: i64 = 86400;
+const SECONDS_PER_HOUR: i32 = 3600;
+// Transition time used when a rule doesn't specify one
+const DEFAULT_TRANSITION_TIME: i32 = 2 * SECONDS_PER_HOUR;
+// Transitions repeat every 400 years, so there's no need to look further ahead than that
+const MAX_TRANSITION_YEARS: i64 = 400;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Posix {
+    pub std_name: String,
+    // Offset from UTC in seconds, positive east of Greenwich. Note that this is the opposite sign
+    // of the offset as it's written in a POSIX string.
+    pub std_offset: i32,
+    pub dst: Option<Dst>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dst {
+    pub name: String,
+    // Offset from UTC in seconds, positive east of Greenwich
+    pub offset: i32,
+    pub start: Rule,
+    pub end: Rule,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub date: RuleDate,
+    // Local time of the transition in seconds, in the offset that is in effect before it
+    pub time: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transition {
+    pub time: i64,
+    pub offset_before: i32,
+    pub offset_after: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleDate {
+    // Jn: day of the year from 1 to 365, where February 29 is never counted
+    Julian(u16),
+    // n: zero-based day of the year from 0 to 365, where February 29 is counted
+    Zero(u16),
+    // Mm.w.d: day d (0 = Sunday) of week w (5 = last) of month m
+    Month { month: u8, week: u8, weekday: u8 },
+}
+
+impl Posix {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parser = Parser { s, pos: 0 };
+        let std_name = parser.name()?;
+        let std_offset = -parser.offset()?;
+        let dst = if parser.is_done() {
+            None
+        } else {
+            let name = parser.name()?;
+            let offset = if parser.peek() == Some(b',') {
+                std_offset + SECONDS_PER_HOUR
+            } else {
+                -parser.offset()?
+            };
+            parser.expect(b',')?;
+            let start = parser.rule()?;
+            parser.expect(b',')?;
+            let end = parser.rule()?;
+            Some(Dst {
+                name,
+                offset,
+                start,
+                end,
+            })
+        };
+        if !parser.is_done() {
+            return Err(format!("unexpected '{}'", &s[parser.pos..]));
+        }
+
+        Ok(Posix {
+            std_name,
+            std_offset,
+            dst,
+        })
+    }
+
+    // Offset from UTC in seconds at the given unix time
+    pub fn offset_at(&self, time: i64) -> i32 {
+        match &self.dst {
+            Some(dst) if self.is_dst_at(time) => dst.offset,
+            _ => self.std_offset,
+        }
+    }
+
+    // Abbreviation in effect at the given unix time, like CET or CEST. Numeric abbreviations like
+    // +03 are returned without the angle brackets that quote them in POSIX strings.
+    pub fn name_at(&self, time: i64) -> &str {
+        match &self.dst {
+            Some(dst) if self.is_dst_at(time) => &dst.name,
+            _ => &self.std_name,
+        }
+    }
+
+    pub fn is_dst_at(&self, time: i64) -> bool {
+        if self.dst.is_none() {
+            return false;
+        }
+        let (start, end) = self.transitions_in_year(year_of(time + self.std_offset as i64));
+        if start < end {
+            // DST starts and ends in the same year, like in the northern hemisphere
+            start <= time && time < end
+        } else {
+            // DST spans the end of the year, like in the southern hemisphere
+            time < end || start <= time
+        }
+    }
+
+    // Every transition between standard time and DST after a unix time, in order
+    pub fn transitions_after(&self, time: i64) -> impl Iterator<Item = Transition> + '_ {
+        // Start from the previous year, since the local year may differ from the UTC year
+        let first_year = year_of(time) - 1;
+        let years = match self.dst {
+            Some(_) => first_year..first_year + MAX_TRANSITION_YEARS,
+            None => 0..0,
+        };
+        years
+            .flat_map(|year| {
+                let (start, end) = self.transitions_in_year(year);
+                if start < end {
+                    [start, end]
+                } else {
+                    [end, start]
+                }
+            })
+            .filter(move |t| *t > time)
+            .filter_map(|t| {
+                // Rules like those for permanent DST have transitions that don't change the offset
+                let transition = Transition {
+                    time: t,
+                    offset_before: self.offset_at(t - 1),
+                    offset_after: self.offset_at(t),
+                };
+                (transition.offset_before != transition.offset_after).then_some(transition)
+            })
+    }
+
+    // Unix times of the start and end of DST in a year, if this timezone observes DST
+    fn transitions_in_year(&self, year: i64) -> (i64, i64) {
+        let Some(dst) = &self.dst else {
+            return (i64::MAX, i64::MAX);
+        };
+        (
+            dst.start.local_time_in_year(year) - self.std_offset as i64,
+            dst.end.local_time_in_year(year) - dst.offset as i64,
+        )
+    }
+}
+
+impl fmt::Display for Posix {
+    // Writes every field out, including the ones that have defaults, like the DST offset and the
+    // time of transitions
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_name(f, &self.std_name)?;
+        write_time(f, -self.std_offset)?;
+        if let Some(dst) = &self.dst {
+            write_name(f, &dst.name)?;
+            write_time(f, -dst.offset)?;
+            write!(f, ",{},{}", dst.start, dst.end)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.date {
+            RuleDate::Julian(day) => write!(f, "J{}", day)?,
+            RuleDate::Zero(day) => write!(f, "{}", day)?,
+            RuleDate::Month {
+                month,
+                week,
+                weekday,
+            } => write!(f, "M{}.{}.{}", month, week, weekday)?,
+        }
+        f.write_str("/")?;
+        write_time(f, self.time)
+    }
+}
+
+// Names that aren't only letters, like +0330, must be quoted
+fn write_name(f: &mut fmt::Formatter, name: &str) -> fmt::Result {
+    if name.bytes().all(|c| c.is_ascii_alphabetic()) {
+        f.write_str(name)
+    } else {
+        write!(f, "<{}>", name)
+    }
+}
+
+// Writes seconds as [-]h[:mm[:ss]]
+fn write_time(f: &mut fmt::Formatter, seconds: i32) -> fmt::Result {
+    if seconds < 0 {
+        f.write_str("-")?;
+    }
+    let seconds = seconds.unsigned_abs();
+    write!(f, "{}", seconds / SECONDS_PER_HOUR as u32)?;
+    match (seconds / 60 % 60, seconds % 60) {
+        (0, 0) => Ok(()),
+        (minutes, 0) => write!(f, ":{:02}", minutes),
+        (minutes, seconds) => write!(f, ":{:02}:{:02}", minutes, seconds),
+    }
+}
+
+// TZ string parsers used by clients, whose quirks POSIX strings can be reformatted for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dialect {
+    // ezTime, which timezone requests are already answered for, so strings are returned as they are
+    EzTime,
+    // glibc, with every field written out instead of relying on defaults, which also suits parsers
+    // that don't implement them
+    Glibc,
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "eztime" => Ok(Dialect::EzTime),
+            "glibc" => Ok(Dialect::Glibc),
+            _ => Err(format!("unknown dialect '{}'", s)),
+        }
+    }
+}
+
+// Rewrites a POSIX string for a dialect. Strings that can't be parsed are returned as they are.
+pub fn reformat(posix: &str, dialect: Dialect) -> String {
+    match dialect {
+        Dialect::EzTime => posix.to_owned(),
+        Dialect::Glibc => match Posix::parse(posix) {
+            Ok(parsed) => parsed.to_string(),
+            Err(_) => posix.to_owned(),
+        },
+    }
+}
+
+impl Rule {
+    // Local time of the transition as seconds since the epoch
+    fn local_time_in_year(&self, year: i64) -> i64 {
+        let leap = is_leap_year(year);
+        let day_of_year = match self.date {
+            RuleDate::Julian(day) => day as i64 - 1 + (leap && day >= 60) as i64,
+            RuleDate::Zero(day) => day as i64,
+            RuleDate::Month {
+                month,
+                week,
+                weekday,
+            } => {
+                let first = days_from_civil(year, month as u32, 1);
+                let mut day =
+                    (weekday as i64 - weekday_of(first)).rem_euclid(7) + (week as i64 - 1) * 7;
+                // Week 5 means the last occurrence of the weekday in the month
+                while day >= days_in_month(year, month as u32) {
+                    day -= 7;
+                }
+                first + day - days_from_civil(year, 1, 1)
+            }
+        };
+        (days_from_civil(year, 1, 1) + day_of_year) * SECONDS_PER_DAY + self.time as i64
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn is_done(&self) -> bool {
+        self.pos >= self.s.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.as_bytes().get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}' at position {}", c as char, self.pos))
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(u8) -> bool) -> &str {
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.pos += 1;
+        }
+        &self.s[start..self.pos]
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let name = if self.peek() == Some(b'<') {
+            // Quoted names can contain digits and signs, like <+0330>
+            self.pos += 1;
+            let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == b'+' || c == b'-');
+            let name = name.to_owned();
+            self.expect(b'>')?;
+            name
+        } else {
+            self.take_while(|c| c.is_ascii_alphabetic()).to_owned()
+        };
+        if name.len() < 3 {
+            return Err(format!("invalid name ending at position {}", self.pos));
+        }
+        Ok(name)
+    }
+
+    fn number(&mut self, max: u32) -> Result<u32, String> {
+        let start = self.pos;
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        match digits.parse::<u32>() {
+            Ok(n) if n <= max => Ok(n),
+            _ => Err(format!("invalid number at position {}", start)),
+        }
+    }
+
+    // Parses [+-]hh[:mm[:ss]] into seconds, with hours up to max_hours
+    fn time(&mut self, max_hours: u32) -> Result<i32, String> {
+        let sign = match self.peek() {
+            Some(b'-') => {
+                self.pos += 1;
+                -1
+            }
+            Some(b'+') => {
+                self.pos += 1;
+                1
+            }
+            _ => 1,
+        };
+        let mut seconds = self.number(max_hours)? * SECONDS_PER_HOUR as u32;
+        if self.peek() == Some(b':') {
+            self.pos += 1;
+            seconds += self.number(59)? * 60;
+            if self.peek() == Some(b':') {
+                self.pos += 1;
+                seconds += self.number(59)?;
+            }
+        }
+        Ok(sign * seconds as i32)
+    }
+
+    fn offset(&mut self) -> Result<i32, String> {
+        self.time(24)
+    }
+
+    fn rule(&mut self) -> Result<Rule, String> {
+        let date = match self.peek() {
+            Some(b'J') => {
+                self.pos += 1;
+                match self.number(365)? {
+                    0 => return Err(format!("invalid Julian day at position {}", self.pos)),
+                    day => RuleDate::Julian(day as u16),
+                }
+            }
+            Some(b'M') => {
+                self.pos += 1;
+                let month = self.number(12)?;
+                self.expect(b'.')?;
+                let week = self.number(5)?;
+                self.expect(b'.')?;
+                let weekday = self.number(6)?;
+                if month == 0 || week == 0 {
+                    return Err(format!("invalid date ending at position {}", self.pos));
+                }
+                RuleDate::Month {
+                    month: month as u8,
+                    week: week as u8,
+                    weekday: weekday as u8,
+                }
+            }
+            _ => RuleDate::Zero(self.number(365)? as u16),
+        };
+        let time = if self.peek() == Some(b'/') {
+            self.pos += 1;
+            self.time(167)?
+        } else {
+            DEFAULT_TRANSITION_TIME
+        };
+        Ok(Rule { date, time })
+    }
+}
+
+pub fn unix_time(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
+}
+
+// Parses an RFC 3339 timestamp like 2024-07-01T12:00:00Z or 2024-07-01T14:00:00+02:00 into a unix
+// time. Fractional seconds are accepted, but ignored.
+pub fn parse_rfc3339(s: &str) -> Option<i64> {
+    let b = s.as_bytes();
+    // Slicing is only safe on ASCII, and every valid time is ASCII
+    if !s.is_ascii()
+        || b.len() < 20
+        || b[4] != b'-'
+        || b[7] != b'-'
+        || !matches!(b[10], b'T' | b't' | b' ')
+        || b[13] != b':'
+        || b[16] != b':'
+    {
+        return None;
+    }
+    let (year, month, day) = (digits(&s[..4])?, digits(&s[5..7])?, digits(&s[8..10])?);
+    let (hour, minute, second) = (
+        digits(&s[11..13])?,
+        digits(&s[14..16])?,
+        digits(&s[17..19])?,
+    );
+    if !(1..=12).contains(&month) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let days = days_from_civil(year as i64, month, day);
+    // Out of range days, like February 30, end up in a different month
+    if civil_from_days(days) != (year as i64, month, day) {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        rest = &fraction[len..];
+    }
+    let offset = match rest.as_bytes() {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), _, _, b':', _, _] => {
+            let (hours, minutes) = (digits(&rest[1..3])?, digits(&rest[4..6])?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = (hours * 3600 + minutes * 60) as i64;
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return None,
+    };
+    Some(days * SECONDS_PER_DAY + (hour * 3600 + minute * 60 + second) as i64 - offset)
+}
+
+// Formats a unix time as a date and time without an offset, like 2024-07-01T14:30:00. Adding a UTC
+// offset to the time first gives the local time at that offset.
+pub fn format_datetime(time: i64) -> String {
+    let (year, month, day) = civil_from_days(time.div_euclid(SECONDS_PER_DAY));
+    let seconds = time.rem_euclid(SECONDS_PER_DAY);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn digits(s: &str) -> Option<u32> {
+    if s.bytes().all(|c| c.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: u32) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Day of the week of a day since the epoch, where 0 is Sunday
+fn weekday_of(days: i64) -> i64 {
+    // 1970-01-01 was a Thursday
+    (days + 4).rem_euclid(7)
+}
+
+fn year_of(time: i64) -> i64 {
+    civil_from_days(time.div_euclid(SECONDS_PER_DAY)).0
+}
+
+// Days since the epoch of a proleptic Gregorian date, from
+// https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// Proleptic Gregorian date of a day since the epoch, from
+// https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
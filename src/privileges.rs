@@ -0,0 +1,148 @@
+use log::info;
+use std::ffi::{CStr, CString};
+use std::io;
+
+// Switches the process to the user and group in TZD_USER and TZD_GROUP, which can be names or
+// numeric IDs. Without a group, the user's primary group is used. This must be done after every
+// privileged socket is bound, and before any requests are handled.
+pub fn drop_privileges(user: &str, group: &str) -> io::Result<()> {
+    let user = if user.is_empty() {
+        None
+    } else {
+        Some(lookup_user(user)?)
+    };
+    let gid = match (group, &user) {
+        ("", Some((_, _, gid))) => *gid,
+        ("", None) => return Ok(()),
+        (group, _) => lookup_group(group)?,
+    };
+
+    // Supplementary groups are dropped first, since that's no longer allowed once the user changes
+    let result = match &user {
+        Some((name, _, _)) => unsafe { libc::initgroups(name.as_ptr(), gid) },
+        None => unsafe { libc::setgroups(1, &gid) },
+    };
+    if result != 0 {
+        return Err(failed(
+            "set supplementary groups",
+            io::Error::last_os_error(),
+        ));
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(failed("set group", io::Error::last_os_error()));
+    }
+    if let Some((_, uid, _)) = user {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(failed("set user", io::Error::last_os_error()));
+        }
+        // Make sure root can't be regained, since continuing as root is what this is meant to
+        // prevent
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(failed(
+                "drop root",
+                io::Error::other("root could be regained"),
+            ));
+        }
+    }
+
+    info!(
+        "Running as user {} and group {}",
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    );
+    Ok(())
+}
+
+// Returns the name, user ID, and primary group ID of a user
+fn lookup_user(user: &str) -> io::Result<(CString, libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user).map_err(|err| failed("look up user", err.into()))?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let err = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        Err(_) => unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        },
+    };
+    if err != 0 {
+        return Err(failed("look up user", io::Error::from_raw_os_error(err)));
+    }
+    if result.is_null() {
+        return Err(failed("look up user", not_found("user", user)));
+    }
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) }.to_owned();
+    Ok((name, passwd.pw_uid, passwd.pw_gid))
+}
+
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|err| failed("look up group", err.into()))?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let err = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if err != 0 {
+        return Err(failed("look up group", io::Error::from_raw_os_error(err)));
+    }
+    if result.is_null() {
+        return Err(failed("look up group", not_found("group", group)));
+    }
+    Ok(entry.gr_gid)
+}
+
+fn not_found(kind: &str, name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} {} does not exist", kind, name),
+    )
+}
+
+fn failed(action: &str, err: io::Error) -> io::Error {
+    io::Error::new(
+        err.kind(),
+        format!("Could not drop privileges, failed to {}: {}", action, err),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every Unix system has root, so it can be looked up without knowing anything else about it
+    #[test]
+    fn lookup() {
+        let (name, uid, gid) = lookup_user("root").unwrap();
+        assert_eq!((name.to_str().unwrap(), uid, gid), ("root", 0, 0));
+        // Users can also be given by ID, and are switched to by their name
+        assert_eq!(lookup_user("0").unwrap().0.to_str().unwrap(), "root");
+        let err = lookup_user("no-such-user-timezoned").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("no-such-user-timezoned"));
+        assert!(lookup_user("root\0").is_err());
+
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        // Group IDs don't need to exist
+        assert_eq!(lookup_group("54321").unwrap(), 54321);
+        let err = lookup_group("no-such-group-timezoned").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,2959 @@
+use crate::dial_codes::DIAL_CODES;
+use crate::geoip_cache::GeoIpCache;
+use crate::logger::REQUEST_LOG_TARGET;
+use crate::posix::{self, format_datetime, parse_rfc3339, unix_time, Dialect, Posix};
+use crate::{normalize_posix, normalize_string, Config, GeoIpDb, Timezone, TimezoneDb};
+use log::{debug, warn};
+use lru::LruCache;
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+pub const ERR_TIMEZONE_NOT_FOUND: &[u8] = "ERROR Timezone Not Found".as_bytes();
+pub const ERR_GEOIP_LOOKUP_FAILED: &[u8] = "ERROR GeoIP Lookup Failed".as_bytes();
+pub const ERR_GEOIP_DISABLED: &[u8] = "ERROR GeoIP Disabled".as_bytes();
+pub const ERR_GEOIP_UNAVAILABLE: &[u8] = "ERROR GeoIP Unavailable".as_bytes();
+pub const ERR_COUNTRY_NOT_FOUND: &[u8] = "ERROR Country Not Found".as_bytes();
+pub const ERR_COUNTRY_SPANS_MULTIPLE_TIMEZONES: &[u8] =
+    "ERROR Country Spans Multiple Timezones".as_bytes();
+pub const ERR_UNKNOWN_COMMAND: &[u8] = "ERROR Unknown Command".as_bytes();
+pub const ERR_DESCRIPTION_NOT_FOUND: &[u8] = "ERROR Description Not Found".as_bytes();
+pub const ERR_INVALID_OFFSET: &[u8] = "ERROR Invalid Offset".as_bytes();
+pub const ERR_UNKNOWN_FORMAT: &[u8] = "ERROR Unknown Format".as_bytes();
+pub const ERR_ADDRESS_UNAVAILABLE: &[u8] = "ERROR Address Unavailable".as_bytes();
+pub const ERR_INVALID_DATE: &[u8] = "ERROR Invalid Date".as_bytes();
+pub const ERR_DATA_TOO_STALE: &[u8] = "ERROR Data Too Stale".as_bytes();
+pub const ERR_RESPONSE_TOO_LARGE: &[u8] = "ERROR Response Too Large".as_bytes();
+pub const ERR_CITY_AMBIGUOUS: &[u8] = "ERROR City Is Ambiguous".as_bytes();
+pub const ERR_NOT_AUTHORIZED: &[u8] = "ERROR Not Authorized".as_bytes();
+pub const ERR_UNKNOWN_DIALECT: &[u8] = "ERROR Unknown Dialect".as_bytes();
+pub const ERR_RELOAD_FAILED: &[u8] = "ERROR Reload Failed".as_bytes();
+pub const ERR_INITIALIZING: &[u8] = "ERROR Initializing".as_bytes();
+pub const ERR_RATE_LIMITED: &[u8] = "ERROR Rate Limited".as_bytes();
+pub const ERR_INVALID_ADDRESS: &[u8] = "ERROR Invalid Address".as_bytes();
+// Followed by the reason the POSIX string is invalid
+pub const ERR_INVALID_POSIX: &[u8] = "ERROR Invalid POSIX".as_bytes();
+pub const ERR_INVALID_COUNT: &[u8] = "ERROR Invalid Count".as_bytes();
+pub const ERR_INVALID_DIAL_CODE: &[u8] = "ERROR Invalid Dial Code".as_bytes();
+// Followed by the countries sharing the dial code
+pub const ERR_DIAL_CODE_AMBIGUOUS: &[u8] = "ERROR Dial Code Is Ambiguous".as_bytes();
+
+// Errors the original ezTime server returns, which clients may match byte for byte
+const EZTIME_ERRORS: [&[u8]; 4] = [
+    ERR_TIMEZONE_NOT_FOUND,
+    ERR_COUNTRY_NOT_FOUND,
+    ERR_COUNTRY_SPANS_MULTIPLE_TIMEZONES,
+    ERR_GEOIP_LOOKUP_FAILED,
+];
+
+// Commands whose first argument is the admin key
+pub const ADMIN_COMMANDS: &[&str] = &["GEOIPRAW", "RELOADGEOIP", "DUMP"];
+
+// Responses listing multiple items are truncated to fit within this size
+pub const MAX_RESPONSE_SIZE: usize = 512;
+// Token appended after a separator to responses that were truncated
+const TRUNCATED_MARKER: u8 = b'+';
+// Longest line BYOFFSETALL appends when it has more groups, like "\nMORE 123"
+const MAX_MORE_LINE_SIZE: usize = 16;
+// Most transitions TRANSITIONS can return, which is 10 years for most timezones
+const MAX_TRANSITIONS: usize = 20;
+
+// Whether a response means the request was for something that doesn't exist or isn't supported
+pub fn is_not_found(response: &[u8]) -> bool {
+    [
+        ERR_TIMEZONE_NOT_FOUND,
+        ERR_COUNTRY_NOT_FOUND,
+        ERR_UNKNOWN_COMMAND,
+        ERR_UNKNOWN_FORMAT,
+        ERR_INVALID_OFFSET,
+        ERR_INVALID_DATE,
+    ]
+    .contains(&response)
+}
+
+// State needed to respond to requests
+pub struct Context<'a> {
+    pub config: &'a Config,
+    pub timezones: &'a TimezoneDb,
+    pub geoip: Option<&'a GeoIpDb>,
+    pub geoip_cache: Option<&'a RefCell<GeoIpCache>>,
+    pub response_cache: Option<&'a RefCell<LruCache<Request, Vec<u8>>>>,
+    // Whether a database refresh is in progress
+    pub reloading: bool,
+    // Whether the timezone data is too old to be trusted
+    pub data_stale: bool,
+    // When the next timezone and GeoIP database refreshes are due, or None if they're disabled
+    pub tz_refresh_at: Option<SystemTime>,
+    pub geoip_refresh_at: Option<SystemTime>,
+    // Whether the GeoIP database was reloaded successfully for a RELOADGEOIP request, since
+    // requests can't reload it themselves
+    pub geoip_reloaded: Option<bool>,
+    // Time the request is answered for, so every part of a response is for the same time
+    pub now: SystemTime,
+}
+
+// Format of responses to requests for a single timezone, selected with a suffix like
+// Europe/Berlin?json
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResponseFormat {
+    // OK <olson> <posix>
+    Default,
+    // OK <posix>
+    Posix,
+    // OK <current UTC offset in seconds>
+    Offset,
+    // {"timezone":<olson>,"posix":<posix>,"offset":<current UTC offset in seconds>}
+    Json,
+}
+
+impl ResponseFormat {
+    fn parse(format: &str) -> Option<Self> {
+        match format.to_lowercase().as_str() {
+            "posix" => Some(ResponseFormat::Posix),
+            "offset" => Some(ResponseFormat::Offset),
+            "json" => Some(ResponseFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Request {
+    Country(String, ResponseFormat),
+    // Country calling code, without the +
+    Dial(String, ResponseFormat),
+    GeoIp(ResponseFormat),
+    GeoIpInfo,
+    // Admin key and address
+    GeoIpRaw(String, IpAddr),
+    // Admin key
+    ReloadGeoIp(String),
+    // Admin key and index of the first timezone to return
+    Dump(String, usize),
+    Olson(String, ResponseFormat),
+    City(String, ResponseFormat),
+    ListCountry(String),
+    CountryPosix(String),
+    ZoneCountries(String),
+    Info(String),
+    ByOffset(i32, ResponseFormat),
+    // Time is None for the current time
+    ListByOffset(i32, Option<i64>),
+    // Index of the first offset group to return
+    ByOffsetAll(usize),
+    Describe(String),
+    NextDst(String),
+    // Number of transitions
+    Transitions(String, usize),
+    HasDst(String),
+    DstNow(String),
+    OffsetIso(String),
+    Offsets(String),
+    OffsetAt(String, i64),
+    Diff(String, String),
+    Now(String),
+    Abbr(String),
+    Raw(String),
+    DiffTz(String),
+    PosixFmt(String, Dialect),
+    // POSIX string given by the client
+    Validate(String),
+    Label(String),
+    Status,
+    Schedule,
+    Count,
+    MyIp,
+    Discover,
+    // A known command with invalid arguments
+    Invalid(&'static [u8]),
+    // Not a known command, and not plausibly a timezone name either
+    Unknown(String, ResponseFormat),
+}
+
+impl Request {
+    pub fn parse(request: &str) -> Self {
+        let (request, format) = match request.trim().split_once('?') {
+            Some((request, format)) => match ResponseFormat::parse(format.trim()) {
+                Some(format) => (request.trim(), format),
+                None => return Request::Invalid(ERR_UNKNOWN_FORMAT),
+            },
+            None => (request.trim(), ResponseFormat::Default),
+        };
+        let (command, args) = match request.split_once(char::is_whitespace) {
+            Some((command, args)) => (command, args.trim()),
+            None => (request, ""),
+        };
+
+        let request = match (command.to_uppercase().as_str(), args) {
+            ("GEOIP", "") => Request::GeoIp(format),
+            ("GEOIPINFO", "") => Request::GeoIpInfo,
+            ("GEOIPRAW", args) => match args.split_whitespace().collect::<Vec<_>>()[..] {
+                [key, addr] => match addr.parse() {
+                    Ok(addr) => Request::GeoIpRaw(key.to_owned(), addr),
+                    Err(_) => Request::Invalid(ERR_INVALID_ADDRESS),
+                },
+                _ => Request::Invalid(ERR_NOT_AUTHORIZED),
+            },
+            ("RELOADGEOIP", key) => Request::ReloadGeoIp(key.to_owned()),
+            ("DUMP", args) => match args.split_whitespace().collect::<Vec<_>>()[..] {
+                [key] => Request::Dump(key.to_owned(), 0),
+                [key, index] => match index.parse() {
+                    Ok(index) => Request::Dump(key.to_owned(), index),
+                    Err(_) => Request::Invalid(ERR_INVALID_OFFSET),
+                },
+                _ => Request::Invalid(ERR_NOT_AUTHORIZED),
+            },
+            ("STATUS", "") => Request::Status,
+            ("SCHEDULE", "") => Request::Schedule,
+            ("COUNT", "") => Request::Count,
+            ("MYIP", "") => Request::MyIp,
+            ("DISCOVER", "") => Request::Discover,
+            ("LISTCOUNTRY", country) if !country.is_empty() => {
+                Request::ListCountry(normalize_string(country))
+            }
+            ("COUNTRYPOSIX", country) if !country.is_empty() => {
+                Request::CountryPosix(normalize_string(country))
+            }
+            ("CITY", city) if !city.is_empty() => {
+                // Collapse runs of whitespace so "New  York" matches New_York
+                let city = city.split_whitespace().collect::<Vec<_>>().join(" ");
+                Request::City(normalize_string(&city), format)
+            }
+            ("ZONECOUNTRIES", olson) if !olson.is_empty() => {
+                Request::ZoneCountries(normalize_string(olson))
+            }
+            ("INFO", olson) if !olson.is_empty() => Request::Info(normalize_string(olson)),
+            ("DESCRIBE", olson) if !olson.is_empty() => Request::Describe(normalize_string(olson)),
+            ("NEXTDST", olson) if !olson.is_empty() => Request::NextDst(normalize_string(olson)),
+            ("TRANSITIONS", args) => match args.split_whitespace().collect::<Vec<_>>()[..] {
+                [olson, count] => match count.parse() {
+                    Ok(count @ 1..=MAX_TRANSITIONS) => {
+                        Request::Transitions(normalize_string(olson), count)
+                    }
+                    _ => Request::Invalid(ERR_INVALID_COUNT),
+                },
+                _ => Request::Invalid(ERR_INVALID_COUNT),
+            },
+            ("HASDST", olson) if !olson.is_empty() => Request::HasDst(normalize_string(olson)),
+            ("DSTNOW", olson) if !olson.is_empty() => Request::DstNow(normalize_string(olson)),
+            ("OFFSETISO", olson) if !olson.is_empty() => {
+                Request::OffsetIso(normalize_string(olson))
+            }
+            ("OFFSETS", olson) if !olson.is_empty() => Request::Offsets(normalize_string(olson)),
+            ("OFFSETAT", args) => match args.split_once(char::is_whitespace) {
+                Some((olson, date)) => match parse_rfc3339(date.trim()) {
+                    Some(time) => Request::OffsetAt(normalize_string(olson), time),
+                    None => Request::Invalid(ERR_INVALID_DATE),
+                },
+                None => Request::Invalid(ERR_INVALID_DATE),
+            },
+            ("DIFF", args) => match args.split_whitespace().collect::<Vec<_>>()[..] {
+                [a, b] => Request::Diff(normalize_string(a), normalize_string(b)),
+                _ => Request::Invalid(ERR_TIMEZONE_NOT_FOUND),
+            },
+            ("NOW", olson) if !olson.is_empty() => Request::Now(normalize_string(olson)),
+            ("ABBR", olson) if !olson.is_empty() => Request::Abbr(normalize_string(olson)),
+            ("RAW", olson) if !olson.is_empty() => Request::Raw(normalize_string(olson)),
+            ("DIFFTZ", olson) if !olson.is_empty() => Request::DiffTz(normalize_string(olson)),
+            ("LABEL", olson) if !olson.is_empty() => Request::Label(normalize_string(olson)),
+            ("POSIXFMT", args) => match args.split_whitespace().collect::<Vec<_>>()[..] {
+                [olson, dialect] => match dialect.parse() {
+                    Ok(dialect) => Request::PosixFmt(normalize_string(olson), dialect),
+                    Err(_) => Request::Invalid(ERR_UNKNOWN_DIALECT),
+                },
+                _ => Request::Invalid(ERR_UNKNOWN_DIALECT),
+            },
+            ("VALIDATE", posix) => Request::Validate(normalize_posix(posix)),
+            ("DIAL", code) => match parse_dial_code(code) {
+                Some(code) => Request::Dial(code, format),
+                None => Request::Invalid(ERR_INVALID_DIAL_CODE),
+            },
+            ("BYOFFSET", offset) => match parse_utc_offset(offset) {
+                Some(offset) => Request::ByOffset(offset, format),
+                None => Request::Invalid(ERR_INVALID_OFFSET),
+            },
+            ("LISTBYOFFSET", offset) => match parse_utc_offset(offset) {
+                Some(offset) => Request::ListByOffset(offset, None),
+                None => Request::Invalid(ERR_INVALID_OFFSET),
+            },
+            ("BYOFFSETALL", "") => Request::ByOffsetAll(0),
+            ("BYOFFSETALL", index) => match index.parse() {
+                Ok(index) => Request::ByOffsetAll(index),
+                Err(_) => Request::Invalid(ERR_INVALID_OFFSET),
+            },
+            ("LISTBYOFFSETAT", args) => {
+                let (offset, date) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                match (parse_utc_offset(offset), parse_date_or_rfc3339(date.trim())) {
+                    (Some(offset), Some(time)) => Request::ListByOffset(offset, Some(time)),
+                    (None, _) => Request::Invalid(ERR_INVALID_OFFSET),
+                    (_, None) => Request::Invalid(ERR_INVALID_DATE),
+                }
+            }
+            _ => {
+                let request = normalize_string(request);
+                if request.len() == 2 {
+                    Request::Country(request, format)
+                } else if request.contains('/') {
+                    // Every timezone name in posixinfo has at least one '/'
+                    Request::Olson(request, format)
+                } else {
+                    Request::Unknown(request, format)
+                }
+            }
+        };
+
+        // Formats only apply to requests for a single timezone
+        match request {
+            Request::Country(..)
+            | Request::Dial(..)
+            | Request::GeoIp(_)
+            | Request::Olson(..)
+            | Request::City(..)
+            | Request::ByOffset(..)
+            | Request::Invalid(_)
+            | Request::Unknown(..) => request,
+            _ if format == ResponseFormat::Default => request,
+            _ => Request::Invalid(ERR_UNKNOWN_FORMAT),
+        }
+    }
+
+    // Replaces errors the original ezTime server doesn't have with the closest one it does, for
+    // clients that only expect its errors. Other responses are returned as-is.
+    pub fn eztime_response(&self, response: Vec<u8>) -> Vec<u8> {
+        if !response.starts_with(b"ERROR") || EZTIME_ERRORS.contains(&response.as_slice()) {
+            return response;
+        }
+        match self {
+            Request::GeoIp(_) | Request::GeoIpInfo => ERR_GEOIP_LOOKUP_FAILED,
+            Request::Country(..) => ERR_COUNTRY_NOT_FOUND,
+            _ => ERR_TIMEZONE_NOT_FOUND,
+        }
+        .to_vec()
+    }
+
+    // Request type used for metrics labels
+    #[cfg(feature = "metrics")]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Country(..) => "country",
+            Request::Dial(..) => "dial",
+            Request::GeoIp(_) => "geoip",
+            Request::GeoIpInfo => "geoip_info",
+            Request::GeoIpRaw(..) => "geoip_raw",
+            Request::ReloadGeoIp(_) => "reload_geoip",
+            Request::Dump(..) => "dump",
+            Request::Olson(..) => "timezone",
+            Request::City(..) => "city",
+            Request::ListCountry(_) => "list_country",
+            Request::CountryPosix(_) => "country_posix",
+            Request::ZoneCountries(_) => "zone_countries",
+            Request::Info(_) => "info",
+            Request::ByOffset(..) => "by_offset",
+            Request::ListByOffset(_, None) => "list_by_offset",
+            Request::ListByOffset(_, Some(_)) => "list_by_offset_at",
+            Request::ByOffsetAll(_) => "by_offset_all",
+            Request::Describe(_) => "describe",
+            Request::NextDst(_) => "next_dst",
+            Request::Transitions(..) => "transitions",
+            Request::HasDst(_) => "has_dst",
+            Request::DstNow(_) => "dst_now",
+            Request::OffsetIso(_) => "offset_iso",
+            Request::Offsets(_) => "offsets",
+            Request::OffsetAt(..) => "offset_at",
+            Request::Diff(..) => "diff",
+            Request::Now(_) => "now",
+            Request::Abbr(_) => "abbr",
+            Request::Raw(_) => "raw",
+            Request::DiffTz(_) => "diff_tz",
+            Request::PosixFmt(..) => "posix_fmt",
+            Request::Validate(_) => "validate",
+            Request::Label(_) => "label",
+            Request::Status => "status",
+            Request::Schedule => "schedule",
+            Request::Count => "count",
+            Request::MyIp => "my_ip",
+            Request::Discover => "discover",
+            Request::Invalid(_) => "invalid",
+            Request::Unknown(..) => "unknown",
+        }
+    }
+
+    // Whether the response only depends on the request and the databases, so it can be cached until
+    // they're reloaded
+    pub fn is_cacheable(&self) -> bool {
+        match self {
+            Request::Country(_, format)
+            | Request::Dial(_, format)
+            | Request::Olson(_, format)
+            | Request::City(_, format) => {
+                // These formats depend on the current time
+                !matches!(format, ResponseFormat::Offset | ResponseFormat::Json)
+            }
+            Request::ListCountry(_)
+            | Request::CountryPosix(_)
+            | Request::ZoneCountries(_)
+            | Request::Info(_)
+            | Request::Describe(_)
+            | Request::HasDst(_)
+            | Request::Offsets(_)
+            | Request::PosixFmt(..)
+            | Request::Label(_)
+            | Request::DiffTz(_)
+            | Request::Raw(_) => true,
+            _ => false,
+        }
+    }
+
+    // Whether the response depends on the timezone data
+    fn is_lookup(&self) -> bool {
+        !matches!(
+            self,
+            Request::Status
+                | Request::Schedule
+                | Request::Count
+                | Request::GeoIpRaw(..)
+                | Request::ReloadGeoIp(_)
+                | Request::MyIp
+                | Request::Discover
+                | Request::Validate(_)
+                | Request::Invalid(_)
+                | Request::Unknown(..)
+        )
+    }
+
+    pub fn respond(&self, ctx: &Context, addr: Option<IpAddr>) -> Vec<u8> {
+        if ctx.data_stale && self.is_lookup() {
+            log_request!("stale");
+            return ERR_DATA_TOO_STALE.to_vec();
+        }
+        match self {
+            Request::Country(country, format) => respond_country(ctx, country, *format),
+            Request::Dial(code, format) => respond_dial(ctx, code, *format),
+            Request::GeoIp(format) => {
+                // GeoIP lookup
+                if !ctx.config.geoip_enabled {
+                    log_request!("geoip", "timezone" => "disabled");
+                    return ERR_GEOIP_DISABLED.to_vec();
+                }
+                let tz = addr.and_then(|addr| lookup_geoip(ctx, addr)).or_else(|| {
+                    // Fall back to the configured default timezone, if there is one
+                    let olson = ctx.config.geoip_default.as_ref()?;
+                    let tz = ctx.timezones.lookup_olson(olson)?;
+                    debug!(
+                        target: REQUEST_LOG_TARGET,
+                        "No GeoIP timezone found, using default {}",
+                        tz.olson
+                    );
+                    Some(tz)
+                });
+                match tz {
+                    Some(tz) => {
+                        log_request!("geoip", "timezone" => tz.olson.to_owned());
+                        ok(ctx, tz, *format)
+                    }
+                    // Without a database, every lookup would fail until one is loaded, so clients
+                    // shouldn't bother retrying
+                    None if ctx.geoip.is_none() => {
+                        log_request!("geoip", "timezone" => "unavailable");
+                        ERR_GEOIP_UNAVAILABLE.to_vec()
+                    }
+                    None => {
+                        log_request!("geoip", "timezone" => "not_found");
+                        ERR_GEOIP_LOOKUP_FAILED.to_vec()
+                    }
+                }
+            }
+            Request::GeoIpInfo => {
+                // GeoIP lookup with the country and subdivision the timezone was found from
+                if !ctx.config.geoip_enabled {
+                    log_request!("geoip_info", "result" => "disabled");
+                    return ERR_GEOIP_DISABLED.to_vec();
+                }
+                let Some(geoip) = ctx.geoip else {
+                    log_request!("geoip_info", "result" => "unavailable");
+                    return ERR_GEOIP_UNAVAILABLE.to_vec();
+                };
+                let Some((addr, location)) =
+                    addr.and_then(|addr| Some((addr, geoip.lookup(addr)?)))
+                else {
+                    log_request!("geoip_info", "result" => "not_found");
+                    return ERR_GEOIP_LOOKUP_FAILED.to_vec();
+                };
+
+                let tz = lookup_geoip(ctx, addr);
+                let fields = [
+                    ("tz", tz.map(|tz| tz.response_olson())),
+                    ("country", location.country),
+                    ("subdivision", location.subdivision),
+                ];
+                let fields = fields
+                    .iter()
+                    .filter_map(|(name, value)| Some(format!("{}={}", name, (*value)?)))
+                    .collect::<Vec<_>>();
+                if fields.is_empty() {
+                    log_request!("geoip_info", "result" => "not_found");
+                    return ERR_GEOIP_LOOKUP_FAILED.to_vec();
+                }
+                log_request!("geoip_info", "result" => "ok");
+                format!("OK {}", fields.join(" ")).into_bytes()
+            }
+            Request::Olson(olson, format) => {
+                // Olson name lookup
+                match ctx.timezones.lookup_olson(olson) {
+                    Some(tz) => {
+                        log_request!("timezone", "timezone" => tz.olson.to_owned());
+                        ok(ctx, tz, *format)
+                    }
+                    None => match ctx.timezones.lookup_country_name(olson) {
+                        // Requests like Germany are answered as if they were for the country code
+                        Some(country) if ctx.config.fuzzy_country => {
+                            respond_country(ctx, country, *format)
+                        }
+                        _ => {
+                            log_request!("timezone", "timezone" => "not_found");
+                            ERR_TIMEZONE_NOT_FOUND.to_vec()
+                        }
+                    },
+                }
+            }
+            Request::City(city, format) => {
+                // Lookup by the last part of the olson name, like Berlin for Europe/Berlin
+                let Some(tzs) = ctx.timezones.lookup_city(city) else {
+                    log_request!("city", "timezone" => "not_found");
+                    return ERR_TIMEZONE_NOT_FOUND.to_vec();
+                };
+
+                match tzs[..] {
+                    [tz] => {
+                        log_request!("city", "timezone" => tz.olson.to_owned());
+                        ok(ctx, tz, *format)
+                    }
+                    _ => {
+                        log_request!("city", "timezone" => "ambiguous");
+                        ERR_CITY_AMBIGUOUS.to_vec()
+                    }
+                }
+            }
+            Request::ListCountry(country) => {
+                // List every timezone in a country
+                let Some(tzs) = ctx.timezones.lookup_country(country) else {
+                    log_request!("list_country", "country" => "not_found");
+                    return ERR_COUNTRY_NOT_FOUND.to_vec();
+                };
+
+                log_request!("list_country", "country" => country.to_owned());
+                let mut response = b"OK".to_vec();
+                let olsons = tzs.iter().map(|tz| tz.olson.as_str()).collect::<Vec<_>>();
+                push_truncatable(&mut response, &olsons, b' ', MAX_RESPONSE_SIZE);
+                response
+            }
+            Request::CountryPosix(country) => {
+                // List every timezone in a country with its POSIX string, one per line
+                let Some(tzs) = ctx.timezones.lookup_country(country) else {
+                    log_request!("country_posix", "country" => "not_found");
+                    return ERR_COUNTRY_NOT_FOUND.to_vec();
+                };
+
+                log_request!("country_posix", "country" => country.to_owned());
+                let mut response = b"OK".to_vec();
+                let pairs = tzs
+                    .iter()
+                    .map(|tz| format!("{} {}", tz.olson, tz.posix()))
+                    .collect::<Vec<_>>();
+                push_truncatable(&mut response, &pairs, b'\n', MAX_RESPONSE_SIZE);
+                response
+            }
+            Request::ZoneCountries(olson) => {
+                // List every country that uses a timezone
+                let Some(countries) = ctx.timezones.lookup_timezone_countries(olson) else {
+                    log_request!("zone_countries", "timezone" => "not_found");
+                    return ERR_TIMEZONE_NOT_FOUND.to_vec();
+                };
+                if countries.is_empty() {
+                    log_request!("zone_countries", "timezone" => "no_countries");
+                    return ERR_COUNTRY_NOT_FOUND.to_vec();
+                }
+
+                log_request!("zone_countries", "timezone" => olson.to_owned());
+                let mut response = b"OK".to_vec();
+                push_truncatable(&mut response, &countries, b' ', MAX_RESPONSE_SIZE);
+                response
+            }
+            Request::Info(olson) => {
+                // Everything known about a timezone, as key=value fields. Fields that aren't known
+                // for the timezone are left out.
+                let Some(tz) = ctx.timezones.lookup_olson(olson) else {
+                    log_request!("info", "timezone" => "not_found");
+                    return ERR_TIMEZONE_NOT_FOUND.to_vec();
+                };
+
+                log_request!("info", "timezone" => tz.olson.to_owned());
+                let mut fields = vec![
+                    format!("olson={}", tz.response_olson()),
+                    format!("posix={}", tz.posix()),
+                ];
+                if let Some((latitude, longitude)) = tz.coordinates {
+                    fields.push(format!("lat={:.2}", latitude));
+                    fields.push(format!("lon={:.2}", longitude));
+                }
+                let countries = ctx
+                    .timezones
+                    .lookup_timezone_countries(olson)
+                    .unwrap_or_default();
+                if !countries.is_empty() {
+                    fields.push(format!("countries={}", countries.join(",")));
+                }
+                let mut response = b"OK".to_vec();
+                push_truncatable(&mut response, &fields, b' ', MAX_RESPONSE_SIZE);
+                response
+            }
+            Request::ByOffset(offset, format) => {
+                // Representative timezone currently at a UTC offset
+                match ctx
+                    .timezones
+                    .lookup_offset(*offset, unix_time(ctx.now))
+                    .first()
+                {
+                    Some(tz) => {
+                        log_request!("by_offset", "timezone" => tz.olson.to_owned());
+                        ok(ctx, tz, *format)
+                    }
+                    None => {
+                        log_request!("by_offset", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::ListByOffset(offset, time) => {
+                // Every timezone at a UTC offset, currently or at a unix time
+                let time = time.unwrap_or(unix_time(ctx.now));
+                let tzs = ctx.timezones.lookup_offset(*offset, time);
+                if tzs.is_empty() {
+                    log_request!(self.kind(), "timezone" => "not_found");
+                    return ERR_TIMEZONE_NOT_FOUND.to_vec();
+                }
+
+                log_request!(self.kind());
+                let mut response = b"OK".to_vec();
+                let olsons = tzs.iter().map(|tz| tz.olson.as_str()).collect::<Vec<_>>();
+                push_truncatable(&mut response, &olsons, b' ', MAX_RESPONSE_SIZE);
+                response
+            }
+            Request::ByOffsetAll(index) => {
+                // Every timezone grouped by current UTC offset, one group per line, as many groups
+                // as fit starting at index. If there are more, the last line is MORE <next index>.
+                let groups = ctx.timezones.offset_groups(unix_time(ctx.now));
+                if *index >= groups.len() {
+                    log_request!("by_offset_all", "result" => "invalid");
+                    return ERR_INVALID_OFFSET.to_vec();
+                }
+
+                log_request!("by_offset_all", "result" => "ok");
+                let mut response = b"OK".to_vec();
+                let mut next = groups.len();
+                for (i, (offset, tzs)) in groups.iter().enumerate().skip(*index) {
+                    // Leave room for the MORE line, unless this is the last group
+                    let max = if i + 1 < groups.len() {
+                        MAX_RESPONSE_SIZE - MAX_MORE_LINE_SIZE
+                    } else {
+                        MAX_RESPONSE_SIZE
+                    };
+                    let olsons = tzs.iter().map(|tz| tz.olson.as_str()).collect::<Vec<_>>();
+                    let line = format!("\n{} {}", format_utc_offset(*offset), olsons.join(" "));
+                    if response.len() + line.len() <= max {
+                        response.extend_from_slice(line.as_bytes());
+                    } else if i == *index {
+                        // Groups too large for a response on their own are truncated
+                        response.push(b'\n');
+                        response.extend_from_slice(format_utc_offset(*offset).as_bytes());
+                        push_truncatable(&mut response, &olsons, b' ', max);
+                        next = i + 1;
+                        break;
+                    } else {
+                        next = i;
+                        break;
+                    }
+                }
+                if next < groups.len() {
+                    response.extend_from_slice(format!("\nMORE {}", next).as_bytes());
+                }
+                response
+            }
+            Request::Describe(olson) => {
+                // Human-readable description of a timezone
+                let Some(tz) = ctx.timezones.lookup_olson(olson) else {
+                    log_request!("describe", "timezone" => "not_found");
+                    return ERR_TIMEZONE_NOT_FOUND.to_vec();
+                };
+
+                log_request!("describe", "timezone" => tz.olson.to_owned());
+                match &tz.description {
+                    Some(description) => format!("OK {} {}", tz.olson, description).into_bytes(),
+                    None => ERR_DESCRIPTION_NOT_FOUND.to_vec(),
+                }
+            }
+            Request::NextDst(olson) => {
+                // Next transition between standard time and DST
+                let Some((tz, rule)) = ctx
+                    .timezones
+                    .lookup_olson(olson)
+                    .and_then(|tz| Some((tz, tz.rule()?)))
+                else {
+                    log_request!("next_dst", "timezone" => "not_found");
+                    return ERR_TIMEZONE_NOT_FOUND.to_vec();
+                };
+
+                log_request!("next_dst", "timezone" => tz.olson.to_owned());
+                let next = rule.transitions_after(unix_time(ctx.now)).next();
+                match next {
+                    Some(transition) => format!(
+                        "OK {} {} {}",
+                        transition.time, transition.offset_before, transition.offset_after
+                    )
+                    .into_bytes(),
+                    None => b"OK none".to_vec(),
+                }
+            }
+            Request::Transitions(olson, count) => {
+                // Upcoming transitions between standard time and DST, with the offset after each
+                let Some((tz, rule)) = ctx
+                    .timezones
+                    .lookup_olson(olson)
+                    .and_then(|tz| Some((tz, tz.rule()?)))
+                else {
+                    log_request!("transitions", "timezone" => "not_found");
+                    return ERR_TIMEZONE_NOT_FOUND.to_vec();
+                };
+
+                log_request!("transitions", "timezone" => tz.olson.to_owned());
+                let transitions = rule
+                    .transitions_after(unix_time(ctx.now))
+                    .take(*count)
+                    .map(|transition| format!("{}:{}", transition.time, transition.offset_after))
+                    .collect::<Vec<_>>();
+                if transitions.is_empty() {
+                    return b"OK none".to_vec();
+                }
+                let mut response = b"OK".to_vec();
+                push_truncatable(&mut response, &transitions, b' ', MAX_RESPONSE_SIZE);
+                response
+            }
+            Request::HasDst(olson) => {
+                // Whether the timezone's current rule has a DST section
+                match ctx
+                    .timezones
+                    .lookup_olson(olson)
+                    .and_then(|tz| Some((tz, tz.rule()?)))
+                {
+                    Some((tz, rule)) => {
+                        log_request!("has_dst", "timezone" => tz.olson.to_owned());
+                        if rule.dst.is_some() {
+                            b"OK yes".to_vec()
+                        } else {
+                            b"OK no".to_vec()
+                        }
+                    }
+                    None => {
+                        log_request!("has_dst", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::DstNow(olson) => {
+                // Whether the timezone is currently in DST
+                match ctx
+                    .timezones
+                    .lookup_olson(olson)
+                    .and_then(|tz| Some((tz, tz.rule()?)))
+                {
+                    Some((tz, rule)) => {
+                        log_request!("dst_now", "timezone" => tz.olson.to_owned());
+                        if rule.is_dst_at(unix_time(ctx.now)) {
+                            b"OK active".to_vec()
+                        } else {
+                            b"OK inactive".to_vec()
+                        }
+                    }
+                    None => {
+                        log_request!("dst_now", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::OffsetIso(olson) => {
+                // Current UTC offset as +hh:mm
+                match ctx
+                    .timezones
+                    .lookup_olson(olson)
+                    .and_then(|tz| Some((tz, tz.rule()?)))
+                {
+                    Some((tz, rule)) => {
+                        log_request!("offset_iso", "timezone" => tz.olson.to_owned());
+                        let offset = rule.offset_at(unix_time(ctx.now));
+                        format!("OK {}", format_iso_offset(offset)).into_bytes()
+                    }
+                    None => {
+                        log_request!("offset_iso", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::Offsets(olson) => {
+                // Standard and DST offsets from the current rule, regardless of which is in effect
+                match ctx
+                    .timezones
+                    .lookup_olson(olson)
+                    .and_then(|tz| Some((tz, tz.rule()?)))
+                {
+                    Some((tz, rule)) => {
+                        log_request!("offsets", "timezone" => tz.olson.to_owned());
+                        match &rule.dst {
+                            Some(dst) => format!("OK std={} dst={}", rule.std_offset, dst.offset),
+                            None => format!("OK std={}", rule.std_offset),
+                        }
+                        .into_bytes()
+                    }
+                    None => {
+                        log_request!("offsets", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::OffsetAt(olson, time) => {
+                // UTC offset at a unix time, extrapolated from the current rule
+                match ctx
+                    .timezones
+                    .lookup_olson(olson)
+                    .and_then(|tz| Some((tz, tz.rule()?)))
+                {
+                    Some((tz, rule)) => {
+                        log_request!("offset_at", "timezone" => tz.olson.to_owned());
+                        format!("OK {}", rule.offset_at(*time)).into_bytes()
+                    }
+                    None => {
+                        log_request!("offset_at", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::Diff(a, b) => {
+                // Difference between the current UTC offsets of two timezones, the first minus the
+                // second
+                let now = unix_time(ctx.now);
+                let offset = |olson| {
+                    let tz = ctx.timezones.lookup_olson(olson)?;
+                    Some((tz, tz.rule()?.offset_at(now)))
+                };
+                match (offset(a), offset(b)) {
+                    (Some((tz_a, offset_a)), Some((tz_b, offset_b))) => {
+                        log_request!("diff", "timezone" => tz_a.olson.to_owned(), "other_timezone" => tz_b.olson.to_owned());
+                        format!("OK {}", offset_a - offset_b).into_bytes()
+                    }
+                    _ => {
+                        log_request!("diff", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::Now(olson) => {
+                // Current local time, for clients that can't evaluate POSIX rules themselves
+                match ctx
+                    .timezones
+                    .lookup_olson(olson)
+                    .and_then(|tz| Some((tz, tz.rule()?)))
+                {
+                    Some((tz, rule)) => {
+                        log_request!("now", "timezone" => tz.olson.to_owned());
+                        let now = unix_time(ctx.now);
+                        let local = now + rule.offset_at(now) as i64;
+                        format!("OK {}", format_datetime(local)).into_bytes()
+                    }
+                    None => {
+                        log_request!("now", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::Abbr(olson) => {
+                // Abbreviation currently in effect
+                match ctx
+                    .timezones
+                    .lookup_olson(olson)
+                    .and_then(|tz| Some((tz, tz.rule()?)))
+                {
+                    Some((tz, rule)) => {
+                        log_request!("abbr", "timezone" => tz.olson.to_owned());
+                        format!("OK {}", rule.name_at(unix_time(ctx.now))).into_bytes()
+                    }
+                    None => {
+                        log_request!("abbr", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::Raw(olson) => {
+                // POSIX string from tzdata, ignoring any rewrites
+                match ctx.timezones.lookup_olson(olson) {
+                    Some(tz) => {
+                        log_request!("raw", "timezone" => tz.olson.to_owned());
+                        format!("OK {} {}", tz.olson, tz.posix).into_bytes()
+                    }
+                    None => {
+                        log_request!("raw", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::DiffTz(olson) => {
+                // POSIX string from tzdata compared with the one from before the last refresh
+                let Some(tz) = ctx.timezones.lookup_olson(olson) else {
+                    log_request!("diff_tz", "timezone" => "not_found");
+                    return ERR_TIMEZONE_NOT_FOUND.to_vec();
+                };
+
+                log_request!("diff_tz", "timezone" => tz.olson.to_owned());
+                match ctx
+                    .timezones
+                    .lookup_previous_posix(&normalize_string(&tz.olson))
+                {
+                    Some(previous) if previous == tz.posix => b"OK unchanged".to_vec(),
+                    Some(previous) => {
+                        format!("OK current={} prev={}", tz.posix, previous).into_bytes()
+                    }
+                    None => format!("OK current={}", tz.posix).into_bytes(),
+                }
+            }
+            Request::Label(olson) => {
+                // Friendly name for a timezone, from its current name if it was given an old one
+                let tz = ctx
+                    .timezones
+                    .lookup_olson(olson)
+                    .or_else(|| ctx.timezones.lookup_link(olson));
+                match tz {
+                    Some(tz) => {
+                        log_request!("label", "timezone" => tz.olson.to_owned());
+                        format!("OK {}", tz.label()).into_bytes()
+                    }
+                    None => {
+                        log_request!("label", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::PosixFmt(olson, dialect) => {
+                // POSIX string rewritten for a client's parser
+                match ctx.timezones.lookup_olson(olson) {
+                    Some(tz) => {
+                        log_request!("posix_fmt", "timezone" => tz.olson.to_owned());
+                        let posix = posix::reformat(tz.posix(), *dialect);
+                        format!("OK {} {}", tz.response_olson(), posix).into_bytes()
+                    }
+                    None => {
+                        log_request!("posix_fmt", "timezone" => "not_found");
+                        ERR_TIMEZONE_NOT_FOUND.to_vec()
+                    }
+                }
+            }
+            Request::Validate(posix) => {
+                // Checks a client's POSIX string with the same parser used for the timezone data
+                match Posix::parse(posix) {
+                    Ok(_) => {
+                        log_request!("validate", "result" => "valid");
+                        b"OK valid".to_vec()
+                    }
+                    Err(reason) => {
+                        log_request!("validate", "result" => "invalid");
+                        [ERR_INVALID_POSIX, b": ", reason.as_bytes()].concat()
+                    }
+                }
+            }
+            Request::GeoIpRaw(key, addr) => {
+                // The GeoIP database's whole record for an address, for debugging lookups. This
+                // reveals the database's contents, so it's only available to admins.
+                if !is_admin(ctx.config, key) {
+                    log_request!("geoip_raw", "result" => "not_authorized");
+                    return ERR_NOT_AUTHORIZED.to_vec();
+                }
+                if !ctx.config.geoip_enabled {
+                    log_request!("geoip_raw", "result" => "disabled");
+                    return ERR_GEOIP_DISABLED.to_vec();
+                }
+                let Some(geoip) = ctx.geoip else {
+                    log_request!("geoip_raw", "result" => "unavailable");
+                    return ERR_GEOIP_UNAVAILABLE.to_vec();
+                };
+                let Some(record) = geoip.lookup_raw(*addr) else {
+                    log_request!("geoip_raw", "result" => "not_found");
+                    return ERR_GEOIP_LOOKUP_FAILED.to_vec();
+                };
+
+                log_request!("geoip_raw", "result" => "ok");
+                let mut response = b"OK ".to_vec();
+                let json = record.to_string();
+                // Records are usually too large to send whole, since they have names in many
+                // languages
+                if response.len() + json.len() <= MAX_RESPONSE_SIZE {
+                    response.extend_from_slice(json.as_bytes());
+                } else {
+                    let mut end = MAX_RESPONSE_SIZE - response.len() - 2;
+                    while !json.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    response.extend_from_slice(&json.as_bytes()[..end]);
+                    response.extend_from_slice(&[b' ', TRUNCATED_MARKER]);
+                }
+                response
+            }
+            Request::Dump(key, index) => {
+                // Every timezone and the POSIX string served for it, one per line, as many as fit
+                // starting at index. If there are more, the last line is MORE <next index>. This
+                // exports the whole database, so it's only available to admins.
+                if !is_admin(ctx.config, key) {
+                    log_request!("dump", "result" => "not_authorized");
+                    return ERR_NOT_AUTHORIZED.to_vec();
+                }
+                let tzs = &ctx.timezones.timezones;
+                if *index >= tzs.len() {
+                    log_request!("dump", "result" => "invalid");
+                    return ERR_INVALID_OFFSET.to_vec();
+                }
+
+                log_request!("dump", "result" => "ok");
+                let mut response = b"OK".to_vec();
+                let mut next = tzs.len();
+                for (i, tz) in tzs.iter().enumerate().skip(*index) {
+                    // Leave room for the MORE line, unless this is the last timezone
+                    let max = if i + 1 < tzs.len() {
+                        MAX_RESPONSE_SIZE - MAX_MORE_LINE_SIZE
+                    } else {
+                        MAX_RESPONSE_SIZE
+                    };
+                    let line = format!("\n{} {}", tz.response_olson(), tz.posix());
+                    if response.len() + line.len() > max {
+                        next = i;
+                        break;
+                    }
+                    response.extend_from_slice(line.as_bytes());
+                }
+                if next < tzs.len() {
+                    response.extend_from_slice(format!("\nMORE {}", next).as_bytes());
+                }
+                response
+            }
+            Request::ReloadGeoIp(key) => {
+                // The server reloads the GeoIP database before responding, if the key is right
+                if !is_admin(ctx.config, key) {
+                    log_request!("reload_geoip", "result" => "not_authorized");
+                    return ERR_NOT_AUTHORIZED.to_vec();
+                }
+                if !ctx.config.geoip_enabled {
+                    log_request!("reload_geoip", "result" => "disabled");
+                    return ERR_GEOIP_DISABLED.to_vec();
+                }
+                if ctx.geoip_reloaded == Some(true) {
+                    log_request!("reload_geoip", "result" => "ok");
+                    b"OK Reloaded".to_vec()
+                } else {
+                    log_request!("reload_geoip", "result" => "failed");
+                    ERR_RELOAD_FAILED.to_vec()
+                }
+            }
+            Request::MyIp => {
+                // Address the request was received from, as seen by the server
+                if !ctx.config.myip_enabled {
+                    log_request!("my_ip", "result" => "disabled");
+                    return ERR_UNKNOWN_COMMAND.to_vec();
+                }
+                match addr {
+                    Some(addr) => {
+                        log_request!("my_ip", "result" => "ok");
+                        format!("OK {}", addr).into_bytes()
+                    }
+                    None => {
+                        log_request!("my_ip", "result" => "not_found");
+                        ERR_ADDRESS_UNAVAILABLE.to_vec()
+                    }
+                }
+            }
+            Request::Discover => {
+                // Lets clients that found the server through multicast or broadcast learn its port
+                if ctx.config.multicast_group.is_none() {
+                    log_request!("discover", "result" => "disabled");
+                    return ERR_UNKNOWN_COMMAND.to_vec();
+                }
+                log_request!("discover", "result" => "ok");
+                format!(
+                    "OK timezoned {} {}",
+                    env!("CARGO_PKG_VERSION"),
+                    ctx.config.port
+                )
+                .into_bytes()
+            }
+            Request::Status => {
+                log_request!("status");
+                format!(
+                    "OK {} {}",
+                    if ctx.reloading { "reloading" } else { "loaded" },
+                    unix_time(ctx.timezones.loaded_at)
+                )
+                .into_bytes()
+            }
+            Request::Schedule => {
+                log_request!("schedule");
+                let next = |time: Option<SystemTime>| match time {
+                    Some(time) => unix_time(time).to_string(),
+                    None => "disabled".into(),
+                };
+                format!(
+                    "OK tz_next={} geoip_next={}",
+                    next(ctx.tz_refresh_at),
+                    next(ctx.geoip_refresh_at)
+                )
+                .into_bytes()
+            }
+            Request::Count => {
+                log_request!("count");
+                format!(
+                    "OK timezones={} countries={}",
+                    ctx.timezones.timezones.len(),
+                    ctx.timezones.country_map.len()
+                )
+                .into_bytes()
+            }
+            Request::Invalid(err) => {
+                log_request!("invalid");
+                err.to_vec()
+            }
+            Request::Unknown(name, format) => match ctx.timezones.lookup_country_name(name) {
+                Some(country) if ctx.config.fuzzy_country => respond_country(ctx, country, *format),
+                _ => {
+                    log_request!("unknown");
+                    ERR_UNKNOWN_COMMAND.to_vec()
+                }
+            },
+        }
+    }
+}
+
+// The response for the default timezone, if one is configured and the response to a timezone name
+// lookup was that it wasn't found. Empty requests only get it if default_timezone_empty is set.
+pub fn respond_default(ctx: &Context, request: &Request, response: &[u8]) -> Option<Vec<u8>> {
+    let (olson, format) = match request {
+        // Without strict command checking, empty requests are looked up as a timezone name
+        Request::Olson(olson, format) | Request::Unknown(olson, format) if olson.is_empty() => {
+            if !ctx.config.default_timezone_empty {
+                return None;
+            }
+            (olson, format)
+        }
+        Request::Olson(olson, format) if response == ERR_TIMEZONE_NOT_FOUND => (olson, format),
+        _ => return None,
+    };
+    let tz = ctx
+        .timezones
+        .lookup_olson(ctx.config.default_timezone.as_ref()?)?;
+    debug!(
+        target: REQUEST_LOG_TARGET,
+        "Timezone {:?} not found, using default {}", olson, tz.olson
+    );
+    Some(ok(ctx, tz, *format))
+}
+
+// Responds with the timezone of a country, given its normalized 2-letter code
+fn respond_country(ctx: &Context, country: &str, format: ResponseFormat) -> Vec<u8> {
+    let Some(tzs) = ctx.timezones.lookup_country(country) else {
+        if ctx.config.country_fallback_olson {
+            if let Some(tz) = ctx.timezones.lookup_olson(country) {
+                log_request!("timezone", "timezone" => tz.olson.to_owned());
+                return ok(ctx, tz, format);
+            }
+        }
+        log_request!("country", "country" => "not_found");
+        return ERR_COUNTRY_NOT_FOUND.to_vec();
+    };
+
+    let tz = match tzs[..] {
+        [tz] => Some(tz),
+        // Countries spanning multiple timezones can have a primary timezone configured
+        _ => ctx.timezones.lookup_country_primary(country),
+    };
+    match tz {
+        Some(tz) => {
+            log_request!("country", "country" => country.to_owned(), "timezone" => tz.olson.to_owned());
+            ok(ctx, tz, format)
+        }
+        None => {
+            log_request!("country", "country" => country.to_owned(), "timezone" => "not_found");
+            ERR_COUNTRY_SPANS_MULTIPLE_TIMEZONES.to_vec()
+        }
+    }
+}
+
+fn respond_dial(ctx: &Context, code: &str, format: ResponseFormat) -> Vec<u8> {
+    let countries = DIAL_CODES
+        .iter()
+        .filter(|(dial_code, _)| *dial_code == code)
+        .map(|(_, country)| *country)
+        .collect::<Vec<_>>();
+    match countries[..] {
+        [] => {
+            log_request!("dial", "country" => "not_found");
+            ERR_INVALID_DIAL_CODE.to_vec()
+        }
+        [country] => respond_country(ctx, country, format),
+        _ => {
+            // Countries sharing a code often share a timezone too, like GB and its crown
+            // dependencies
+            let tzs = countries
+                .iter()
+                .map(|country| match ctx.timezones.lookup_country(country)?[..] {
+                    [tz] => Some(tz),
+                    _ => ctx.timezones.lookup_country_primary(country),
+                })
+                .collect::<Option<Vec<_>>>();
+            match tzs.as_deref() {
+                Some([tz, rest @ ..]) if rest.iter().all(|other| other.olson == tz.olson) => {
+                    log_request!("dial", "timezone" => tz.olson.to_owned());
+                    ok(ctx, tz, format)
+                }
+                _ => {
+                    log_request!("dial", "timezone" => "ambiguous");
+                    [
+                        ERR_DIAL_CODE_AMBIGUOUS,
+                        b": ",
+                        countries.join(" ").as_bytes(),
+                    ]
+                    .concat()
+                }
+            }
+        }
+    }
+}
+
+// Timezone of an IP address from the GeoIP database
+fn lookup_geoip<'a>(ctx: &Context<'a>, addr: IpAddr) -> Option<&'a Timezone> {
+    if let Some(cache) = ctx.geoip_cache {
+        // Cached timezones might no longer exist after the timezone database is refreshed
+        let cached = cache
+            .borrow_mut()
+            .get(addr)
+            .and_then(|olson| ctx.timezones.lookup_olson(&normalize_string(olson)));
+        #[cfg(feature = "metrics")]
+        {
+            let result = if cached.is_some() { "hit" } else { "miss" };
+            metrics::increment_counter!("timezoned_cache_requests", "cache" => "geoip", "result" => result);
+        }
+        if cached.is_some() {
+            return cached;
+        }
+    }
+
+    // The GeoIP database may not be available
+    let location = ctx.geoip?.lookup(addr);
+    let tz = location
+        .as_ref()
+        .and_then(|location| location.timezone)
+        .and_then(|olson| {
+            let normalized = normalize_string(olson);
+            let tz = ctx
+                .timezones
+                .lookup_olson(&normalized)
+                // The GeoIP database may use deprecated names, or be newer than the timezone
+                // database
+                .or_else(|| ctx.timezones.lookup_link(&normalized));
+            if tz.is_none() {
+                warn!(
+                    "GeoIP timezone {} for {} is not in the timezone database",
+                    olson, addr
+                );
+            }
+            tz
+        })
+        .or_else(|| {
+            // Fall back to the timezone of the country, if it only has one or has a primary one
+            let country = normalize_string(location.as_ref()?.country?);
+            match ctx.timezones.lookup_country(&country)?[..] {
+                [tz] => Some(tz),
+                _ => ctx.timezones.lookup_country_primary(&country),
+            }
+        })?;
+
+    if let Some(cache) = ctx.geoip_cache {
+        cache.borrow_mut().insert(addr, &tz.olson);
+    }
+    Some(tz)
+}
+
+fn ok(ctx: &Context, tz: &Timezone, format: ResponseFormat) -> Vec<u8> {
+    let offset = || tz.rule().map(|rule| rule.offset_at(unix_time(ctx.now)));
+    match format {
+        ResponseFormat::Default => {
+            format!("OK {} {}", tz.response_olson(), tz.posix()).into_bytes()
+        }
+        ResponseFormat::Posix => format!("OK {}", tz.posix()).into_bytes(),
+        ResponseFormat::Offset => match offset() {
+            Some(offset) => format!("OK {}", offset).into_bytes(),
+            None => ERR_TIMEZONE_NOT_FOUND.to_vec(),
+        },
+        ResponseFormat::Json => serde_json::json!({
+            "timezone": tz.response_olson(),
+            "posix": tz.posix(),
+            "offset": offset(),
+        })
+        .to_string()
+        .into_bytes(),
+    }
+}
+
+// Parses a UTC offset in the form +hh, +hhmm, or +hh:mm into seconds
+fn parse_utc_offset(offset: &str) -> Option<i32> {
+    let (sign, offset) = match offset.as_bytes().first()? {
+        b'+' => (1, &offset[1..]),
+        b'-' => (-1, &offset[1..]),
+        _ => return None,
+    };
+    // Slicing is only safe on ASCII, and every valid offset is ASCII
+    if !offset.is_ascii() {
+        return None;
+    }
+    let (hours, minutes) = match offset.len() {
+        2 => (offset, "00"),
+        4 => offset.split_at(2),
+        5 if offset.as_bytes()[2] == b':' => (&offset[..2], &offset[3..]),
+        _ => return None,
+    };
+    if !(hours.bytes().chain(minutes.bytes())).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    match (hours.parse::<i32>().ok()?, minutes.parse::<i32>().ok()?) {
+        (hours, minutes) if hours <= 24 && minutes < 60 => {
+            Some(sign * (hours * 3600 + minutes * 60))
+        }
+        _ => None,
+    }
+}
+
+// Formats a UTC offset in seconds as +hhmm, the inverse of parse_utc_offset
+fn format_utc_offset(offset: i32) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let minutes = offset.unsigned_abs() / 60;
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+// Formats a UTC offset in seconds as +hh:mm, as in ISO 8601
+fn format_iso_offset(offset: i32) -> String {
+    let mut offset = format_utc_offset(offset);
+    offset.insert(3, ':');
+    offset
+}
+
+// Parses a country calling code like +49 into its digits. Separators are allowed in NANP codes with
+// area codes, like +1-876.
+fn parse_dial_code(code: &str) -> Option<String> {
+    let code = code.strip_prefix('+').unwrap_or(code);
+    let digits = code
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-'))
+        .collect::<String>();
+    if digits.is_empty() || digits.len() > 4 || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(digits)
+}
+
+// Parses an RFC 3339 time, or a date like 2024-12-25, which is taken as 12:00 UTC so that it falls
+// on the same date in nearly every timezone
+fn parse_date_or_rfc3339(s: &str) -> Option<i64> {
+    if s.len() == 10 {
+        parse_rfc3339(&format!("{}T12:00:00Z", s))
+    } else {
+        parse_rfc3339(s)
+    }
+}
+
+// Whether a key given in a request is TZD_ADMIN_KEY. Admin commands are never allowed without one.
+pub fn is_admin(config: &Config, key: &str) -> bool {
+    let admin_key = &config.admin_key.0;
+    !admin_key.is_empty() && constant_time_eq(key.as_bytes(), admin_key.as_bytes())
+}
+
+// Compares two byte strings in time that only depends on their lengths, so secrets can't be
+// guessed one byte at a time by timing comparisons
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Appends items to buf, each preceded by separator, stopping before buf would exceed max bytes. If
+// not every item fits, TRUNCATED_MARKER is appended in place of the remaining items and true is
+// returned.
+fn push_truncatable<S: AsRef<str>>(
+    buf: &mut Vec<u8>,
+    items: &[S],
+    separator: u8,
+    max: usize,
+) -> bool {
+    for (i, item) in items.iter().enumerate() {
+        let item = item.as_ref();
+        // Unless this is the last item, leave room to append the marker after it
+        let reserved = if i + 1 < items.len() { 2 } else { 0 };
+        if buf.len() + 1 + item.len() + reserved > max {
+            log_request!("truncated");
+            buf.extend_from_slice(&[separator, TRUNCATED_MARKER]);
+            return true;
+        }
+        buf.push(separator);
+        buf.extend_from_slice(item.as_bytes());
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, GeoIpRecord};
+    use std::num::NonZeroUsize;
+
+    fn respond(ctx: &Context, request: &str) -> String {
+        String::from_utf8(Request::parse(request).respond(ctx, None)).unwrap()
+    }
+
+    fn respond_to(ctx: &Context, request: &str, addr: &str) -> String {
+        let addr = addr.parse().unwrap();
+        String::from_utf8(Request::parse(request).respond(ctx, Some(addr))).unwrap()
+    }
+
+    #[test]
+    fn parse_requests() {
+        use ResponseFormat::*;
+        assert!(matches!(
+            Request::parse(" geoip\n"),
+            Request::GeoIp(Default)
+        ));
+        assert!(matches!(Request::parse(" de \n"), Request::Country(c, Default) if c == "DE"));
+        assert!(matches!(
+            Request::parse("America/New York?json"),
+            Request::Olson(olson, Json) if olson == "AMERICA/NEW_YORK"
+        ));
+        // Every timezone name has a '/', so anything else is an unknown command
+        assert!(matches!(Request::parse("UTC"), Request::Unknown(name, _) if name == "UTC"));
+        assert!(matches!(
+            Request::parse("frobnicate"),
+            Request::Unknown(name, _) if name == "FROBNICATE"
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn kind() {
+        assert_eq!(Request::parse("Europe/Berlin").kind(), "timezone");
+        assert_eq!(Request::parse("de").kind(), "country");
+        assert_eq!(Request::parse("GEOIP").kind(), "geoip");
+        assert_eq!(Request::parse("FROBNICATE").kind(), "unknown");
+    }
+
+    #[test]
+    fn olson() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "Europe/Berlin"),
+            "OK Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        assert_eq!(
+            respond(&ctx, "America/Argentina/Buenos_Aires"),
+            "OK America/Argentina/Buenos_Aires <-03>3"
+        );
+        assert_eq!(respond(&ctx, "Europe/Atlantis"), "ERROR Timezone Not Found");
+    }
+
+    #[test]
+    fn country() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "IN"), "OK Asia/Kolkata IST-5:30");
+        assert_eq!(
+            respond(&ctx, "gb"),
+            "OK Europe/London GMT0BST,M3.5.0/1,M10.5.0"
+        );
+        // Aliased and overridden like the ezTime server
+        assert_eq!(
+            respond(&ctx, "UK"),
+            "OK Europe/London GMT0BST,M3.5.0/1,M10.5.0"
+        );
+        assert_eq!(
+            respond(&ctx, "DE"),
+            "OK Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        assert_eq!(
+            respond(&ctx, "US"),
+            "ERROR Country Spans Multiple Timezones"
+        );
+        assert_eq!(respond(&ctx, "XX"), "ERROR Country Not Found");
+    }
+
+    #[test]
+    fn geoip() {
+        let db = fixtures::timezone_db();
+        let geoip = fixtures::geoip([(
+            "192.0.2.1",
+            GeoIpRecord {
+                timezone: Some("Europe/Zurich"),
+                country: Some("CH"),
+                ..Default::default()
+            },
+        )]);
+        let mut config = fixtures::config();
+
+        // Without a database, lookups fail until one is loaded
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.1"),
+            "ERROR GeoIP Unavailable"
+        );
+
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.geoip = Some(&geoip);
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.1"),
+            "OK Europe/Zurich CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.2"),
+            "ERROR GeoIP Lookup Failed"
+        );
+
+        // Disabling GeoIP takes priority over whether there's a database, and over the default
+        config.geoip_enabled = false;
+        config.geoip_default = Some("EUROPE/LONDON".into());
+        for geoip in [None, Some(&geoip)] {
+            let mut ctx = fixtures::context(&config, &db);
+            ctx.geoip = geoip;
+            assert_eq!(
+                respond_to(&ctx, "GEOIP", "192.0.2.1"),
+                "ERROR GeoIP Disabled"
+            );
+        }
+    }
+
+    #[test]
+    fn geoip_default() {
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        config.geoip_default = Some(normalize_string("Asia/Kolkata"));
+        let geoip = fixtures::geoip([(
+            "192.0.2.1",
+            GeoIpRecord {
+                timezone: Some("Australia/Brisbane"),
+                ..Default::default()
+            },
+        )]);
+        let mut ctx = fixtures::context(&config, &db);
+        // Without a GeoIP database or a client address
+        assert_eq!(respond(&ctx, "GEOIP"), "OK Asia/Kolkata IST-5:30");
+        ctx.geoip = Some(&geoip);
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.1"),
+            "OK Australia/Brisbane AEST-10"
+        );
+        // Addresses missing from the GeoIP database
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.2"),
+            "OK Asia/Kolkata IST-5:30"
+        );
+    }
+
+    #[test]
+    fn geoip_cache() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let geoip = fixtures::geoip([(
+            "192.0.2.1",
+            GeoIpRecord {
+                timezone: Some("Asia/Kolkata"),
+                ..Default::default()
+            },
+        )]);
+        let cache = RefCell::new(GeoIpCache::new(NonZeroUsize::new(8).unwrap()));
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.geoip = Some(&geoip);
+        ctx.geoip_cache = Some(&cache);
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.1"),
+            "OK Asia/Kolkata IST-5:30"
+        );
+        // Other addresses in the same subnet are answered from the cache
+        ctx.geoip = None;
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.99"),
+            "OK Asia/Kolkata IST-5:30"
+        );
+        // Addresses that aren't cached need the database
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "198.51.100.1"),
+            "ERROR GeoIP Unavailable"
+        );
+    }
+
+    #[test]
+    fn city() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "CITY berlin"),
+            "OK Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        assert_eq!(
+            respond(&ctx, "city new   york"),
+            "OK America/New_York EST5EDT,M3.2.0,M11.1.0"
+        );
+        assert_eq!(respond(&ctx, "CITY Buenos_Aires?offset"), "OK -10800");
+        assert_eq!(respond(&ctx, "CITY Atlantis"), "ERROR Timezone Not Found");
+
+        let db = TimezoneDb::parse(
+            "America/Indiana/Vevay EST5EDT,M3.2.0,M11.1.0\nEurope/Vevay CET-1\n".as_bytes(),
+            "".as_bytes(),
+            &[],
+        )
+        .unwrap();
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "CITY Vevay"), "ERROR City Is Ambiguous");
+    }
+
+    #[test]
+    fn data_stale() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.data_stale = true;
+        assert_eq!(respond(&ctx, "Europe/London"), "ERROR Data Too Stale");
+        assert_eq!(respond(&ctx, "GB"), "ERROR Data Too Stale");
+        assert_eq!(respond(&ctx, "GEOIP"), "ERROR Data Too Stale");
+        // Requests that don't depend on the timezone data are still answered
+        assert_eq!(respond_to(&ctx, "MYIP", "192.0.2.1"), "OK 192.0.2.1");
+        assert_eq!(respond(&ctx, "FROBNICATE"), "ERROR Unknown Command");
+    }
+
+    #[test]
+    fn list_country() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "listcountry au"),
+            "OK Australia/Lord_Howe Australia/Sydney Australia/Brisbane"
+        );
+        assert_eq!(respond(&ctx, "LISTCOUNTRY IN"), "OK Asia/Kolkata");
+        assert_eq!(respond(&ctx, "LISTCOUNTRY XX"), "ERROR Country Not Found");
+    }
+
+    #[test]
+    fn truncation() {
+        let push = |max| {
+            let mut buf = b"OK".to_vec();
+            let truncated = push_truncatable(&mut buf, &["aaa", "bbb"], b' ', max);
+            assert!(buf.len() <= max);
+            (String::from_utf8(buf).unwrap(), truncated)
+        };
+        // Exactly filling the response
+        assert_eq!(push(10), ("OK aaa bbb".into(), false));
+        // One byte short, so the last item is replaced by the marker
+        assert_eq!(push(9), ("OK aaa +".into(), true));
+        // Room for an item, but not for the marker after it
+        assert_eq!(push(7), ("OK +".into(), true));
+        assert_eq!(push(100), ("OK aaa bbb".into(), false));
+    }
+
+    #[test]
+    fn utc_offsets() {
+        assert_eq!(parse_utc_offset("+0545"), Some(5 * 3600 + 45 * 60));
+        assert_eq!(parse_utc_offset("+05:45"), Some(5 * 3600 + 45 * 60));
+        assert_eq!(parse_utc_offset("-0930"), Some(-(9 * 3600 + 30 * 60)));
+        assert_eq!(parse_utc_offset("+02"), Some(2 * 3600));
+        assert_eq!(parse_utc_offset("-00"), Some(0));
+        assert_eq!(parse_utc_offset("-00:00"), Some(0));
+        for malformed in [
+            "",
+            "+",
+            "0545",
+            "+5",
+            "+545",
+            "+05:4",
+            "+05-45",
+            "+0560",
+            "+2500",
+            "+05:45:00",
+        ] {
+            assert_eq!(parse_utc_offset(malformed), None, "{}", malformed);
+        }
+
+        // Neither of these timezones has DST, so the response doesn't depend on the current time
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "BYOFFSET +0545"),
+            "OK Asia/Kathmandu <+0545>-5:45"
+        );
+        assert_eq!(
+            respond(&ctx, "BYOFFSET +05:45"),
+            "OK Asia/Kathmandu <+0545>-5:45"
+        );
+        assert_eq!(
+            respond(&ctx, "BYOFFSET -0930"),
+            "OK Pacific/Marquesas <-0930>9:30"
+        );
+        assert_eq!(respond(&ctx, "BYOFFSET +1300"), "ERROR Timezone Not Found");
+        assert_eq!(respond(&ctx, "BYOFFSET 0545"), "ERROR Invalid Offset");
+        assert_eq!(respond(&ctx, "LISTBYOFFSET +05:4"), "ERROR Invalid Offset");
+        // Non-ASCII characters could otherwise be sliced through
+        assert_eq!(respond(&ctx, "BYOFFSET +1é2"), "ERROR Invalid Offset");
+    }
+
+    #[test]
+    fn lookup_offset() {
+        let db = fixtures::timezone_db();
+        let olsons = |offset, time| {
+            db.lookup_offset(offset, time)
+                .iter()
+                .map(|tz| tz.olson.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        // 2024-01-15T12:00:00Z, in northern winter and southern summer
+        let winter = 1705320000;
+        assert_eq!(
+            olsons(0, winter),
+            "Africa/Abidjan Etc/UTC Europe/Dublin Europe/London"
+        );
+        assert_eq!(olsons(3600, winter), "Europe/Berlin Europe/Zurich");
+        assert_eq!(
+            olsons(11 * 3600, winter),
+            "Australia/Lord_Howe Australia/Sydney"
+        );
+        // 2024-07-01T12:00:00Z
+        let summer = 1719835200;
+        assert_eq!(olsons(0, summer), "Africa/Abidjan Etc/UTC");
+        assert_eq!(olsons(3600, summer), "Europe/Dublin Europe/London");
+        assert_eq!(
+            olsons(10 * 3600, summer),
+            "Australia/Brisbane Australia/Sydney"
+        );
+        assert_eq!(olsons(-(3 * 3600 + 30 * 60), summer), "");
+    }
+
+    #[test]
+    fn describe() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "DESCRIBE Europe/Zurich"),
+            "OK Europe/Zurich Büsingen"
+        );
+        assert_eq!(
+            respond(&ctx, "DESCRIBE America/Argentina/Buenos_Aires"),
+            "OK America/Argentina/Buenos_Aires Buenos Aires (BA, CF)"
+        );
+        // In zone1970.tab without a comment, or not in it at all
+        assert_eq!(
+            respond(&ctx, "DESCRIBE Europe/London"),
+            "ERROR Description Not Found"
+        );
+        assert_eq!(
+            respond(&ctx, "DESCRIBE Etc/UTC"),
+            "ERROR Description Not Found"
+        );
+        assert_eq!(
+            respond(&ctx, "DESCRIBE Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn next_dst() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        let next = |olson| {
+            let rule = db.lookup_olson(olson).unwrap().rule().unwrap();
+            // 2024-12-31T23:00:00Z
+            let t = rule.transitions_after(1735686000).next().unwrap();
+            (t.time, t.offset_before, t.offset_after)
+        };
+        // The next transition is in the next year, 2025-03-30T01:00:00Z
+        assert_eq!(next("EUROPE/BERLIN"), (1743296400, 3600, 7200));
+        // Southern hemisphere DST ends in the next year, at 2025-04-06T03:00:00+11:00
+        assert_eq!(next("AUSTRALIA/SYDNEY"), (1743868800, 39600, 36000));
+
+        assert_eq!(respond(&ctx, "NEXTDST Asia/Kolkata"), "OK none");
+        assert_eq!(respond(&ctx, "NEXTDST Etc/UTC"), "OK none");
+        assert_eq!(
+            respond(&ctx, "NEXTDST Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn geoip_country_without_timezone() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let geoip = fixtures::geoip([
+            (
+                "192.0.2.1",
+                GeoIpRecord {
+                    country: Some("IN"),
+                    ..Default::default()
+                },
+            ),
+            (
+                "192.0.2.2",
+                GeoIpRecord {
+                    country: Some("US"),
+                    ..Default::default()
+                },
+            ),
+            (
+                "192.0.2.3",
+                GeoIpRecord {
+                    timezone: Some("America/Chicago"),
+                    country: Some("US"),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.geoip = Some(&geoip);
+        // Countries with one timezone fall back to it, but others can't
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.1"),
+            "OK Asia/Kolkata IST-5:30"
+        );
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.2"),
+            "ERROR GeoIP Lookup Failed"
+        );
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.3"),
+            "OK America/Chicago CST6CDT,M3.2.0,M11.1.0"
+        );
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.4"),
+            "ERROR GeoIP Lookup Failed"
+        );
+    }
+
+    #[test]
+    fn status() {
+        let (config, mut db) = (fixtures::config(), fixtures::timezone_db());
+        db.loaded_at = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1700000000);
+        let mut ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "status"), "OK loaded 1700000000");
+        ctx.reloading = true;
+        assert_eq!(respond(&ctx, "STATUS"), "OK reloading 1700000000");
+    }
+
+    #[test]
+    fn my_ip() {
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond_to(&ctx, "myip", "203.0.113.5"), "OK 203.0.113.5");
+        assert_eq!(respond_to(&ctx, "MYIP", "2001:db8::1"), "OK 2001:db8::1");
+        // Unix socket requests have no address
+        assert_eq!(respond(&ctx, "MYIP"), "ERROR Address Unavailable");
+        config.myip_enabled = false;
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond_to(&ctx, "MYIP", "203.0.113.5"),
+            "ERROR Unknown Command"
+        );
+    }
+
+    #[test]
+    fn discover() {
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        // Only answered when the server has joined a multicast group
+        assert_eq!(respond(&ctx, "DISCOVER"), "ERROR Unknown Command");
+        config.multicast_group = Some("239.255.23.42".parse().unwrap());
+        config.port = 2400;
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "discover"),
+            format!("OK timezoned {} 2400", env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn formats() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "Europe/Berlin?posix"),
+            "OK CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        // Timezones without DST, so the offset doesn't depend on the current time
+        assert_eq!(respond(&ctx, "Asia/Kathmandu?offset"), "OK 20700");
+        assert_eq!(respond(&ctx, "Asia/Kathmandu ? offset"), "OK 20700");
+        assert_eq!(
+            respond(&ctx, "Asia/Kolkata?json"),
+            r#"{"offset":19800,"posix":"IST-5:30","timezone":"Asia/Kolkata"}"#
+        );
+        assert_eq!(respond(&ctx, "IN?offset"), "OK 19800");
+        assert_eq!(respond(&ctx, "Europe/Berlin?xml"), "ERROR Unknown Format");
+        assert_eq!(respond(&ctx, "Europe/Berlin?"), "ERROR Unknown Format");
+    }
+
+    #[test]
+    fn raw() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        // tzdata has negative DST for Dublin, which ezTime can't parse
+        assert_eq!(
+            respond(&ctx, "Europe/Dublin"),
+            "OK Europe/Dublin GMT0IST,M3.5.0/1,M10.5.0"
+        );
+        assert_eq!(
+            respond(&ctx, "RAW Europe/Dublin"),
+            "OK Europe/Dublin IST-1GMT0,M10.5.0,M3.5.0/1"
+        );
+        assert_eq!(
+            respond(&ctx, "RAW Europe/Berlin"),
+            respond(&ctx, "Europe/Berlin")
+        );
+        assert_eq!(
+            respond(&ctx, "RAW Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn not_found_responses() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        let not_found = |request| is_not_found(respond(&ctx, request).as_bytes());
+        assert!(not_found("Europe/Atlantis"));
+        assert!(not_found("XX"));
+        assert!(not_found("FROBNICATE"));
+        assert!(not_found("UTC?xml"));
+        assert!(!not_found("Europe/London"));
+        // Lookups that can fail for reasons other than the request
+        assert!(!not_found("GEOIP"));
+        assert!(!not_found("US"));
+    }
+
+    #[test]
+    fn country_posix() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "COUNTRYPOSIX IN"),
+            "OK\nAsia/Kolkata IST-5:30"
+        );
+        // One timezone and its POSIX string per line, with the same rewrites as other requests
+        assert_eq!(
+            respond(&ctx, "COUNTRYPOSIX US"),
+            "OK\nAmerica/New_York EST5EDT,M3.2.0,M11.1.0\nAmerica/Chicago CST6CDT,M3.2.0,M11.1.0"
+        );
+        assert_eq!(
+            respond(&ctx, "COUNTRYPOSIX IE"),
+            "OK\nEurope/Dublin GMT0IST,M3.5.0/1,M10.5.0"
+        );
+        assert_eq!(respond(&ctx, "COUNTRYPOSIX XX"), "ERROR Country Not Found");
+
+        // A country with more timezones than fit in a response
+        let (mut posixinfo, mut zonetab) = (String::new(), String::new());
+        for i in 0..40 {
+            posixinfo += &format!("America/Zone_{i:02} EST5EDT,M3.2.0,M11.1.0\n");
+            zonetab += &format!("US\t+4042-07400\tAmerica/Zone_{i:02}\n");
+        }
+        let db = TimezoneDb::parse(posixinfo.as_bytes(), zonetab.as_bytes(), &[]).unwrap();
+        let ctx = fixtures::context(&config, &db);
+        let response = respond(&ctx, "COUNTRYPOSIX US");
+        assert!(response.len() <= MAX_RESPONSE_SIZE);
+        let lines = response.lines().collect::<Vec<_>>();
+        assert_eq!(lines.first(), Some(&"OK"));
+        assert_eq!(lines.last(), Some(&"+"));
+        for (i, line) in lines[1..lines.len() - 1].iter().enumerate() {
+            assert_eq!(*line, format!("America/Zone_{i:02} EST5EDT,M3.2.0,M11.1.0"));
+        }
+    }
+
+    #[test]
+    fn abbr() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        // Timezones without DST always have the same abbreviation
+        assert_eq!(respond(&ctx, "ABBR Asia/Kolkata"), "OK IST");
+        // Numeric abbreviations are returned without the angle brackets
+        assert_eq!(respond(&ctx, "ABBR Asia/Riyadh"), "OK +03");
+        assert_eq!(
+            respond(&ctx, "ABBR Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+
+        let name_at = |olson, time| {
+            let rule = db
+                .lookup_olson(&normalize_string(olson))
+                .unwrap()
+                .rule()
+                .unwrap();
+            rule.name_at(time).to_owned()
+        };
+        // 2024-07-01T12:00:00Z
+        let july = 1719835200;
+        assert_eq!(name_at("Europe/Berlin", july), "CEST");
+        assert_eq!(name_at("Australia/Sydney", july), "AEST");
+        // 2024-01-15T12:00:00Z
+        let january = 1705320000;
+        assert_eq!(name_at("Europe/Berlin", january), "CET");
+        assert_eq!(name_at("Australia/Sydney", january), "AEDT");
+        assert_eq!(name_at("Australia/Lord_Howe", january), "+11");
+    }
+
+    #[test]
+    fn zone_countries() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        // Zurich is shared with Liechtenstein. zone1970.tab lists DE for Büsingen too, but DE is
+        // overridden to only Europe/Berlin.
+        assert_eq!(respond(&ctx, "ZONECOUNTRIES Europe/Zurich"), "OK CH LI");
+        assert_eq!(
+            respond(&ctx, "ZONECOUNTRIES Europe/Berlin"),
+            "OK DE DK NO SE SJ"
+        );
+        assert_eq!(respond(&ctx, "ZONECOUNTRIES Asia/Kolkata"), "OK IN");
+        assert_eq!(
+            respond(&ctx, "ZONECOUNTRIES Etc/UTC"),
+            "ERROR Country Not Found"
+        );
+        assert_eq!(
+            respond(&ctx, "ZONECOUNTRIES Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn response_aliases() {
+        let config = fixtures::config();
+        let mut db = fixtures::timezone_db();
+        db.set_response_alias("Asia/Kolkata", "Asia/Calcutta")
+            .unwrap();
+        // Aliasing to its own name is allowed, but not to another timezone or a missing one
+        db.set_response_alias("Europe/Berlin", "europe/berlin")
+            .unwrap();
+        assert!(db
+            .set_response_alias("Europe/Zurich", "Europe/Berlin")
+            .is_err());
+        assert!(db
+            .set_response_alias("Europe/Atlantis", "Europe/Lemuria")
+            .is_err());
+
+        let ctx = fixtures::context(&config, &db);
+        // Both names return the alias
+        assert_eq!(respond(&ctx, "Asia/Kolkata"), "OK Asia/Calcutta IST-5:30");
+        assert_eq!(respond(&ctx, "ASIA/CALCUTTA"), "OK Asia/Calcutta IST-5:30");
+        assert_eq!(respond(&ctx, "IN"), "OK Asia/Calcutta IST-5:30");
+        assert!(respond(&ctx, "INFO Asia/Kolkata").contains(" olson=Asia/Calcutta"));
+        assert_eq!(
+            respond(&ctx, "Asia/Kolkata?json"),
+            r#"{"offset":19800,"posix":"IST-5:30","timezone":"Asia/Calcutta"}"#
+        );
+        assert_eq!(
+            respond(&ctx, "Europe/Berlin"),
+            "OK europe/berlin CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        // Lists still use the tzdata names
+        assert_eq!(respond(&ctx, "LISTCOUNTRY IN"), "OK Asia/Kolkata");
+        assert_eq!(
+            respond(&ctx, "Europe/Zurich"),
+            "OK Europe/Zurich CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+    }
+
+    #[test]
+    fn offset_at() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        // Berlin's DST starts and ends at 01:00 UTC on the last Sundays of March and October
+        for (time, offset) in [
+            ("2024-03-31T00:59:59Z", 3600),
+            ("2024-03-31T01:00:00Z", 7200),
+            ("2024-10-27T00:59:59Z", 7200),
+            ("2024-10-27T01:00:00Z", 3600),
+        ] {
+            assert_eq!(
+                respond(&ctx, &format!("OFFSETAT Europe/Berlin {time}")),
+                format!("OK {offset}")
+            );
+        }
+        assert_eq!(
+            respond(&ctx, "OFFSETAT Asia/Kolkata 2024-07-01T12:00:00Z"),
+            "OK 19800"
+        );
+        assert_eq!(
+            respond(&ctx, "OFFSETAT Europe/Berlin 2024-13-01T00:00:00Z"),
+            "ERROR Invalid Date"
+        );
+        assert_eq!(
+            respond(&ctx, "OFFSETAT Europe/Berlin"),
+            "ERROR Invalid Date"
+        );
+        assert_eq!(
+            respond(&ctx, "OFFSETAT Europe/Berlin 2024-07-01T12:00:0é"),
+            "ERROR Invalid Date"
+        );
+        assert_eq!(
+            respond(&ctx, "OFFSETAT Europe/Atlantis 2024-07-01T12:00:00Z"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn fuzzy_country() {
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "India"), "ERROR Unknown Command");
+
+        config.fuzzy_country = true;
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "India"), "OK Asia/Kolkata IST-5:30");
+        assert_eq!(respond(&ctx, "india"), "OK Asia/Kolkata IST-5:30");
+        assert_eq!(
+            respond(&ctx, "United States"),
+            "ERROR Country Spans Multiple Timezones"
+        );
+        assert_eq!(respond(&ctx, "Atlantis"), "ERROR Unknown Command");
+    }
+
+    #[test]
+    fn diff() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "DIFF Asia/Riyadh Asia/Kolkata"), "OK -9000");
+        assert_eq!(respond(&ctx, "DIFF Asia/Kathmandu Asia/Kolkata"), "OK 900");
+        // Both observe the same DST, so they never differ
+        assert_eq!(respond(&ctx, "DIFF Europe/Berlin Europe/Zurich"), "OK 0");
+        assert_eq!(
+            respond(&ctx, "DIFF Europe/Berlin Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+        assert_eq!(
+            respond(&ctx, "DIFF Europe/Berlin"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn list_by_offset_at() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        // Berlin and Zurich move from +01 to +02 for the summer, and Dublin and London from +00
+        // to +01
+        assert_eq!(
+            respond(&ctx, "LISTBYOFFSETAT +01 2024-01-15"),
+            "OK Europe/Berlin Europe/Zurich"
+        );
+        assert_eq!(
+            respond(&ctx, "LISTBYOFFSETAT +01 2024-07-01"),
+            "OK Europe/Dublin Europe/London"
+        );
+        assert_eq!(
+            respond(&ctx, "LISTBYOFFSETAT +02 2024-07-01T12:00:00Z"),
+            "OK Europe/Berlin Europe/Zurich"
+        );
+        assert_eq!(
+            respond(&ctx, "LISTBYOFFSETAT +02 2024-01-15"),
+            "ERROR Timezone Not Found"
+        );
+        // Sydney is the other way around
+        assert_eq!(
+            respond(&ctx, "LISTBYOFFSETAT +10 2024-01-15"),
+            "OK Australia/Brisbane"
+        );
+        assert_eq!(
+            respond(&ctx, "LISTBYOFFSETAT +10 2024-07-01"),
+            "OK Australia/Brisbane Australia/Sydney"
+        );
+        assert_eq!(respond(&ctx, "LISTBYOFFSETAT +10"), "ERROR Invalid Date");
+        assert_eq!(
+            respond(&ctx, "LISTBYOFFSETAT +1x 2024-07-01"),
+            "ERROR Invalid Offset"
+        );
+    }
+
+    #[test]
+    fn now_across_dst_transitions() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let mut ctx = fixtures::context(&config, &db);
+        // Europe/Berlin springs forward at 01:00 UTC on the last Sunday of March
+        ctx.now = fixtures::time("2024-03-31T00:59:59Z");
+        assert_eq!(respond(&ctx, "NOW Europe/Berlin"), "OK 2024-03-31T01:59:59");
+        ctx.now = fixtures::time("2024-03-31T01:00:00Z");
+        assert_eq!(respond(&ctx, "NOW Europe/Berlin"), "OK 2024-03-31T03:00:00");
+        // America/New_York falls back at 06:00 UTC on the first Sunday of November
+        ctx.now = fixtures::time("2024-11-03T05:59:59Z");
+        assert_eq!(
+            respond(&ctx, "NOW America/New_York"),
+            "OK 2024-11-03T01:59:59"
+        );
+        ctx.now = fixtures::time("2024-11-03T06:00:00Z");
+        assert_eq!(
+            respond(&ctx, "NOW America/New_York"),
+            "OK 2024-11-03T01:00:00"
+        );
+        // Local time can be on a different date than UTC
+        ctx.now = fixtures::time("2024-12-31T23:30:00Z");
+        assert_eq!(
+            respond(&ctx, "NOW Australia/Sydney"),
+            "OK 2025-01-01T10:30:00"
+        );
+        assert_eq!(
+            respond(&ctx, "NOW Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn schedule() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let mut ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "SCHEDULE"),
+            "OK tz_next=disabled geoip_next=disabled"
+        );
+        ctx.tz_refresh_at = Some(fixtures::time("2024-07-01T12:00:00Z"));
+        ctx.geoip_refresh_at = Some(fixtures::time("2024-07-08T12:00:00Z"));
+        assert_eq!(
+            respond(&ctx, "schedule"),
+            "OK tz_next=1719835200 geoip_next=1720440000"
+        );
+        assert_eq!(respond(&ctx, "SCHEDULE now"), "ERROR Unknown Command");
+    }
+
+    #[test]
+    fn country_fallback_olson() {
+        // tzdata has a few timezones with 2-letter names, like the backward links NZ and GB
+        let posixinfo = format!(
+            "{}NZ NZST-12NZDT,M9.5.0,M4.1.0/3\nGB GMT0BST,M3.5.0/1,M10.5.0\n",
+            fixtures::POSIXINFO
+        );
+        let db =
+            TimezoneDb::parse(posixinfo.as_bytes(), fixtures::ZONETAB.as_bytes(), &[]).unwrap();
+        let mut config = fixtures::config();
+        assert_eq!(
+            respond(&fixtures::context(&config, &db), "NZ"),
+            "ERROR Country Not Found"
+        );
+
+        config.country_fallback_olson = true;
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "nz"), "OK NZ NZST-12NZDT,M9.5.0,M4.1.0/3");
+        // Countries are still looked up first
+        assert_eq!(
+            respond(&ctx, "GB"),
+            "OK Europe/London GMT0BST,M3.5.0/1,M10.5.0"
+        );
+        assert_eq!(respond(&ctx, "XX"), "ERROR Country Not Found");
+    }
+
+    #[test]
+    fn info() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "INFO Europe/Zurich"),
+            "OK olson=Europe/Zurich posix=CET-1CEST,M3.5.0,M10.5.0/3 lat=47.38 lon=8.53 \
+             countries=CH,LI"
+        );
+        assert_eq!(
+            respond(&ctx, "INFO America/New_York"),
+            "OK olson=America/New_York posix=EST5EDT,M3.2.0,M11.1.0 lat=40.71 lon=-74.01 \
+             countries=US"
+        );
+        // Timezones that aren't in zone1970.tab only have the fields from posixinfo
+        assert_eq!(respond(&ctx, "INFO Etc/UTC"), "OK olson=Etc/UTC posix=UTC0");
+        assert_eq!(
+            respond(&ctx, "INFO Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn zone_prefix_allow() {
+        let prefixes = ["EUROPE/".to_owned(), "ETC/".to_owned()];
+        let db = TimezoneDb::parse(
+            fixtures::POSIXINFO.as_bytes(),
+            fixtures::ZONETAB.as_bytes(),
+            &prefixes,
+        )
+        .unwrap();
+        let config = fixtures::config();
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "europe/zurich"),
+            "OK Europe/Zurich CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        assert_eq!(respond(&ctx, "Etc/UTC"), "OK Etc/UTC UTC0");
+        // Filtered timezones are missing, as if they weren't in tzdata
+        assert_eq!(respond(&ctx, "Asia/Kolkata"), "ERROR Timezone Not Found");
+        assert_eq!(
+            respond(&ctx, "RAW Asia/Kolkata"),
+            "ERROR Timezone Not Found"
+        );
+        assert_eq!(
+            respond(&ctx, "LISTBYOFFSET +0530"),
+            "ERROR Timezone Not Found"
+        );
+        // So are countries with only filtered timezones
+        assert_eq!(respond(&ctx, "IN"), "ERROR Country Not Found");
+        assert_eq!(respond(&ctx, "LISTCOUNTRY US"), "ERROR Country Not Found");
+        assert_eq!(
+            respond(&ctx, "CH"),
+            "OK Europe/Zurich CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+    }
+
+    #[test]
+    fn geoip_alternate_ip() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        // Databases may have an IPv4 address in either of its representations
+        let geoip = fixtures::geoip([
+            (
+                "192.0.2.1",
+                GeoIpRecord {
+                    timezone: Some("Europe/Zurich"),
+                    ..Default::default()
+                },
+            ),
+            (
+                "::ffff:198.51.100.1",
+                GeoIpRecord {
+                    timezone: Some("Asia/Kolkata"),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.geoip = Some(&geoip);
+        for addr in ["192.0.2.1", "::ffff:192.0.2.1"] {
+            assert_eq!(
+                respond_to(&ctx, "GEOIP", addr),
+                "OK Europe/Zurich CET-1CEST,M3.5.0,M10.5.0/3"
+            );
+        }
+        for addr in ["198.51.100.1", "::ffff:198.51.100.1"] {
+            assert_eq!(respond_to(&ctx, "GEOIP", addr), "OK Asia/Kolkata IST-5:30");
+        }
+        // Other IPv6 addresses have no IPv4 representation
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "2001:db8::c000:201"),
+            "ERROR GeoIP Lookup Failed"
+        );
+    }
+
+    #[test]
+    fn geoip_raw() {
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        let geoip = fixtures::geoip([(
+            "::ffff:192.0.2.1",
+            GeoIpRecord {
+                timezone: Some("Europe/Zurich"),
+                country: Some("CH"),
+                ..Default::default()
+            },
+        )]);
+        let record = r#"OK {"country":{"iso_code":"CH"},"location":{"time_zone":"Europe/Zurich"}}"#;
+        let respond = |config: &Config, geoip, request| {
+            let mut ctx = fixtures::context(config, &db);
+            ctx.geoip = geoip;
+            respond(&ctx, request)
+        };
+        // Admin commands are disabled without a key
+        assert_eq!(
+            respond(&config, Some(&geoip), "GEOIPRAW key 192.0.2.1"),
+            "ERROR Not Authorized"
+        );
+        config.admin_key = crate::Secret("key".into());
+        for request in ["GEOIPRAW wrong 192.0.2.1", "GEOIPRAW key", "GEOIPRAW"] {
+            assert_eq!(
+                respond(&config, Some(&geoip), request),
+                "ERROR Not Authorized"
+            );
+        }
+        assert_eq!(
+            respond(&config, Some(&geoip), "GEOIPRAW key not-an-ip"),
+            "ERROR Invalid Address"
+        );
+        assert_eq!(
+            respond(&config, Some(&geoip), "geoipraw key 192.0.2.1"),
+            record
+        );
+        assert_eq!(
+            respond(&config, Some(&geoip), "GEOIPRAW key 198.51.100.1"),
+            "ERROR GeoIP Lookup Failed"
+        );
+        assert_eq!(
+            respond(&config, None, "GEOIPRAW key 192.0.2.1"),
+            "ERROR GeoIP Unavailable"
+        );
+        config.geoip_enabled = false;
+        assert_eq!(
+            respond(&config, Some(&geoip), "GEOIPRAW key 192.0.2.1"),
+            "ERROR GeoIP Disabled"
+        );
+    }
+
+    #[test]
+    fn geoip_timezone_not_in_database() {
+        let config = fixtures::config();
+        let mut db = fixtures::timezone_db();
+        let dir = tempfile::tempdir().unwrap();
+        let backward = dir.path().join("backward");
+        std::fs::write(&backward, "L Asia/Kolkata Asia/Calcutta # Renamed 2008\n").unwrap();
+        db.load_links(&backward);
+
+        let record = |timezone, country| GeoIpRecord {
+            timezone: Some(timezone),
+            country,
+            ..Default::default()
+        };
+        let geoip = fixtures::geoip([
+            ("192.0.2.1", record("Asia/Calcutta", None)),
+            ("192.0.2.2", record("Asia/Lemuria", Some("IN"))),
+            ("192.0.2.3", record("Asia/Lemuria", None)),
+        ]);
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.geoip = Some(&geoip);
+        // Deprecated names are resolved through tzdata's links
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.1"),
+            "OK Asia/Kolkata IST-5:30"
+        );
+        // Unknown timezones fall back to the country's timezone, if there is one
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.2"),
+            "OK Asia/Kolkata IST-5:30"
+        );
+        assert_eq!(
+            respond_to(&ctx, "GEOIP", "192.0.2.3"),
+            "ERROR GeoIP Lookup Failed"
+        );
+    }
+
+    #[test]
+    fn count() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "COUNT"), "OK timezones=18 countries=37");
+        assert_eq!(respond(&ctx, "count "), "OK timezones=18 countries=37");
+        assert_eq!(respond(&ctx, "COUNT US"), "ERROR Unknown Command");
+
+        // Only what was loaded is counted
+        let db = TimezoneDb::parse(fixtures::POSIXINFO.as_bytes(), "".as_bytes(), &[]).unwrap();
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "COUNT"), "OK timezones=18 countries=0");
+    }
+
+    #[test]
+    fn has_dst() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let mut ctx = fixtures::context(&config, &db);
+        // Answered from the rule, whether or not DST is currently in effect
+        for now in ["2024-01-15T12:00:00Z", "2024-07-01T12:00:00Z"] {
+            ctx.now = fixtures::time(now);
+            assert_eq!(respond(&ctx, "HASDST Europe/Berlin"), "OK yes");
+            assert_eq!(respond(&ctx, "HASDST Australia/Sydney"), "OK yes");
+            assert_eq!(respond(&ctx, "HASDST Asia/Kolkata"), "OK no");
+            assert_eq!(respond(&ctx, "HASDST Etc/UTC"), "OK no");
+        }
+        assert_eq!(
+            respond(&ctx, "HASDST Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn by_offset_all() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.now = fixtures::time("2024-01-15T12:00:00Z");
+        assert_eq!(
+            respond(&ctx, "BYOFFSETALL"),
+            "OK\n-0930 Pacific/Marquesas\n-0600 America/Chicago\n-0500 America/New_York\n\
+             -0330 America/St_Johns\n-0300 America/Argentina/Buenos_Aires\n\
+             +0000 Africa/Abidjan Etc/UTC Europe/Dublin Europe/London\n\
+             +0100 Europe/Berlin Europe/Zurich\n+0300 Asia/Riyadh Europe/Istanbul\n\
+             +0530 Asia/Kolkata\n+0545 Asia/Kathmandu\n+1000 Australia/Brisbane\n\
+             +1100 Australia/Lord_Howe Australia/Sydney"
+        );
+        assert_eq!(
+            respond(&ctx, "BYOFFSETALL 11"),
+            "OK\n+1100 Australia/Lord_Howe Australia/Sydney"
+        );
+        assert_eq!(respond(&ctx, "BYOFFSETALL 12"), "ERROR Invalid Offset");
+        assert_eq!(respond(&ctx, "BYOFFSETALL -1"), "ERROR Invalid Offset");
+
+        // Groups of 5 timezones at every whole hour, which take several responses
+        let mut posixinfo = String::new();
+        let mut expected = Vec::new();
+        for hours in -12..=12 {
+            let olsons = (0..5)
+                .map(|i| format!("Test/Zone_{hours}_{i}"))
+                .collect::<Vec<_>>();
+            for olson in &olsons {
+                posixinfo += &format!("{olson} <{hours:+03}>{}\n", -hours);
+            }
+            expected.push(format!(
+                "{} {}",
+                format_utc_offset(hours * 3600),
+                olsons.join(" ")
+            ));
+        }
+        let db = TimezoneDb::parse(posixinfo.as_bytes(), &b""[..], &[]).unwrap();
+        let ctx = fixtures::context(&config, &db);
+        let (mut lines, mut pages) = (Vec::new(), 0);
+        let mut next = Some(0);
+        while let Some(index) = next {
+            pages += 1;
+            let response = respond(&ctx, &format!("BYOFFSETALL {index}"));
+            assert!(response.len() <= MAX_RESPONSE_SIZE);
+            let (response, more) = match response.rsplit_once("\nMORE ") {
+                Some((response, more)) => (response, Some(more.parse::<usize>().unwrap())),
+                None => (response.as_str(), None),
+            };
+            let mut page = response.lines();
+            assert_eq!(page.next(), Some("OK"));
+            lines.extend(page.map(str::to_owned));
+            if let Some(more) = more {
+                // Each response is filled with as many groups as fit, and the next one starts
+                // where it left off
+                assert_eq!(more, lines.len());
+                assert!(
+                    response.len() + 1 + expected[more].len()
+                        > MAX_RESPONSE_SIZE - MAX_MORE_LINE_SIZE
+                );
+            }
+            next = more;
+        }
+        assert!(pages > 2);
+        assert_eq!(lines, expected);
+
+        // A group too large for a response on its own is truncated
+        let posixinfo = (0..60)
+            .map(|i| format!("Test/Zone_{i:02} UTC0\nTest/Other_{i:02} <+01>-1\n"))
+            .collect::<String>();
+        let db = TimezoneDb::parse(posixinfo.as_bytes(), &b""[..], &[]).unwrap();
+        let ctx = fixtures::context(&config, &db);
+        let response = respond(&ctx, "BYOFFSETALL");
+        assert!(response.len() <= MAX_RESPONSE_SIZE);
+        assert!(response.starts_with("OK\n+0000 Test/Zone_00 Test/Zone_01 "));
+        assert!(response.ends_with(" +\nMORE 1"));
+        assert!(respond(&ctx, "BYOFFSETALL 1").starts_with("OK\n+0100 Test/Other_00 "));
+    }
+
+    #[test]
+    fn posix_fmt() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        // ezTime strings are returned as they are
+        for (olson, posix) in [
+            ("Europe/Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+            ("Asia/Kolkata", "IST-5:30"),
+            (
+                "Australia/Lord_Howe",
+                "<+1030>-10:30<+11>-11,M10.1.0,M4.1.0",
+            ),
+        ] {
+            assert_eq!(
+                respond(&ctx, &format!("POSIXFMT {olson} eztime")),
+                format!("OK {olson} {posix}")
+            );
+        }
+        // glibc strings have the DST offset and transition times written out
+        assert_eq!(
+            respond(&ctx, "POSIXFMT Europe/Berlin GLIBC"),
+            "OK Europe/Berlin CET-1CEST-2,M3.5.0/2,M10.5.0/3"
+        );
+        assert_eq!(
+            respond(&ctx, "POSIXFMT Australia/Lord_Howe glibc"),
+            "OK Australia/Lord_Howe <+1030>-10:30<+11>-11,M10.1.0/2,M4.1.0/2"
+        );
+        assert_eq!(
+            respond(&ctx, "POSIXFMT Asia/Kolkata glibc"),
+            "OK Asia/Kolkata IST-5:30"
+        );
+        assert_eq!(
+            respond(&ctx, "POSIXFMT Asia/Riyadh glibc"),
+            "OK Asia/Riyadh <+03>-3"
+        );
+        assert_eq!(
+            respond(&ctx, "POSIXFMT Europe/Berlin musl"),
+            "ERROR Unknown Dialect"
+        );
+        assert_eq!(
+            respond(&ctx, "POSIXFMT Europe/Atlantis glibc"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn label() {
+        let config = fixtures::config();
+        let posixinfo = format!("{}UTC UTC0\n", fixtures::POSIXINFO);
+        let mut db = TimezoneDb::parse(posixinfo.as_bytes(), &b""[..], &[]).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let backward = dir.path().join("backward");
+        std::fs::write(&backward, "L Asia/Kolkata Asia/Calcutta\n").unwrap();
+        db.load_links(&backward);
+
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "LABEL America/Argentina/Buenos_Aires"),
+            "OK Buenos Aires"
+        );
+        assert_eq!(respond(&ctx, "LABEL Etc/UTC"), "OK UTC");
+        // Names without a '/' are their own label
+        assert_eq!(respond(&ctx, "LABEL UTC"), "OK UTC");
+        // Old names are labeled with their current name
+        assert_eq!(respond(&ctx, "LABEL Asia/Calcutta"), "OK Kolkata");
+        assert_eq!(
+            respond(&ctx, "LABEL Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn dst_now_in_both_hemispheres() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.now = fixtures::time("2024-07-01T12:00:00Z");
+        assert_eq!(respond(&ctx, "DSTNOW Europe/Berlin"), "OK active");
+        assert_eq!(respond(&ctx, "DSTNOW Australia/Sydney"), "OK inactive");
+        assert_eq!(respond(&ctx, "DSTNOW Asia/Kolkata"), "OK inactive");
+        ctx.now = fixtures::time("2024-01-15T12:00:00Z");
+        assert_eq!(respond(&ctx, "DSTNOW Europe/Berlin"), "OK inactive");
+        assert_eq!(respond(&ctx, "DSTNOW Australia/Sydney"), "OK active");
+        assert_eq!(respond(&ctx, "DSTNOW Asia/Kolkata"), "OK inactive");
+        // Sydney's DST ends at 03:00 local DST time on the first Sunday of April
+        ctx.now = fixtures::time("2024-04-06T15:59:59Z");
+        assert_eq!(respond(&ctx, "DSTNOW Australia/Sydney"), "OK active");
+        ctx.now = fixtures::time("2024-04-06T16:00:00Z");
+        assert_eq!(respond(&ctx, "DSTNOW Australia/Sydney"), "OK inactive");
+        assert_eq!(
+            respond(&ctx, "DSTNOW Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn diff_tz() {
+        let config = fixtures::config();
+        let mut db = fixtures::timezone_db();
+        let dir = tempfile::tempdir().unwrap();
+        let previous = dir.path().join("posixinfo.previous");
+        std::fs::write(
+            &previous,
+            "Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3\nAsia/Riyadh <+0330>-3:30\n",
+        )
+        .unwrap();
+        db.load_previous(&previous);
+
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "DIFFTZ Europe/Berlin"), "OK unchanged");
+        assert_eq!(
+            respond(&ctx, "DIFFTZ Asia/Riyadh"),
+            "OK current=<+03>-3 prev=<+0330>-3:30"
+        );
+        // Timezones that are new since the last refresh
+        assert_eq!(respond(&ctx, "DIFFTZ Asia/Kolkata"), "OK current=IST-5:30");
+        // tzdata strings are compared, ignoring rewrites
+        assert_eq!(
+            respond(&ctx, "DIFFTZ Europe/Dublin"),
+            "OK current=IST-1GMT0,M10.5.0,M3.5.0/1"
+        );
+        assert_eq!(
+            respond(&ctx, "DIFFTZ Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn offset_iso() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.now = fixtures::time("2024-01-15T12:00:00Z");
+        assert_eq!(respond(&ctx, "OFFSETISO Asia/Kathmandu"), "OK +05:45");
+        assert_eq!(respond(&ctx, "OFFSETISO America/New_York"), "OK -05:00");
+        assert_eq!(respond(&ctx, "OFFSETISO Pacific/Marquesas"), "OK -09:30");
+        assert_eq!(respond(&ctx, "OFFSETISO Etc/UTC"), "OK +00:00");
+        assert_eq!(respond(&ctx, "OFFSETISO Europe/London"), "OK +00:00");
+        ctx.now = fixtures::time("2024-07-01T12:00:00Z");
+        assert_eq!(respond(&ctx, "OFFSETISO America/New_York"), "OK -04:00");
+        assert_eq!(respond(&ctx, "OFFSETISO Europe/London"), "OK +01:00");
+        assert_eq!(
+            respond(&ctx, "OFFSETISO Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn offsets() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.now = fixtures::time("2024-01-15T12:00:00Z");
+        // DST is an hour ahead of standard time unless its offset is given
+        assert_eq!(
+            respond(&ctx, "OFFSETS Europe/Berlin"),
+            "OK std=3600 dst=7200"
+        );
+        assert_eq!(
+            respond(&ctx, "OFFSETS Australia/Lord_Howe"),
+            "OK std=37800 dst=39600"
+        );
+        assert_eq!(
+            respond(&ctx, "OFFSETS America/St_Johns"),
+            "OK std=-12600 dst=-9000"
+        );
+        assert_eq!(respond(&ctx, "OFFSETS Asia/Kathmandu"), "OK std=20700");
+        assert_eq!(respond(&ctx, "OFFSETS Etc/UTC"), "OK std=0");
+        // The same whether or not DST is in effect
+        ctx.now = fixtures::time("2024-07-01T12:00:00Z");
+        assert_eq!(
+            respond(&ctx, "OFFSETS Europe/Berlin"),
+            "OK std=3600 dst=7200"
+        );
+        assert_eq!(
+            respond(&ctx, "OFFSETS Europe/Atlantis"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn geoip_info() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let geoip = fixtures::geoip([
+            (
+                "192.0.2.1",
+                GeoIpRecord {
+                    timezone: Some("America/Chicago"),
+                    country: Some("US"),
+                    subdivision: Some("IL"),
+                },
+            ),
+            (
+                "192.0.2.2",
+                GeoIpRecord {
+                    country: Some("IN"),
+                    ..Default::default()
+                },
+            ),
+            ("192.0.2.3", GeoIpRecord::default()),
+        ]);
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.geoip = Some(&geoip);
+        assert_eq!(
+            respond_to(&ctx, "GEOIPINFO", "192.0.2.1"),
+            "OK tz=America/Chicago country=US subdivision=IL"
+        );
+        // Fields that aren't known are left out, and the timezone can come from the country
+        assert_eq!(
+            respond_to(&ctx, "GEOIPINFO", "192.0.2.2"),
+            "OK tz=Asia/Kolkata country=IN"
+        );
+        assert_eq!(
+            respond_to(&ctx, "GEOIPINFO", "192.0.2.3"),
+            "ERROR GeoIP Lookup Failed"
+        );
+        assert_eq!(
+            respond_to(&ctx, "GEOIPINFO", "192.0.2.4"),
+            "ERROR GeoIP Lookup Failed"
+        );
+        // Requests from the Unix socket have no address
+        assert_eq!(respond(&ctx, "GEOIPINFO"), "ERROR GeoIP Lookup Failed");
+    }
+
+    #[test]
+    fn validate() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        for posix in [
+            "CET-1CEST,M3.5.0,M10.5.0/3",
+            "CET-1CEST-2,M3.5.0/2,M10.5.0/3",
+            "<+1030>-10:30<+11>-11,M10.1.0,M4.1.0",
+            "<-03>3",
+            "IST-5:30",
+        ] {
+            assert_eq!(respond(&ctx, &format!("VALIDATE {posix}")), "OK valid");
+        }
+        // Zone names aren't uppercased like timezone names are
+        assert_eq!(
+            Request::parse("VALIDATE  Cet-1Cest,M3.5.0,M10.5.0/3\n"),
+            Request::Validate("Cet-1Cest,M3.5.0,M10.5.0/3".into())
+        );
+        // Errors give the position in the string, counting from 0
+        for (posix, error) in [
+            ("", "invalid name ending at position 0"),
+            ("C-1", "invalid name ending at position 1"),
+            ("CET-1CEST,M3.5.0", "expected ',' at position 16"),
+            ("CET-1CESTM3.5.0,M10.5.0", "expected ',' at position 11"),
+            ("CET-1CEST,M13.5.0,M10.5.0", "invalid number at position 11"),
+            ("CET-1CEST,M3.5.0,M10.5.0/3x", "unexpected 'x'"),
+        ] {
+            assert_eq!(
+                respond(&ctx, &format!("VALIDATE {posix}")),
+                format!("ERROR Invalid POSIX: {error}")
+            );
+        }
+    }
+
+    #[test]
+    fn dial() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(
+            respond(&ctx, "DIAL +41"),
+            "OK Europe/Zurich CET-1CEST,M3.5.0,M10.5.0/3"
+        );
+        assert_eq!(respond(&ctx, "DIAL 91"), "OK Asia/Kolkata IST-5:30");
+        // Codes shared by countries in the same timezone
+        assert_eq!(
+            respond(&ctx, "DIAL +44"),
+            "OK Europe/London GMT0BST,M3.5.0/1,M10.5.0"
+        );
+        // Codes shared by countries in different timezones, or countries without one timezone
+        assert_eq!(
+            respond(&ctx, "DIAL +1"),
+            "ERROR Dial Code Is Ambiguous: US CA"
+        );
+        for code in ["", "+", "+12345", "+4a", "++41"] {
+            assert_eq!(
+                respond(&ctx, &format!("DIAL {code}")),
+                "ERROR Invalid Dial Code"
+            );
+        }
+        assert_eq!(respond(&ctx, "DIAL +999"), "ERROR Invalid Dial Code");
+    }
+
+    #[test]
+    fn transitions() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.now = fixtures::time("2024-11-15T12:00:00Z");
+        let transitions = |transitions: &[(&str, i32)]| {
+            let transitions = transitions
+                .iter()
+                .map(|(time, offset)| format!("{}:{}", parse_rfc3339(time).unwrap(), offset))
+                .collect::<Vec<_>>();
+            format!("OK {}", transitions.join(" "))
+        };
+        // Alternating between starting and ending DST, into the next years
+        assert_eq!(
+            respond(&ctx, "TRANSITIONS Europe/Berlin 3"),
+            transitions(&[
+                ("2025-03-30T01:00:00Z", 7200),
+                ("2025-10-26T01:00:00Z", 3600),
+                ("2026-03-29T01:00:00Z", 7200),
+            ])
+        );
+        assert_eq!(
+            respond(&ctx, "TRANSITIONS Australia/Sydney 2"),
+            transitions(&[
+                ("2025-04-05T16:00:00Z", 36000),
+                ("2025-10-04T16:00:00Z", 39600),
+            ])
+        );
+        assert_eq!(respond(&ctx, "TRANSITIONS Asia/Kolkata 3"), "OK none");
+
+        let response = respond(
+            &ctx,
+            &format!("TRANSITIONS Europe/Berlin {MAX_TRANSITIONS}"),
+        );
+        assert_eq!(response.split(' ').count(), MAX_TRANSITIONS + 1);
+        let too_many = (MAX_TRANSITIONS + 1).to_string();
+        for count in ["0", "-1", &too_many, "x"] {
+            assert_eq!(
+                respond(&ctx, &format!("TRANSITIONS Europe/Berlin {count}")),
+                "ERROR Invalid Count"
+            );
+        }
+        assert_eq!(
+            respond(&ctx, "TRANSITIONS Europe/Berlin"),
+            "ERROR Invalid Count"
+        );
+        assert_eq!(
+            respond(&ctx, "TRANSITIONS Europe/Atlantis 3"),
+            "ERROR Timezone Not Found"
+        );
+    }
+
+    #[test]
+    fn canonical_case() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let geoip = fixtures::geoip([(
+            "192.0.2.1",
+            GeoIpRecord {
+                timezone: Some("europe/berlin"),
+                ..Default::default()
+            },
+        )]);
+        let mut ctx = fixtures::context(&config, &db);
+        ctx.geoip = Some(&geoip);
+        let berlin = "OK Europe/Berlin CET-1CEST,M3.5.0,M10.5.0/3";
+        assert_eq!(respond(&ctx, "europe/berlin"), berlin);
+        assert_eq!(respond(&ctx, "EUROPE/BERLIN"), berlin);
+        assert_eq!(respond(&ctx, "de"), berlin);
+        assert_eq!(respond_to(&ctx, "geoip", "192.0.2.1"), berlin);
+    }
+
+    #[test]
+    fn dump() {
+        let (mut config, db) = (fixtures::config(), fixtures::timezone_db());
+        assert_eq!(
+            respond(&fixtures::context(&config, &db), "DUMP key"),
+            "ERROR Not Authorized"
+        );
+        config.admin_key = crate::Secret("key".into());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "DUMP wrong"), "ERROR Not Authorized");
+        assert_eq!(respond(&ctx, "DUMP"), "ERROR Not Authorized");
+
+        // Walking every page gives every timezone once, with the POSIX string it's served with
+        let (mut lines, mut pages) = (Vec::new(), 0);
+        let mut next = Some(0);
+        while let Some(index) = next {
+            pages += 1;
+            let response = respond(&ctx, &format!("DUMP key {index}"));
+            assert!(response.len() <= MAX_RESPONSE_SIZE);
+            let (response, more) = match response.rsplit_once("\nMORE ") {
+                Some((response, more)) => (response, Some(more.parse::<usize>().unwrap())),
+                None => (response.as_str(), None),
+            };
+            let mut page = response.lines();
+            assert_eq!(page.next(), Some("OK"));
+            lines.extend(page.map(str::to_owned));
+            if let Some(more) = more {
+                assert_eq!(more, lines.len());
+            }
+            next = more;
+        }
+        assert!(pages > 1);
+        let mut expected = fixtures::POSIXINFO
+            .lines()
+            .map(|line| match line {
+                "Europe/Dublin IST-1GMT0,M10.5.0,M3.5.0/1" => {
+                    "Europe/Dublin GMT0IST,M3.5.0/1,M10.5.0".to_owned()
+                }
+                line => line.to_owned(),
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        expected.sort();
+        assert_eq!(lines, expected);
+
+        assert_eq!(
+            respond(&ctx, &format!("DUMP key {}", expected.len())),
+            "ERROR Invalid Offset"
+        );
+        assert_eq!(respond(&ctx, "DUMP key x"), "ERROR Invalid Offset");
+    }
+
+    #[test]
+    fn unknown_command() {
+        let (config, db) = (fixtures::config(), fixtures::timezone_db());
+        let ctx = fixtures::context(&config, &db);
+        assert_eq!(respond(&ctx, "FROBNICATE"), "ERROR Unknown Command");
+        assert_eq!(respond(&ctx, "LISTCOUNTRY"), "ERROR Unknown Command");
+    }
+}
@@ -0,0 +1,249 @@
+use crate::{Config, IpFamily};
+use log::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::fd::{FromRawFd, RawFd};
+use tokio::net::{lookup_host, UdpSocket};
+
+// First file descriptor passed by systemd socket activation, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// Binds the UDP socket used to serve requests, or takes the one passed by systemd, configured
+// according to config
+pub async fn bind_udp(config: &Config) -> io::Result<UdpSocket> {
+    let socket = if config.systemd_socket {
+        let socket = systemd_socket()?;
+        info!(
+            "Using UDP socket {} passed by systemd",
+            socket.local_addr()?.as_socket().unwrap()
+        );
+        socket
+    } else {
+        bind(config).await?
+    };
+    let families = match socket.local_addr()?.as_socket() {
+        Some(SocketAddr::V4(_)) => "IPv4",
+        _ if socket.only_v6()? => "IPv6",
+        _ => "IPv4 and IPv6",
+    };
+    info!("Serving {} requests", families);
+
+    if config.so_rcvbuf > 0 {
+        if let Err(err) = socket.set_recv_buffer_size(config.so_rcvbuf) {
+            warn!(
+                "Could not set socket receive buffer size to {} bytes: {}",
+                config.so_rcvbuf, err
+            );
+        }
+        // The kernel may clamp the size (see net.core.rmem_max on Linux), or adjust it for
+        // bookkeeping overhead
+        let size = socket.recv_buffer_size()?;
+        if size < config.so_rcvbuf {
+            warn!(
+                "Socket receive buffer size is {} bytes, which is less than the {} bytes requested",
+                size, config.so_rcvbuf
+            );
+        } else {
+            info!("Socket receive buffer size is {} bytes", size);
+        }
+    }
+
+    socket.set_nonblocking(true)?;
+
+    if let Some(group) = config.multicast_group {
+        info!("Joining multicast group {}", group);
+        match group {
+            IpAddr::V4(group) => socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?,
+            // Interface 0 lets the OS choose the interface
+            IpAddr::V6(group) => socket.join_multicast_v6(&group, 0)?,
+        }
+    }
+
+    UdpSocket::from_std(socket.into())
+}
+
+async fn bind(config: &Config) -> io::Result<Socket> {
+    let host = match config.ip_family {
+        // The default host only listens on IPv4, so it's replaced with its IPv6 equivalent
+        Some(IpFamily::V6 | IpFamily::Dual) if config.host == "0.0.0.0" => "::",
+        _ => config.host.as_str(),
+    };
+    let addr = lookup_host((host, config.port))
+        .await?
+        .find(|addr| match config.ip_family {
+            None => true,
+            Some(IpFamily::V4) => addr.is_ipv4(),
+            Some(IpFamily::V6 | IpFamily::Dual) => addr.is_ipv6(),
+        })
+        .ok_or_else(|| {
+            let err = match config.ip_family {
+                None => format!("{} could not be resolved", host),
+                Some(IpFamily::V4) => format!("{} could not be resolved to an IPv4 address", host),
+                Some(_) => format!("{} could not be resolved to an IPv6 address", host),
+            };
+            io::Error::new(io::ErrorKind::AddrNotAvailable, err)
+        })?;
+
+    info!("Binding UDP socket {}", addr);
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    // Without a family, IPv6 sockets are left as the OS defaults them (see net.ipv6.bindv6only on
+    // Linux)
+    match config.ip_family {
+        Some(IpFamily::V6) => socket.set_only_v6(true)?,
+        Some(IpFamily::Dual) => socket.set_only_v6(false)?,
+        _ => {}
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+// Takes the first socket passed by systemd socket activation, which must be a UDP socket
+fn systemd_socket() -> io::Result<Socket> {
+    let not_passed = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "TZD_SYSTEMD_SOCKET is set, but no socket was passed by systemd ({})",
+                reason
+            ),
+        )
+    };
+    let listen_pid = std::env::var("LISTEN_PID").map_err(|_| not_passed("LISTEN_PID is unset"))?;
+    // The variables may have been inherited from a parent that was passed the sockets instead
+    if listen_pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Err(not_passed("LISTEN_PID is not the server's PID"));
+    }
+    let listen_fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<u32>().ok())
+        .unwrap_or(0);
+    if listen_fds == 0 {
+        return Err(not_passed("LISTEN_FDS is unset or 0"));
+    }
+    if listen_fds > 1 {
+        warn!(
+            "systemd passed {} sockets, only the first will be used",
+            listen_fds
+        );
+    }
+    // Update scripts shouldn't think the sockets were passed to them
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+
+    let fd = SD_LISTEN_FDS_START;
+    // systemd doesn't set close-on-exec, so the socket would otherwise be inherited by update
+    // scripts
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: the descriptor is open, and systemd passed ownership of it to this process
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    let invalid = |err: io::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The socket passed by systemd is not a UDP socket: {}", err),
+        )
+    };
+    if socket.r#type().map_err(invalid)? != Type::DGRAM {
+        return Err(invalid(io::Error::other("not a datagram socket")));
+    }
+    if socket.local_addr().map_err(invalid)?.as_socket().is_none() {
+        return Err(invalid(io::Error::other("not an IP socket")));
+    }
+    Ok(socket)
+}
+
+// Number of packets the kernel has dropped for a UDP socket, usually because its receive buffer was
+// full. This is read from /proc/net/udp, so it's only available on Linux.
+#[cfg(all(feature = "metrics", target_os = "linux"))]
+pub fn dropped_packets(socket: &UdpSocket) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::MetadataExt;
+
+    // Sockets are listed by inode, which is the inode of their file descriptor
+    let inode = std::fs::metadata(format!("/proc/self/fd/{}", socket.as_raw_fd()))?
+        .ino()
+        .to_string();
+    for path in ["/proc/net/udp", "/proc/net/udp6"] {
+        let table = std::fs::read_to_string(path)?;
+        // After the header, each line is like "sl local_address rem_address ... inode ref pointer
+        // drops", where inode is the 10th field and drops is the last
+        for line in table.lines().skip(1) {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.get(9) == Some(&inode.as_str()) {
+                return fields
+                    .last()
+                    .and_then(|drops| drops.parse().ok())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, line.to_owned()));
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "socket is not in /proc/net/udp",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::net::Ipv6Addr;
+
+    #[tokio::test]
+    async fn so_rcvbuf() {
+        let mut config = fixtures::config();
+        config.host = "127.0.0.1".into();
+        config.port = 0;
+        config.so_rcvbuf = 65536;
+        let socket = bind_udp(&config).await.unwrap();
+        let size = socket2::SockRef::from(&socket).recv_buffer_size().unwrap();
+        // Linux doubles the requested size to leave room for bookkeeping
+        assert!(size >= config.so_rcvbuf, "{}", size);
+        assert!(socket.local_addr().unwrap().ip().is_loopback());
+    }
+
+    #[tokio::test]
+    async fn systemd_socket_not_passed() {
+        let mut config = fixtures::config();
+        config.systemd_socket = true;
+        // Tests aren't run with socket activation, so there's no LISTEN_PID for this process
+        let err = bind_udp(&config).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("TZD_SYSTEMD_SOCKET"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn ip_family() {
+        let mut config = fixtures::config();
+        config.port = 0;
+        config.ip_family = Some(IpFamily::Dual);
+        // The default host is replaced with its IPv6 equivalent
+        let socket = bind_udp(&config).await.unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), Ipv6Addr::UNSPECIFIED);
+        assert!(!socket2::SockRef::from(&socket).only_v6().unwrap());
+
+        config.ip_family = Some(IpFamily::V6);
+        let socket = bind_udp(&config).await.unwrap();
+        assert!(socket2::SockRef::from(&socket).only_v6().unwrap());
+
+        config.host = "127.0.0.1".into();
+        let err = bind_udp(&config).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        config.ip_family = Some(IpFamily::V4);
+        let socket = bind_udp(&config).await.unwrap();
+        assert!(socket.local_addr().unwrap().is_ipv4());
+    }
+
+    #[cfg(all(feature = "metrics", target_os = "linux"))]
+    #[tokio::test]
+    async fn dropped_packets() {
+        let mut config = fixtures::config();
+        config.host = "127.0.0.1".into();
+        config.port = 0;
+        let socket = bind_udp(&config).await.unwrap();
+        assert_eq!(super::dropped_packets(&socket).unwrap(), 0);
+    }
+}
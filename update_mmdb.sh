@@ -1,5 +1,6 @@
 #!/bin/sh
 
+# The license key, if any, is in $MAXMIND_LICENSE_KEY
 DIR=$1
 URL=$2
 
@@ -10,15 +11,28 @@ cd $DIR || exit 1
 rm -rf mmdb
 mkdir mmdb && cd mmdb || exit 1
 
-wget -nv $URL || exit 1
+if [ -n "$MAXMIND_LICENSE_KEY" ]; then
+	# wget's usual output includes the URL, which would leak the license key into the logs. The URL
+	# is read from stdin, since wget's arguments can be seen by every user in ps.
+	echo "$URL&license_key=$MAXMIND_LICENSE_KEY" | wget -q --content-disposition -i - || exit 1
+else
+	wget -nv $URL || exit 1
+fi
 
 if [ -f *.tar.gz ]; then
 	tar zxf *.tar.gz
 	rm *.tar.gz
+	# MaxMind archives contain the database inside of a dated directory
+	find . -mindepth 2 \( -name GeoLite2-City.mmdb -o -name GeoLite2-Country.mmdb \) -exec mv {} . \;
+fi
+
+# Country databases are stored under the same name, since the server detects the type when loading
+if [ ! -f GeoLite2-City.mmdb ] && [ -f GeoLite2-Country.mmdb ]; then
+	mv GeoLite2-Country.mmdb GeoLite2-City.mmdb
 fi
 
 if [ ! -f GeoLite2-City.mmdb ]; then
-	echo "update_mmdb: GeoLite2-City.mmdb (or a .tar.gz archive of it) could not be found in the data downloaded"
+	echo "update_mmdb: GeoLite2-City.mmdb or GeoLite2-Country.mmdb (or a .tar.gz archive of either) could not be found in the data downloaded"
 	exit 1
 fi
 
@@ -1,6 +1,7 @@
 #!/bin/sh
 
 DIR=$1
+OVERRIDES_URL=$2
 
 echo update_tzdata: Using data directory $DIR
 
@@ -15,13 +16,16 @@ mkdir tzdata && cd tzdata || exit 1
 wget -nv ftp://ftp.iana.org/tz/tzdata-latest.tar.gz || exit 1
 tar zxf tzdata-latest.tar.gz || exit 1
 rm tzdata-latest.tar.gz
-mv zone1970.tab $DIR
+mv zone1970.tab backward $DIR
 for i in africa antarctica asia australasia etcetera europe northamerica southamerica; do
 	zic -d $DIR/zoneinfo $i;
 done
 
 cd $DIR
-rm posixinfo
+# The previous POSIX strings are kept to compare with the new ones
+if [ -f posixinfo ]; then
+	mv posixinfo posixinfo.prev
+fi
 cd zoneinfo
 for i in `find *|grep /`
 do
@@ -35,3 +39,13 @@ done
 cd $DIR
 rm -rf zoneinfo
 rm -rf tzdata
+
+if [ -n "$OVERRIDES_URL" ]; then
+	# A failed download keeps the last good copy of the overrides, and doesn't fail the update
+	if wget -nv --tries=3 --waitretry=10 -O overrides.remote.new "$OVERRIDES_URL"; then
+		mv overrides.remote.new overrides.remote
+	else
+		echo "update_tzdata: Could not download overrides from $OVERRIDES_URL, keeping the last copy"
+		rm -f overrides.remote.new
+	fi
+fi